@@ -0,0 +1,159 @@
+//! `#[derive(Chunked)]` for `common::connection_protocol`
+//!
+//! Generates the protocol (`Chunked::SCHEMA`), the writer and the reader of a struct from its
+//! fields, so the three can't drift apart.
+//!
+//! Every field type has to implement `ChunkField`, fields are written in declaration order.
+//! Field attributes:
+//! - `#[chunk(skip)]` the field is not sent, it is set to `Default::default()` when reading
+//! - `#[chunk(binary)]` a `Vec<u8>` written as `Chunks::Binary`
+//! - `#[chunk(uint = N)]` a field written as `Chunks::Uint { size: N }` instead of its default size
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, Data, DeriveInput, Fields, LitInt};
+
+#[proc_macro_derive(Chunked, attributes(chunk))]
+pub fn derive_chunked(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand(input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+/// How a single field is put on the wire
+enum FieldKind {
+    Default,
+    Skip,
+    Binary,
+    Uint(LitInt),
+}
+
+fn field_kind(field: &syn::Field) -> syn::Result<FieldKind> {
+    let mut kind = FieldKind::Default;
+    for attr in &field.attrs {
+        if !attr.path().is_ident("chunk") {
+            continue;
+        }
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("skip") {
+                kind = FieldKind::Skip;
+                Ok(())
+            } else if meta.path.is_ident("binary") {
+                kind = FieldKind::Binary;
+                Ok(())
+            } else if meta.path.is_ident("uint") {
+                let size: LitInt = meta.value()?.parse()?;
+                let value: u8 = size.base10_parse()?;
+                if value == 0 || value > 8 {
+                    return Err(meta.error("uint size must be between 1 and 8"));
+                }
+                kind = FieldKind::Uint(size);
+                Ok(())
+            } else {
+                Err(meta.error("expected `skip`, `binary` or `uint = N`"))
+            }
+        })?;
+    }
+    Ok(kind)
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    if !input.generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(
+            &input.generics,
+            "Chunked can't be derived for generic types",
+        ));
+    }
+    let fields = match &input.data {
+        Data::Struct(data) => &data.fields,
+        _ => {
+            return Err(syn::Error::new_spanned(
+                name,
+                "Chunked can only be derived for structs",
+            ))
+        }
+    };
+
+    let proto = quote!(::common::connection_protocol);
+    let mut schema = Vec::new();
+    let mut writes = Vec::new();
+    let mut reads = Vec::new();
+    let mut bindings = Vec::new();
+
+    for (i, field) in fields.iter().enumerate() {
+        let ty = &field.ty;
+        let member = match &field.ident {
+            Some(ident) => quote!(#ident),
+            None => {
+                let index = syn::Index::from(i);
+                quote!(#index)
+            }
+        };
+        let binding = match &field.ident {
+            Some(ident) => ident.clone(),
+            None => format_ident!("field_{}", i),
+        };
+        match field_kind(field)? {
+            FieldKind::Skip => {
+                reads.push(quote!(let #binding = ::std::default::Default::default();));
+            }
+            FieldKind::Binary => {
+                schema.push(quote!(#proto::Chunks::Binary));
+                writes.push(quote!(writer.write_binary(&self.#member);));
+                reads.push(quote!(let #binding = reader.try_read_binary()?;));
+            }
+            kind => {
+                match kind {
+                    FieldKind::Uint(size) => schema.push(quote!(#proto::Chunks::Uint { size: #size })),
+                    _ => schema.push(quote!(<#ty as #proto::ChunkField>::CHUNK)),
+                }
+                writes.push(quote!(#proto::ChunkField::write_field(&self.#member, writer);));
+                reads.push(quote!(let #binding = <#ty as #proto::ChunkField>::read_field(reader)?;));
+            }
+        }
+        bindings.push(binding);
+    }
+
+    let construct = match fields {
+        Fields::Named(_) => quote!(Self { #(#bindings),* }),
+        Fields::Unnamed(_) => quote!(Self ( #(#bindings),* )),
+        Fields::Unit => quote!(Self),
+    };
+
+    Ok(quote! {
+        impl #proto::Chunked for #name {
+            const SCHEMA: &'static [#proto::Chunks] = &[#(#schema),*];
+
+            #[allow(unused_variables)]
+            fn write_chunks(&self, writer: &mut #proto::ConnectionWriter) {
+                #(#writes)*
+            }
+
+            #[allow(unused_variables)]
+            fn read_chunks(
+                reader: &mut #proto::ConnectionReader<'_>,
+            ) -> ::std::result::Result<Self, #proto::ReadError> {
+                #(#reads)*
+                ::std::result::Result::Ok(#construct)
+            }
+        }
+
+        impl #proto::ChunkField for #name {
+            const CHUNK: #proto::Chunks =
+                #proto::Chunks::Struct(<Self as #proto::Chunked>::SCHEMA);
+
+            fn write_field(&self, writer: &mut #proto::ConnectionWriter) {
+                writer.write_struct(|writer| #proto::Chunked::write_chunks(self, writer));
+            }
+
+            fn read_field(
+                reader: &mut #proto::ConnectionReader<'_>,
+            ) -> ::std::result::Result<Self, #proto::ReadError> {
+                reader.try_read_struct(<Self as #proto::Chunked>::read_chunks)
+            }
+        }
+    })
+}
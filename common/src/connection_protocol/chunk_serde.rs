@@ -0,0 +1,777 @@
+//! Serde support for the chunk wire format
+//!
+//! Values are laid out the same way `ConnectionWriter` writes them:
+//! - integers are big-endian, `u8`/`i8` take 1 byte, `u16`/`i16` 2, `u32`/`i32` 4 and
+//!   `u64`/`i64` 8 (`Chunks::Uint`/`Chunks::Int`)
+//! - floats are 8 bytes (`Chunks::Float`), `f32` is widened
+//! - `bool` is 1 byte (`Chunks::Bool`)
+//! - strings and byte buffers have an 8 byte size section (`Chunks::String`/`Chunks::Binary`)
+//! - `Option` is a flag byte followed by the value (`Chunks::Optional`)
+//! - sequences and maps have an 8 byte size section with the length of all items in bytes
+//!   (`Chunks::List`)
+//! - structs and tuples are written in place (`Chunks::Struct`)
+//! - enums are the variant index (8 bytes) followed by the variant fields
+//!
+//! The format is not self-describing, so `deserialize_any` is not supported.
+use serde::de::{self, DeserializeSeed, IntoDeserializer, Visitor};
+use serde::ser::{self, Serialize};
+
+/// Serialize a value into the chunk wire format
+pub fn to_chunks_bytes<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, ChunkSerdeError> {
+    let mut serializer = Serializer { output: Vec::new() };
+    value.serialize(&mut serializer)?;
+    Ok(serializer.output)
+}
+
+/// Deserialize a value from the chunk wire format, all bytes have to be used
+pub fn from_chunks_bytes<'de, T: de::Deserialize<'de>>(
+    bytes: &'de [u8],
+) -> Result<T, ChunkSerdeError> {
+    let mut deserializer = Deserializer {
+        input: bytes,
+        offset: 0,
+    };
+    let value = T::deserialize(&mut deserializer)?;
+    if deserializer.offset != bytes.len() {
+        return Err(ChunkSerdeError::TrailingBytes {
+            offset: deserializer.offset,
+        });
+    }
+    Ok(value)
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ChunkSerdeError {
+    /// Error reported by the serialized type
+    Message(String),
+    /// The input ended before the value did
+    UnexpectedEnd { offset: usize, needed: usize },
+    /// A string is not valid UTF-8
+    InvalidUtf8 { offset: usize },
+    /// A flag or char holds an invalid value
+    InvalidValue { offset: usize },
+    /// Bytes were left after the value
+    TrailingBytes { offset: usize },
+    /// The type needs a self-describing format
+    NotSelfDescribing,
+}
+
+impl std::fmt::Display for ChunkSerdeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChunkSerdeError::Message(msg) => write!(f, "{msg}"),
+            ChunkSerdeError::UnexpectedEnd { offset, needed } => {
+                write!(
+                    f,
+                    "input ended at byte {offset}, needed {needed} more bytes"
+                )
+            }
+            ChunkSerdeError::InvalidUtf8 { offset } => {
+                write!(f, "string at byte {offset} is not valid utf-8")
+            }
+            ChunkSerdeError::InvalidValue { offset } => write!(f, "invalid value at byte {offset}"),
+            ChunkSerdeError::TrailingBytes { offset } => {
+                write!(f, "unexpected bytes after the value at byte {offset}")
+            }
+            ChunkSerdeError::NotSelfDescribing => {
+                write!(f, "the chunk format can't be read without knowing the type")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ChunkSerdeError {}
+
+impl ser::Error for ChunkSerdeError {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        ChunkSerdeError::Message(msg.to_string())
+    }
+}
+
+impl de::Error for ChunkSerdeError {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        ChunkSerdeError::Message(msg.to_string())
+    }
+}
+
+struct Serializer {
+    output: Vec<u8>,
+}
+
+impl Serializer {
+    fn write_sized(&mut self, bytes: &[u8]) {
+        self.output
+            .extend_from_slice(&(bytes.len() as u64).to_be_bytes());
+        self.output.extend_from_slice(bytes);
+    }
+
+    /// Reserve the size section of a list, filled in by `end_list`
+    fn begin_list(&mut self) -> usize {
+        let start = self.output.len();
+        self.output.extend_from_slice(&[0; 8]);
+        start
+    }
+
+    fn end_list(&mut self, start: usize) {
+        let size = (self.output.len() - start - 8) as u64;
+        self.output[start..start + 8].copy_from_slice(&size.to_be_bytes());
+    }
+}
+
+/// Serializes the items of a list and fills in its size section at the end
+struct ListSerializer<'a> {
+    serializer: &'a mut Serializer,
+    start: usize,
+}
+
+impl<'a> ser::Serializer for &'a mut Serializer {
+    type Ok = ();
+    type Error = ChunkSerdeError;
+    type SerializeSeq = ListSerializer<'a>;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Self;
+    type SerializeTupleVariant = Self;
+    type SerializeMap = ListSerializer<'a>;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Self;
+
+    fn serialize_bool(self, v: bool) -> Result<(), ChunkSerdeError> {
+        self.output.push(v as u8);
+        Ok(())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), ChunkSerdeError> {
+        self.output.extend_from_slice(&v.to_be_bytes());
+        Ok(())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), ChunkSerdeError> {
+        self.output.extend_from_slice(&v.to_be_bytes());
+        Ok(())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), ChunkSerdeError> {
+        self.output.extend_from_slice(&v.to_be_bytes());
+        Ok(())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), ChunkSerdeError> {
+        self.output.extend_from_slice(&v.to_be_bytes());
+        Ok(())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), ChunkSerdeError> {
+        self.output.push(v);
+        Ok(())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), ChunkSerdeError> {
+        self.output.extend_from_slice(&v.to_be_bytes());
+        Ok(())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), ChunkSerdeError> {
+        self.output.extend_from_slice(&v.to_be_bytes());
+        Ok(())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), ChunkSerdeError> {
+        self.output.extend_from_slice(&v.to_be_bytes());
+        Ok(())
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<(), ChunkSerdeError> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<(), ChunkSerdeError> {
+        self.output.extend_from_slice(&v.to_be_bytes());
+        Ok(())
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), ChunkSerdeError> {
+        self.serialize_u32(v as u32)
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), ChunkSerdeError> {
+        self.write_sized(v.as_bytes());
+        Ok(())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), ChunkSerdeError> {
+        self.write_sized(v);
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<(), ChunkSerdeError> {
+        self.output.push(0);
+        Ok(())
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), ChunkSerdeError> {
+        self.output.push(1);
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), ChunkSerdeError> {
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), ChunkSerdeError> {
+        Ok(())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+    ) -> Result<(), ChunkSerdeError> {
+        self.serialize_u64(variant_index as u64)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<(), ChunkSerdeError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        value: &T,
+    ) -> Result<(), ChunkSerdeError> {
+        self.serialize_u64(variant_index as u64)?;
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<ListSerializer<'a>, ChunkSerdeError> {
+        let start = self.begin_list();
+        Ok(ListSerializer {
+            serializer: self,
+            start,
+        })
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self, ChunkSerdeError> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self, ChunkSerdeError> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self, ChunkSerdeError> {
+        self.serialize_u64(variant_index as u64)?;
+        Ok(self)
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<ListSerializer<'a>, ChunkSerdeError> {
+        self.serialize_seq(None)
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self, ChunkSerdeError> {
+        Ok(self)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self, ChunkSerdeError> {
+        self.serialize_u64(variant_index as u64)?;
+        Ok(self)
+    }
+}
+
+impl ser::SerializeSeq for ListSerializer<'_> {
+    type Ok = ();
+    type Error = ChunkSerdeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(
+        &mut self,
+        value: &T,
+    ) -> Result<(), ChunkSerdeError> {
+        value.serialize(&mut *self.serializer)
+    }
+
+    fn end(self) -> Result<(), ChunkSerdeError> {
+        self.serializer.end_list(self.start);
+        Ok(())
+    }
+}
+
+impl ser::SerializeMap for ListSerializer<'_> {
+    type Ok = ();
+    type Error = ChunkSerdeError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), ChunkSerdeError> {
+        key.serialize(&mut *self.serializer)
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), ChunkSerdeError> {
+        value.serialize(&mut *self.serializer)
+    }
+
+    fn end(self) -> Result<(), ChunkSerdeError> {
+        self.serializer.end_list(self.start);
+        Ok(())
+    }
+}
+
+impl ser::SerializeTuple for &mut Serializer {
+    type Ok = ();
+    type Error = ChunkSerdeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(
+        &mut self,
+        value: &T,
+    ) -> Result<(), ChunkSerdeError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), ChunkSerdeError> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeTupleStruct for &mut Serializer {
+    type Ok = ();
+    type Error = ChunkSerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), ChunkSerdeError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), ChunkSerdeError> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeTupleVariant for &mut Serializer {
+    type Ok = ();
+    type Error = ChunkSerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), ChunkSerdeError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), ChunkSerdeError> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeStruct for &mut Serializer {
+    type Ok = ();
+    type Error = ChunkSerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        _key: &'static str,
+        value: &T,
+    ) -> Result<(), ChunkSerdeError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), ChunkSerdeError> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeStructVariant for &mut Serializer {
+    type Ok = ();
+    type Error = ChunkSerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        _key: &'static str,
+        value: &T,
+    ) -> Result<(), ChunkSerdeError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), ChunkSerdeError> {
+        Ok(())
+    }
+}
+
+struct Deserializer<'de> {
+    input: &'de [u8],
+    offset: usize,
+}
+
+impl<'de> Deserializer<'de> {
+    fn take(&mut self, len: usize) -> Result<&'de [u8], ChunkSerdeError> {
+        let available = self.input.len() - self.offset;
+        if len > available {
+            return Err(ChunkSerdeError::UnexpectedEnd {
+                offset: self.offset,
+                needed: len - available,
+            });
+        }
+        let bytes = &self.input[self.offset..self.offset + len];
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    fn take_array<const N: usize>(&mut self) -> Result<[u8; N], ChunkSerdeError> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn take_u64(&mut self) -> Result<u64, ChunkSerdeError> {
+        Ok(u64::from_be_bytes(self.take_array()?))
+    }
+
+    fn take_sized(&mut self) -> Result<&'de [u8], ChunkSerdeError> {
+        let start = self.offset;
+        let size = usize::try_from(self.take_u64()?).unwrap_or(usize::MAX);
+        self.take(size).inspect_err(|_| self.offset = start)
+    }
+
+    fn take_str(&mut self) -> Result<&'de str, ChunkSerdeError> {
+        let offset = self.offset;
+        std::str::from_utf8(self.take_sized()?).map_err(|_| ChunkSerdeError::InvalidUtf8 { offset })
+    }
+
+    fn take_flag(&mut self) -> Result<bool, ChunkSerdeError> {
+        let offset = self.offset;
+        match self.take(1)?[0] {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(ChunkSerdeError::InvalidValue { offset }),
+        }
+    }
+
+    /// Read the size section of a list and return the offset where it ends
+    fn take_list(&mut self) -> Result<usize, ChunkSerdeError> {
+        let start = self.offset;
+        let size = self.take_sized()?.len();
+        self.offset = start + 8;
+        Ok(start + 8 + size)
+    }
+}
+
+/// Reads list items until the end of the list
+struct ListAccess<'a, 'de> {
+    deserializer: &'a mut Deserializer<'de>,
+    end: usize,
+}
+
+impl<'de> de::SeqAccess<'de> for ListAccess<'_, 'de> {
+    type Error = ChunkSerdeError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, ChunkSerdeError> {
+        if self.deserializer.offset >= self.end {
+            return Ok(None);
+        }
+        let start = self.deserializer.offset;
+        let value = seed.deserialize(&mut *self.deserializer)?;
+        if self.deserializer.offset > self.end || self.deserializer.offset == start {
+            return Err(ChunkSerdeError::InvalidValue { offset: start });
+        }
+        Ok(Some(value))
+    }
+}
+
+impl<'de> de::MapAccess<'de> for ListAccess<'_, 'de> {
+    type Error = ChunkSerdeError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, ChunkSerdeError> {
+        de::SeqAccess::next_element_seed(self, seed)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, ChunkSerdeError> {
+        let start = self.deserializer.offset;
+        let value = seed.deserialize(&mut *self.deserializer)?;
+        if self.deserializer.offset > self.end {
+            return Err(ChunkSerdeError::InvalidValue { offset: start });
+        }
+        Ok(value)
+    }
+}
+
+/// Reads a fixed number of fields written in place
+struct FieldAccess<'a, 'de> {
+    deserializer: &'a mut Deserializer<'de>,
+    remaining: usize,
+}
+
+impl<'de> de::SeqAccess<'de> for FieldAccess<'_, 'de> {
+    type Error = ChunkSerdeError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, ChunkSerdeError> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        self.remaining -= 1;
+        seed.deserialize(&mut *self.deserializer).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.remaining)
+    }
+}
+
+impl<'de> de::EnumAccess<'de> for &mut Deserializer<'de> {
+    type Error = ChunkSerdeError;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, Self), ChunkSerdeError> {
+        let offset = self.offset;
+        let index = u32::try_from(self.take_u64()?)
+            .map_err(|_| ChunkSerdeError::InvalidValue { offset })?;
+        let value = seed.deserialize(index.into_deserializer())?;
+        Ok((value, self))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for &mut Deserializer<'de> {
+    type Error = ChunkSerdeError;
+
+    fn unit_variant(self) -> Result<(), ChunkSerdeError> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(
+        self,
+        seed: T,
+    ) -> Result<T::Value, ChunkSerdeError> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(
+        self,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, ChunkSerdeError> {
+        de::Deserializer::deserialize_tuple(self, len, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, ChunkSerdeError> {
+        de::Deserializer::deserialize_tuple(self, fields.len(), visitor)
+    }
+}
+
+impl<'de> de::Deserializer<'de> for &mut Deserializer<'de> {
+    type Error = ChunkSerdeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, ChunkSerdeError> {
+        Err(ChunkSerdeError::NotSelfDescribing)
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ChunkSerdeError> {
+        visitor.visit_bool(self.take_flag()?)
+    }
+
+    fn deserialize_i8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ChunkSerdeError> {
+        visitor.visit_i8(i8::from_be_bytes(self.take_array()?))
+    }
+
+    fn deserialize_i16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ChunkSerdeError> {
+        visitor.visit_i16(i16::from_be_bytes(self.take_array()?))
+    }
+
+    fn deserialize_i32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ChunkSerdeError> {
+        visitor.visit_i32(i32::from_be_bytes(self.take_array()?))
+    }
+
+    fn deserialize_i64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ChunkSerdeError> {
+        visitor.visit_i64(i64::from_be_bytes(self.take_array()?))
+    }
+
+    fn deserialize_u8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ChunkSerdeError> {
+        visitor.visit_u8(self.take(1)?[0])
+    }
+
+    fn deserialize_u16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ChunkSerdeError> {
+        visitor.visit_u16(u16::from_be_bytes(self.take_array()?))
+    }
+
+    fn deserialize_u32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ChunkSerdeError> {
+        visitor.visit_u32(u32::from_be_bytes(self.take_array()?))
+    }
+
+    fn deserialize_u64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ChunkSerdeError> {
+        visitor.visit_u64(self.take_u64()?)
+    }
+
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ChunkSerdeError> {
+        visitor.visit_f32(f64::from_be_bytes(self.take_array()?) as f32)
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ChunkSerdeError> {
+        visitor.visit_f64(f64::from_be_bytes(self.take_array()?))
+    }
+
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ChunkSerdeError> {
+        let offset = self.offset;
+        let value = u32::from_be_bytes(self.take_array()?);
+        visitor.visit_char(char::from_u32(value).ok_or(ChunkSerdeError::InvalidValue { offset })?)
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ChunkSerdeError> {
+        visitor.visit_borrowed_str(self.take_str()?)
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ChunkSerdeError> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ChunkSerdeError> {
+        visitor.visit_borrowed_bytes(self.take_sized()?)
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(
+        self,
+        visitor: V,
+    ) -> Result<V::Value, ChunkSerdeError> {
+        self.deserialize_bytes(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ChunkSerdeError> {
+        if self.take_flag()? {
+            visitor.visit_some(self)
+        } else {
+            visitor.visit_none()
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ChunkSerdeError> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, ChunkSerdeError> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, ChunkSerdeError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ChunkSerdeError> {
+        let end = self.take_list()?;
+        let value = visitor.visit_seq(ListAccess {
+            deserializer: &mut *self,
+            end,
+        })?;
+        if self.offset != end {
+            return Err(ChunkSerdeError::InvalidValue {
+                offset: self.offset,
+            });
+        }
+        Ok(value)
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, ChunkSerdeError> {
+        visitor.visit_seq(FieldAccess {
+            deserializer: self,
+            remaining: len,
+        })
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, ChunkSerdeError> {
+        self.deserialize_tuple(len, visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ChunkSerdeError> {
+        let end = self.take_list()?;
+        let value = visitor.visit_map(ListAccess {
+            deserializer: &mut *self,
+            end,
+        })?;
+        if self.offset != end {
+            return Err(ChunkSerdeError::InvalidValue {
+                offset: self.offset,
+            });
+        }
+        Ok(value)
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, ChunkSerdeError> {
+        self.deserialize_tuple(fields.len(), visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, ChunkSerdeError> {
+        visitor.visit_enum(self)
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(
+        self,
+        _visitor: V,
+    ) -> Result<V::Value, ChunkSerdeError> {
+        Err(ChunkSerdeError::NotSelfDescribing)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(
+        self,
+        _visitor: V,
+    ) -> Result<V::Value, ChunkSerdeError> {
+        Err(ChunkSerdeError::NotSelfDescribing)
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
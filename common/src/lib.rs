@@ -1,4 +1,5 @@
-use connection_protocol::FriendSummary;
+// lets `#[derive(Chunked)]` refer to `::common` inside this crate too
+extern crate self as common;
 
 pub const DEFAULT_SERVER_IP: &str = "127.0.0.1:3000";
 
@@ -23,24 +24,37 @@ pub mod login {
     ///
     /// Login also can not contain any whitespace
     pub fn validate(username: &str, password: &str) -> LoginValidation {
-        let u_chars = username.chars().count();
-        let p_chars = password.chars().count();
+        let username = validate_username(username);
+        if !username.is_valid() {
+            return username;
+        }
+        validate_password(password)
+    }
 
+    /// The username rules of `validate`, the server checks these too
+    pub fn validate_username(username: &str) -> LoginValidation {
+        let u_chars = username.chars().count();
         if u_chars < USERNAME_MIN {
             return LoginValidation::UsernameTooShort;
         }
         if u_chars > USERNAME_MAX {
             return LoginValidation::UsernameTooLong;
         }
+        if username.chars().any(|c| c.is_whitespace()) {
+            return LoginValidation::UsernameContainsWhitespace;
+        }
+        LoginValidation::Valid
+    }
+
+    /// The password rules of `validate`, only the client sees the password itself
+    pub fn validate_password(password: &str) -> LoginValidation {
+        let p_chars = password.chars().count();
         if p_chars < PASSWORD_MIN {
             return LoginValidation::PasswordTooShort;
         }
         if p_chars > PASSWORD_MAX {
             return LoginValidation::PasswordTooLong;
         }
-        if username.chars().any(|c| c.is_whitespace()) {
-            return LoginValidation::UsernameContainsWhitespace;
-        }
         if password.chars().any(|c| c.is_whitespace()) {
             return LoginValidation::PasswordContainsWhitespace;
         }
@@ -80,13 +94,13 @@ pub mod login {
 
     impl LoginValidation {
         pub fn is_valid(&self) -> bool {
-            match self {
-                LoginValidation::Valid => true,
-                _ => false,
-            }
+            matches!(self, LoginValidation::Valid)
         }
     }
 
+    /// Length of `hash_password` output, what the server gets instead of the password
+    pub const PASSWORD_HASH_SIZE: usize = 32;
+
     pub fn hash_password(password: &str) -> Vec<u8> {
         use sha2::{Digest, Sha256};
         let mut hasher = Sha256::new();
@@ -97,12 +111,30 @@ pub mod login {
 
 /// helper module for validating and defining the connection protocol
 pub mod connection_protocol {
-    use std::io::Read;
+    pub use chunk_serde::{from_chunks_bytes, to_chunks_bytes, ChunkSerdeError};
+    pub use chunked_derive::Chunked;
 
-    use tokio::{io::AsyncReadExt, net::TcpStream};
+    mod chunk_serde;
+
+    use bytes::BytesMut;
+    use tokio::io::{AsyncRead, AsyncReadExt};
+    use tokio_util::codec::{Decoder, Encoder, Framed};
 
     /// The default protocol for the connection
-    pub const CONTAINER: &'static [Chunks] = &[
+    pub const CONTAINER: &[Chunks] = &[
+        // head
+        Chunks::Uint { size: 8 },
+        // request id
+        Chunks::Uint { size: 8 },
+        // body
+        Chunks::Binary,
+    ];
+
+    /// The layout of `CONTAINER` before version 2, without the request id
+    ///
+    /// note: the `Message::Hello` exchange always uses this layout, so a client of any version
+    /// can read the `ErrorCode::ProtocolMismatch` that refuses it, see `MessageCodec::handshake`
+    pub const HANDSHAKE_CONTAINER: &[Chunks] = &[
         // head
         Chunks::Uint { size: 8 },
         // body
@@ -110,7 +142,7 @@ pub mod connection_protocol {
     ];
 
     /// The default protocol for the database user entry
-    pub const DB_USER: &'static [Chunks] = &[
+    pub const DB_USER: &[Chunks] = &[
         // username
         Chunks::String,
         // password hash
@@ -118,21 +150,21 @@ pub mod connection_protocol {
         // player id
         Chunks::Uint { size: 8 },
         // friends
-        //
-        // just an array of ids
-        Chunks::Binary,
+        Chunks::List(&[Chunks::Uint { size: 8 }]),
         // quote
         Chunks::String,
         // funds
         Chunks::Uint { size: 8 },
         // card collection
-        //
-        // just an array of (id, amount) tuples
-        Chunks::Binary,
+        Chunks::List(&[Chunks::Struct(DB_OWNED_CARD)]),
+        // ids of the players that sent a friend request
+        Chunks::List(&[Chunks::Uint { size: 8 }]),
+        // ids of the players a friend request was sent to
+        Chunks::List(&[Chunks::Uint { size: 8 }]),
     ];
 
     /// The default protocol for the database card entry
-    pub const DB_OWNED_CARD: &'static [Chunks] = &[
+    pub const DB_OWNED_CARD: &[Chunks] = &[
         // card id
         Chunks::Uint { size: 8 },
         // amount
@@ -140,63 +172,133 @@ pub mod connection_protocol {
     ];
 
     /// Expands the clientdata struct into a protocol
-    pub const CLIENT_DATA: &'static [Chunks] = &[
+    pub const CLIENT_DATA: &[Chunks] = <ClientData as Chunked>::SCHEMA;
+
+    /// The default protocol for the friend summary
+    pub const FRIEND_SUMMARY: &[Chunks] = <Friend as Chunked>::SCHEMA;
+
+    /// The default protocol for login and registration
+    pub const LOGIN: &[Chunks] = &[
         // username
         Chunks::String,
-        // friends
+        // password hash
         Chunks::Binary,
-        // funds
-        Chunks::Uint { size: 8 },
-        // status
-        Chunks::Uint { size: 8 },
-        // quote
-        Chunks::String,
     ];
 
-    /// The default protocol for the friend summary
-    pub const FRIEND_SUMMARY: &'static [Chunks] = &[
-        // username
+    /// The protocol for resuming a session
+    pub const RESUME: &[Chunks] = &[
+        // session token
+        Chunks::Binary,
+    ];
+
+    /// The protocol for changing the password
+    pub const CHANGE_PASSWORD: &[Chunks] = &[
+        // current password hash
+        Chunks::Binary,
+        // new password hash
+        Chunks::Binary,
+    ];
+
+    /// The protocol for friend requests and their answers
+    pub const FRIEND: &[Chunks] = &[
+        // username of the other player
         Chunks::String,
+    ];
+
+    /// The protocol for changing the quote
+    pub const QUOTE: &[Chunks] = &[
         // quote
         Chunks::String,
-        // status
+    ];
+
+    /// The protocol for choosing a status
+    pub const SET_STATUS: &[Chunks] = &[
+        // status, see `PlayerStatus::to_uint`
         Chunks::Uint { size: 1 },
     ];
 
-    /// The default protocol for login and registration
-    pub const LOGIN: &'static [Chunks] = &[
+    /// The protocol for quote changes of friends
+    pub const FRIEND_QUOTE: &[Chunks] = &[
         // username
         Chunks::String,
-        // password hash
-        Chunks::Binary,
+        // quote
+        Chunks::String,
     ];
 
-    /// The default protocol for saving the DB
-    pub const LOAD: &'static [Chunks] = &[
+    /// The protocol for status changes of friends
+    pub const FRIEND_STATUS: &[Chunks] = &[
         // username
         Chunks::String,
-        // password
-        Chunks::String,
-        // auto login
-        Chunks::Bool,
-        // server ip
-        Chunks::String,
+        // status, see `PlayerStatus::to_uint`
+        Chunks::Uint { size: 1 },
     ];
 
     /// The default protocol for status messages
-    pub const STATUS: &'static [Chunks] = &[
+    pub const STATUS: &[Chunks] = &[
         // player id
         Chunks::Uint { size: 8 },
-        // flag for message
-        Chunks::Bool,
         // message
-        Chunks::Binary,
+        Chunks::Optional(&Chunks::Binary),
+    ];
+
+    /// The default protocol for the handshake
+    pub const HELLO: &[Chunks] = &[
+        // protocol version
+        Chunks::Uint { size: 8 },
+        // client name
+        Chunks::String,
+        // capabilities
+        Chunks::List(CAPABILITY),
+    ];
+
+    /// The protocol of a single capability inside `HELLO`
+    pub const CAPABILITY: &[Chunks] = &[Chunks::String];
+
+    /// Version of the connection protocol, bumped on every incompatible change
+    pub const PROTOCOL_VERSION: u64 = 2;
+
+    /// Oldest protocol version this build can still talk to
+    ///
+    /// note: version 2 added the request id to `CONTAINER`, older clients still get their
+    /// `ErrorCode::ProtocolMismatch` in the layout they know, see `HANDSHAKE_CONTAINER`
+    pub const MIN_PROTOCOL_VERSION: u64 = 2;
+
+    /// Optional features of this build, announced in `Message::Hello`
+    ///
+    /// note: `presence` clients get `Message::FriendStatusChanged` pushes, `friends` clients
+    /// get friend requests and their answers pushed, `profile` clients get
+    /// `Message::FriendQuoteChanged` pushes
+    pub const CAPABILITIES: &[&str] = &[
+        "login", "register", "resume", "presence", "friends", "profile",
     ];
 
+    /// Checks if a peer speaking `version` can be talked to
+    pub fn is_compatible(version: u64) -> bool {
+        (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version)
+    }
+
+    /// Capabilities supported by both sides
+    pub fn common_capabilities(theirs: &[String]) -> Vec<String> {
+        CAPABILITIES
+            .iter()
+            .filter(|cap| theirs.iter().any(|their| their == *cap))
+            .map(|cap| cap.to_string())
+            .collect()
+    }
+
+    /// Our side of the handshake
+    pub fn hello(name: &str) -> Message {
+        Message::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            client_name: name.to_string(),
+            capabilities: CAPABILITIES.iter().map(|cap| cap.to_string()).collect(),
+        }
+    }
+
     /// The default protocol for dynamic messages
     ///
     /// note: set mode to unchecked to get better performance and more predictable results
-    pub const DYNAMIC: &'static [Chunks] = &[Chunks::Rest];
+    pub const DYNAMIC: &[Chunks] = &[Chunks::Rest];
 
     /// Holds the connection protocol
     pub struct ConnectionProtocol {
@@ -362,6 +464,112 @@ pub mod connection_protocol {
             self
         }
 
+        /// Writer for a nested protocol, inherits the unchecked mode
+        fn nested(&self, protocol: &'static [Chunks]) -> ConnectionWriter {
+            let writer = ConnectionWriter::new(protocol);
+            if self.unchecked {
+                writer.unchecked()
+            } else {
+                writer
+            }
+        }
+
+        /// Take the bytes of a nested writer
+        fn finish_nested(&self, mut writer: ConnectionWriter) -> Vec<u8> {
+            if self.unchecked {
+                writer.finalize_unchecked()
+            } else {
+                writer.finalize()
+            }
+        }
+
+        /// Write a list to the message, each item is written by `write_item` using the protocol of the list
+        pub fn write_list<T>(
+            &mut self,
+            items: &[T],
+            mut write_item: impl FnMut(&mut ConnectionWriter, &T),
+        ) -> &mut Self {
+            let inner = if self.unchecked {
+                DYNAMIC
+            } else {
+                match &self.protocol[self.current_chunk] {
+                    Chunks::List(inner) => *inner,
+                    _ => panic!(
+                        "Invalid chunk type, expected {:?}, got list\nExecute protocol: {:?}",
+                        self.protocol[self.current_chunk], self.protocol
+                    ),
+                }
+            };
+            let mut body = Vec::new();
+            for item in items {
+                let mut writer = self.nested(inner);
+                write_item(&mut writer, item);
+                body.extend(self.finish_nested(writer));
+            }
+            self.msg.extend_from_slice(&(body.len() as u64).to_be_bytes());
+            self.msg.extend(body);
+            if !self.unchecked {
+                self.current_chunk += 1;
+            }
+            self
+        }
+
+        /// Write an optional value to the message, `write_value` is only called for `Some`
+        pub fn write_optional<T>(
+            &mut self,
+            value: Option<&T>,
+            write_value: impl FnOnce(&mut ConnectionWriter, &T),
+        ) -> &mut Self {
+            let inner = if self.unchecked {
+                DYNAMIC
+            } else {
+                match &self.protocol[self.current_chunk] {
+                    Chunks::Optional(inner) => std::slice::from_ref(*inner),
+                    _ => panic!(
+                        "Invalid chunk type, expected {:?}, got optional\nExecute protocol: {:?}",
+                        self.protocol[self.current_chunk], self.protocol
+                    ),
+                }
+            };
+            match value {
+                Some(value) => {
+                    self.msg.push(1);
+                    let mut writer = self.nested(inner);
+                    write_value(&mut writer, value);
+                    let bytes = self.finish_nested(writer);
+                    self.msg.extend(bytes);
+                }
+                None => self.msg.push(0),
+            }
+            if !self.unchecked {
+                self.current_chunk += 1;
+            }
+            self
+        }
+
+        /// Write a nested struct to the message using the protocol of the struct
+        pub fn write_struct(&mut self, write_fields: impl FnOnce(&mut ConnectionWriter)) -> &mut Self {
+            let inner = if self.unchecked {
+                DYNAMIC
+            } else {
+                match &self.protocol[self.current_chunk] {
+                    Chunks::Struct(inner) => *inner,
+                    _ => panic!(
+                        "Invalid chunk type, expected {:?}, got struct\nExecute protocol: {:?}",
+                        self.protocol[self.current_chunk], self.protocol
+                    ),
+                }
+            };
+            let mut writer = self.nested(inner);
+            write_fields(&mut writer);
+            let bytes = self.finish_nested(writer);
+            self.msg.extend(bytes);
+            if !self.unchecked {
+                self.current_chunk += 1;
+            }
+            self
+        }
+
         /// Finish writing the message
         pub fn finalize(&mut self) -> Vec<u8> {
             if self.current_chunk != self.protocol.len() {
@@ -404,240 +612,323 @@ pub mod connection_protocol {
             self
         }
 
-        /// Read an integer from the message
-        pub fn read_int(&mut self) -> i64 {
-            if self.unchecked {
-                let value = i64::from_be_bytes(
-                    self.msg[self.current_byte..self.current_byte + 8]
-                        .try_into()
-                        .unwrap(),
-                );
-                self.current_byte += 8;
-                println!("Int: {value}");
-                return value;
-            }
-            match &self.protocol[self.current_chunk] {
-                Chunks::Int { size } => {
-                    let value = i64::from_be_bytes(
-                        self.msg[self.current_byte..self.current_byte + *size as usize]
-                            .try_into()
-                            .unwrap(),
-                    );
-                    self.current_chunk += 1;
-                    self.current_byte += *size as usize;
-                    value
-                }
-                Chunks::Any | Chunks::Rest => {
-                    let value = i64::from_be_bytes(
-                        self.msg[self.current_byte..self.current_byte + 8]
-                            .try_into()
-                            .unwrap(),
-                    );
-                    self.current_byte += 8;
-                    if let Chunks::Any = self.protocol[self.current_chunk] {
-                        self.current_chunk += 1;
-                    }
-                    value
-                }
-                _ => panic!("Invalid chunk type"),
+        /// Build an error for the current position
+        fn error(&self, kind: ReadErrorKind) -> ReadError {
+            ReadError {
+                offset: self.current_byte,
+                chunk: self.current_chunk,
+                expected: self.protocol.get(self.current_chunk).copied(),
+                kind,
             }
         }
 
-        /// Read a float from the message
-        pub fn read_float(&mut self) -> f64 {
+        /// Check that the protocol allows reading `requested` at the current chunk
+        ///
+        /// Returns `None` in unchecked mode
+        fn expect_chunk(
+            &self,
+            requested: &'static str,
+            accepts: fn(&Chunks) -> bool,
+        ) -> Result<Option<&'static Chunks>, ReadError> {
             if self.unchecked {
-                let value = f64::from_be_bytes(
-                    self.msg[self.current_byte..self.current_byte + 8]
-                        .try_into()
-                        .unwrap(),
-                );
-                self.current_byte += 8;
-                println!("Float: {value}");
-                return value;
+                return Ok(None);
             }
-            match &self.protocol[self.current_chunk] {
-                Chunks::Float => {
-                    let value = f64::from_be_bytes(
-                        self.msg[self.current_byte..self.current_byte + 8]
-                            .try_into()
-                            .unwrap(),
-                    );
-                    self.current_byte += 8;
-                    self.current_chunk += 1;
-                    value
-                }
-                Chunks::Any | Chunks::Rest => {
-                    let value = f64::from_be_bytes(
-                        self.msg[self.current_byte..self.current_byte + 8]
-                            .try_into()
-                            .unwrap(),
-                    );
-                    self.current_byte += 8;
-                    if let Chunks::Any = self.protocol[self.current_chunk] {
-                        self.current_chunk += 1;
-                    }
-                    value
-                }
-                _ => panic!("Invalid chunk type"),
+            let chunk = match self.protocol.get(self.current_chunk) {
+                Some(chunk) => chunk,
+                None => return Err(self.error(ReadErrorKind::EndOfProtocol)),
+            };
+            match chunk {
+                Chunks::Any | Chunks::Rest => Ok(Some(chunk)),
+                _ if accepts(chunk) => Ok(Some(chunk)),
+                _ => Err(self.error(ReadErrorKind::TypeMismatch { requested })),
             }
         }
 
-        /// Read an unsigned integer from the message
-        pub fn read_uint(&mut self) -> u64 {
-            if self.unchecked {
-                let value = u64::from_be_bytes(
-                    self.msg[self.current_byte..self.current_byte + 8]
-                        .try_into()
-                        .unwrap(),
-                );
-                self.current_byte += 8;
-                println!("Uint: {value}");
-                return value;
+        /// Move past a chunk returned by `expect_chunk`
+        fn next_chunk(&mut self, chunk: Option<&'static Chunks>) {
+            match chunk {
+                None | Some(Chunks::Rest) => (),
+                Some(_) => self.current_chunk += 1,
             }
-            match &self.protocol[self.current_chunk] {
-                Chunks::Uint { size } => {
-                    let value = u64::from_be_bytes(
-                        self.msg[self.current_byte..self.current_byte + *size as usize]
-                            .try_into()
-                            .unwrap(),
-                    );
-                    self.current_byte += *size as usize;
-                    self.current_chunk += 1;
-                    value
-                }
-                Chunks::Any | Chunks::Rest => {
-                    let value = u64::from_be_bytes(
-                        self.msg[self.current_byte..self.current_byte + 8]
-                            .try_into()
-                            .unwrap(),
-                    );
-                    self.current_byte += 8;
-                    if let Chunks::Any = self.protocol[self.current_chunk] {
-                        self.current_chunk += 1;
-                    }
-                    value
+        }
+
+        /// Take the next `len` bytes of the message
+        fn take(&mut self, len: usize) -> Result<&'a [u8], ReadError> {
+            let available = self.msg.len().saturating_sub(self.current_byte);
+            if len > available {
+                return Err(self.error(ReadErrorKind::UnexpectedEnd {
+                    needed: len,
+                    available,
+                }));
+            }
+            let bytes = &self.msg[self.current_byte..self.current_byte + len];
+            self.current_byte += len;
+            Ok(bytes)
+        }
+
+        /// Take a size section (8 bytes) and the data section that follows it
+        fn take_sized(&mut self) -> Result<&'a [u8], ReadError> {
+            let start = self.current_byte;
+            let size = u64::from_be_bytes(self.take(8)?.try_into().unwrap());
+            let size = match usize::try_from(size) {
+                Ok(size) => size,
+                Err(_) => usize::MAX,
+            };
+            match self.take(size) {
+                Ok(bytes) => Ok(bytes),
+                Err(mut e) => {
+                    self.current_byte = start;
+                    e.offset = start;
+                    Err(e)
                 }
-                _ => panic!("Invalid chunk type"),
             }
         }
 
-        /// Read a string from the message
-        pub fn read_string(&mut self) -> String {
-            if self.unchecked {
-                let size = u64::from_be_bytes(
-                    self.msg[self.current_byte..self.current_byte + 8]
-                        .try_into()
-                        .unwrap(),
-                );
-                self.current_byte += 8;
-                let value = String::from_utf8(
-                    self.msg[self.current_byte..self.current_byte + size as usize].to_vec(),
-                )
-                .unwrap();
-                self.current_byte += size as usize;
-                return value;
+        /// Take `size` bytes and pad them to 8 bytes, extending the sign if asked to
+        fn take_padded(&mut self, size: usize, signed: bool) -> Result<[u8; 8], ReadError> {
+            if size > 8 {
+                return Err(self.error(ReadErrorKind::InvalidValue));
             }
-            match &self.protocol[self.current_chunk] {
-                Chunks::String => {
-                    println!("Current byte: {}", self.current_byte);
-                    println!("Current chunk: {}", self.current_chunk);
-                    let size = u64::from_be_bytes(
-                        self.msg[self.current_byte..self.current_byte + 8]
-                            .try_into()
-                            .unwrap(),
-                    );
-                    self.current_byte += 8;
-                    let value = String::from_utf8(
-                        self.msg[self.current_byte..self.current_byte + size as usize].to_vec(),
-                    )
-                    .unwrap();
-                    self.current_byte += size as usize;
-                    self.current_chunk += 1;
-                    value
+            let bytes = self.take(size)?;
+            let negative = signed && bytes.first().is_some_and(|b| b & 0x80 != 0);
+            let mut value = if negative { [0xff; 8] } else { [0; 8] };
+            value[8 - size..].copy_from_slice(bytes);
+            Ok(value)
+        }
+
+        /// Read an integer from the message
+        pub fn try_read_int(&mut self) -> Result<i64, ReadError> {
+            let chunk = self.expect_chunk("int", |c| matches!(c, Chunks::Int { .. }))?;
+            let size = match chunk {
+                Some(Chunks::Int { size }) => *size as usize,
+                _ => 8,
+            };
+            let value = i64::from_be_bytes(self.take_padded(size, true)?);
+            self.next_chunk(chunk);
+            Ok(value)
+        }
+
+        /// Read a float from the message
+        pub fn try_read_float(&mut self) -> Result<f64, ReadError> {
+            let chunk = self.expect_chunk("float", |c| matches!(c, Chunks::Float))?;
+            let value = f64::from_be_bytes(self.take(8)?.try_into().unwrap());
+            self.next_chunk(chunk);
+            Ok(value)
+        }
+
+        /// Read an unsigned integer from the message
+        pub fn try_read_uint(&mut self) -> Result<u64, ReadError> {
+            let chunk = self.expect_chunk("uint", |c| matches!(c, Chunks::Uint { .. }))?;
+            let size = match chunk {
+                Some(Chunks::Uint { size }) => *size as usize,
+                _ => 8,
+            };
+            let value = u64::from_be_bytes(self.take_padded(size, false)?);
+            self.next_chunk(chunk);
+            Ok(value)
+        }
+
+        /// Read a string from the message
+        pub fn try_read_string(&mut self) -> Result<String, ReadError> {
+            let chunk = self.expect_chunk("string", |c| matches!(c, Chunks::String))?;
+            let start = self.current_byte;
+            let bytes = match chunk {
+                Some(Chunks::Any | Chunks::Rest) => self.take(8)?,
+                _ => self.take_sized()?,
+            };
+            match std::str::from_utf8(bytes) {
+                Ok(value) => {
+                    self.next_chunk(chunk);
+                    Ok(value.to_string())
                 }
-                Chunks::Any | Chunks::Rest => {
-                    let value = String::from_utf8(
-                        self.msg[self.current_byte..self.current_byte + 8].to_vec(),
-                    )
-                    .unwrap();
-                    self.current_byte += 8;
-                    if let Chunks::Any = self.protocol[self.current_chunk] {
-                        self.current_chunk += 1;
-                    }
-                    value
+                Err(_) => {
+                    self.current_byte = start;
+                    Err(self.error(ReadErrorKind::InvalidUtf8))
                 }
-                _ => panic!("Invalid chunk type"),
             }
         }
 
         /// Read a binary from the message
-        pub fn read_binary(&mut self) -> Vec<u8> {
-            if self.unchecked {
-                let size = u64::from_be_bytes(
-                    self.msg[self.current_byte..self.current_byte + 8]
-                        .try_into()
-                        .unwrap(),
-                );
-                self.current_byte += 8;
-                let value = self.msg[self.current_byte..self.current_byte + size as usize].to_vec();
-                self.current_byte += size as usize;
-                println!("Size: {size}");
-                println!("Binary: {:x?}", value);
-                return value;
+        pub fn try_read_binary(&mut self) -> Result<Vec<u8>, ReadError> {
+            let chunk = self.expect_chunk("binary", |c| matches!(c, Chunks::Binary))?;
+            let bytes = match chunk {
+                Some(Chunks::Any | Chunks::Rest) => self.take(8)?,
+                _ => self.take_sized()?,
+            };
+            self.next_chunk(chunk);
+            Ok(bytes.to_vec())
+        }
+
+        /// Read a boolean from the message
+        pub fn try_read_bool(&mut self) -> Result<bool, ReadError> {
+            let chunk = self.expect_chunk("bool", |c| matches!(c, Chunks::Bool))?;
+            let size = match chunk {
+                Some(Chunks::Any | Chunks::Rest) => 8,
+                _ => 1,
+            };
+            let value = self.take(size)?[0] != 0;
+            self.next_chunk(chunk);
+            Ok(value)
+        }
+
+        /// Reader for a nested protocol starting at the current byte and ending at `end`
+        fn nested(&self, protocol: &'static [Chunks], end: usize) -> ConnectionReader<'a> {
+            ConnectionReader {
+                current_byte: self.current_byte,
+                current_chunk: 0,
+                msg: &self.msg[..end],
+                protocol,
+                unchecked: self.unchecked,
             }
-            match &self.protocol[self.current_chunk] {
-                Chunks::Binary => {
-                    println!("Current byte: {}", self.current_byte);
-                    println!("Current chunk: {}", self.current_chunk);
-                    let size = u64::from_be_bytes(
-                        self.msg[self.current_byte..self.current_byte + 8]
-                            .try_into()
-                            .unwrap(),
-                    );
-                    self.current_byte += 8;
-                    let value =
-                        self.msg[self.current_byte..self.current_byte + size as usize].to_vec();
-                    self.current_byte += size as usize;
-                    self.current_chunk += 1;
-                    value
-                }
-                Chunks::Any | Chunks::Rest => {
-                    let value = self.msg[self.current_byte..self.current_byte + 8].to_vec();
-                    self.current_byte += 8;
-                    if let Chunks::Any = self.protocol[self.current_chunk] {
-                        self.current_chunk += 1;
+        }
+
+        /// Read a nested value and move past it
+        fn read_nested<T>(
+            &mut self,
+            protocol: &'static [Chunks],
+            read: impl FnOnce(&mut ConnectionReader<'a>) -> Result<T, ReadError>,
+        ) -> Result<T, ReadError> {
+            let mut reader = self.nested(protocol, self.msg.len());
+            let value = read(&mut reader)?;
+            if !reader.unchecked {
+                reader.try_finalize()?;
+            }
+            self.current_byte = reader.current_byte;
+            Ok(value)
+        }
+
+        /// Read a list from the message, each item is read by `read_item` using the protocol of the list
+        pub fn try_read_list<T>(
+            &mut self,
+            mut read_item: impl FnMut(&mut ConnectionReader<'a>) -> Result<T, ReadError>,
+        ) -> Result<Vec<T>, ReadError> {
+            let chunk = self.expect_chunk("list", |c| matches!(c, Chunks::List(_)))?;
+            let inner = match chunk {
+                Some(Chunks::List(inner)) => *inner,
+                _ => DYNAMIC,
+            };
+            let start = self.current_byte;
+            let end = start + 8 + self.take_sized()?.len();
+            let mut reader = self.nested(inner, end);
+            reader.current_byte = start + 8;
+            let mut items = Vec::new();
+            while reader.current_byte < end {
+                let item_start = reader.current_byte;
+                reader.current_chunk = 0;
+                let item = read_item(&mut reader).and_then(|item| {
+                    if !reader.unchecked {
+                        reader.try_finalize()?;
+                    }
+                    if reader.current_byte == item_start {
+                        // an empty item would repeat forever
+                        return Err(reader.error(ReadErrorKind::InvalidValue));
+                    }
+                    Ok(item)
+                });
+                match item {
+                    Ok(item) => items.push(item),
+                    Err(e) => {
+                        self.current_byte = start;
+                        return Err(e);
                     }
-                    value
                 }
-                _ => panic!("Invalid chunk type"),
             }
+            self.next_chunk(chunk);
+            Ok(items)
         }
 
-        /// Read a boolean from the message
-        pub fn read_bool(&mut self) -> bool {
-            if self.unchecked {
-                let value = self.msg[self.current_byte] != 0;
-                self.current_byte += 1;
-                println!("Bool: {value}");
-                return value;
-            }
-            match &self.protocol[self.current_chunk] {
-                Chunks::Bool => {
-                    let value = self.msg[self.current_byte] != 0;
-                    self.current_byte += 1;
-                    self.current_chunk += 1;
-                    value
-                }
-                Chunks::Any | Chunks::Rest => {
-                    let value = self.msg[self.current_byte] != 0;
-                    self.current_byte += 8;
-                    if let Chunks::Any = self.protocol[self.current_chunk] {
-                        self.current_chunk += 1;
+        /// Read an optional value from the message, `read_value` is only called if the flag is set
+        pub fn try_read_optional<T>(
+            &mut self,
+            read_value: impl FnOnce(&mut ConnectionReader<'a>) -> Result<T, ReadError>,
+        ) -> Result<Option<T>, ReadError> {
+            let chunk = self.expect_chunk("optional", |c| matches!(c, Chunks::Optional(_)))?;
+            let inner = match chunk {
+                Some(Chunks::Optional(inner)) => std::slice::from_ref(*inner),
+                _ => DYNAMIC,
+            };
+            let start = self.current_byte;
+            let value = match self.take(1)?[0] {
+                0 => None,
+                1 => match self.read_nested(inner, read_value) {
+                    Ok(value) => Some(value),
+                    Err(e) => {
+                        self.current_byte = start;
+                        return Err(e);
                     }
-                    value
+                },
+                _ => {
+                    self.current_byte = start;
+                    return Err(self.error(ReadErrorKind::InvalidValue));
                 }
-                _ => panic!("Invalid chunk type"),
+            };
+            self.next_chunk(chunk);
+            Ok(value)
+        }
+
+        /// Read a nested struct from the message using the protocol of the struct
+        pub fn try_read_struct<T>(
+            &mut self,
+            read_fields: impl FnOnce(&mut ConnectionReader<'a>) -> Result<T, ReadError>,
+        ) -> Result<T, ReadError> {
+            let chunk = self.expect_chunk("struct", |c| matches!(c, Chunks::Struct(_)))?;
+            let inner = match chunk {
+                Some(Chunks::Struct(inner)) => *inner,
+                _ => DYNAMIC,
+            };
+            let value = self.read_nested(inner, read_fields)?;
+            self.next_chunk(chunk);
+            Ok(value)
+        }
+
+        /// Read an integer from the message
+        ///
+        /// Panics if the message does not match the protocol, see `try_read_int`
+        pub fn read_int(&mut self) -> i64 {
+            self.try_read_int().unwrap_or_else(|e| panic!("{e}"))
+        }
+
+        /// Read a float from the message
+        ///
+        /// Panics if the message does not match the protocol, see `try_read_float`
+        pub fn read_float(&mut self) -> f64 {
+            self.try_read_float().unwrap_or_else(|e| panic!("{e}"))
+        }
+
+        /// Read an unsigned integer from the message
+        ///
+        /// Panics if the message does not match the protocol, see `try_read_uint`
+        pub fn read_uint(&mut self) -> u64 {
+            self.try_read_uint().unwrap_or_else(|e| panic!("{e}"))
+        }
+
+        /// Read a string from the message
+        ///
+        /// Panics if the message does not match the protocol, see `try_read_string`
+        pub fn read_string(&mut self) -> String {
+            self.try_read_string().unwrap_or_else(|e| panic!("{e}"))
+        }
+
+        /// Read a binary from the message
+        ///
+        /// Panics if the message does not match the protocol, see `try_read_binary`
+        pub fn read_binary(&mut self) -> Vec<u8> {
+            self.try_read_binary().unwrap_or_else(|e| panic!("{e}"))
+        }
+
+        /// Read a boolean from the message
+        ///
+        /// Panics if the message does not match the protocol, see `try_read_bool`
+        pub fn read_bool(&mut self) -> bool {
+            self.try_read_bool().unwrap_or_else(|e| panic!("{e}"))
+        }
+
+        /// Check that all chunks were read
+        pub fn try_finalize(&self) -> Result<(), ReadError> {
+            if self.current_chunk != self.protocol.len() {
+                return Err(self.error(ReadErrorKind::Incomplete));
             }
+            Ok(())
         }
 
         /// Finish reading the message
@@ -654,50 +945,52 @@ pub mod connection_protocol {
         }
 
         /// skips data in the current chunk
-        pub fn skip(&mut self) {
-            match &self.protocol[self.current_chunk] {
-                Chunks::Int { size } => {
-                    self.current_byte += *size as usize;
-                    self.current_chunk += 1;
+        pub fn try_skip(&mut self) -> Result<(), ReadError> {
+            let chunk = match self.protocol.get(self.current_chunk) {
+                Some(chunk) => chunk,
+                None => return Err(self.error(ReadErrorKind::EndOfProtocol)),
+            };
+            match chunk {
+                Chunks::Int { size } | Chunks::Uint { size } => {
+                    self.take(*size as usize)?;
                 }
-                Chunks::Float => {
-                    self.current_byte += 8;
-                    self.current_chunk += 1;
-                }
-                Chunks::Uint { size } => {
-                    self.current_byte += *size as usize;
-                    self.current_chunk += 1;
-                }
-                Chunks::String => {
-                    let size = u64::from_be_bytes(
-                        self.msg[self.current_byte..self.current_byte + 8]
-                            .try_into()
-                            .unwrap(),
-                    );
-                    self.current_byte += 8 + size as usize;
-                    self.current_chunk += 1;
+                Chunks::Float | Chunks::Any | Chunks::Rest => {
+                    self.take(8)?;
                 }
-                Chunks::Binary => {
-                    let size = u64::from_be_bytes(
-                        self.msg[self.current_byte..self.current_byte + 8]
-                            .try_into()
-                            .unwrap(),
-                    );
-                    self.current_byte += 8 + size as usize;
-                    self.current_chunk += 1;
+                Chunks::String | Chunks::Binary | Chunks::List(_) => {
+                    self.take_sized()?;
                 }
                 Chunks::Bool => {
-                    self.current_byte += 1;
-                    self.current_chunk += 1;
+                    self.take(1)?;
                 }
-                Chunks::Any => {
-                    self.current_byte += 8;
-                    self.current_chunk += 1;
+                Chunks::Optional(inner) => {
+                    let start = self.current_byte;
+                    if self.take(1)?[0] != 0 {
+                        let skipped = self.read_nested(std::slice::from_ref(*inner), |reader| reader.try_skip());
+                        if let Err(e) = skipped {
+                            self.current_byte = start;
+                            return Err(e);
+                        }
+                    }
                 }
-                Chunks::Rest => {
-                    self.current_byte += 8;
+                Chunks::Struct(inner) => {
+                    self.read_nested(inner, |reader| {
+                        while reader.current_chunk < reader.protocol.len() {
+                            reader.try_skip()?;
+                        }
+                        Ok(())
+                    })?;
                 }
             }
+            self.next_chunk(Some(chunk));
+            Ok(())
+        }
+
+        /// skips data in the current chunk
+        ///
+        /// Panics if the message does not match the protocol, see `try_skip`
+        pub fn skip(&mut self) {
+            self.try_skip().unwrap_or_else(|e| panic!("{e}"))
         }
 
         /// advances to the next chunk without skipping data
@@ -706,31 +999,256 @@ pub mod connection_protocol {
         }
     }
 
-    /// Defines each chunk of the connection protocol that can be sent or received
-    #[derive(Debug, PartialEq)]
-    pub enum Chunks {
-        /// Integer with a defined size
-        Int { size: u8 },
-        /// Float with a constant size of 8 bits
-        Float,
-        /// Unsigned integer with a defined size
-        Uint { size: u8 },
-        /// Unicode data Split into size section(8 bytes) and data section(size bytes)
-        String,
-        /// Binary data split into size section(8 bytes) and data section(size bytes)
-        Binary,
+    /// Error returned by the `try_*` methods of `ConnectionReader`
+    #[derive(Debug, PartialEq, Clone)]
+    pub struct ReadError {
+        /// Byte offset into the message where the chunk starts
+        pub offset: usize,
+        /// Index of the chunk in the protocol
+        pub chunk: usize,
+        /// The chunk the protocol defines at this index (`None` past the end of the protocol)
+        pub expected: Option<Chunks>,
+        pub kind: ReadErrorKind,
+    }
+
+    /// What went wrong while reading a chunk
+    #[derive(Debug, PartialEq, Clone)]
+    pub enum ReadErrorKind {
+        /// The message ended before the chunk did
+        UnexpectedEnd { needed: usize, available: usize },
+        /// A string chunk is not valid UTF-8
+        InvalidUtf8,
+        /// The protocol defines a different chunk type than was requested
+        TypeMismatch { requested: &'static str },
+        /// Every chunk of the protocol was already read
+        EndOfProtocol,
+        /// Not every chunk of the protocol was read
+        Incomplete,
+        /// The chunk holds a value that can't be represented
+        InvalidValue,
+    }
+
+    impl std::fmt::Display for ReadError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(
+                f,
+                "failed to read chunk {} ({:?}) at byte {}: ",
+                self.chunk, self.expected, self.offset
+            )?;
+            match &self.kind {
+                ReadErrorKind::UnexpectedEnd { needed, available } => write!(
+                    f,
+                    "message ended early, needed {needed} bytes, {available} left"
+                ),
+                ReadErrorKind::InvalidUtf8 => write!(f, "string is not valid utf-8"),
+                ReadErrorKind::TypeMismatch { requested } => {
+                    write!(f, "requested {requested}")
+                }
+                ReadErrorKind::EndOfProtocol => write!(f, "all chunks were already read"),
+                ReadErrorKind::Incomplete => write!(f, "not all chunks were read"),
+                ReadErrorKind::InvalidValue => write!(f, "invalid value"),
+            }
+        }
+    }
+
+    impl std::error::Error for ReadError {}
+
+    /// Defines each chunk of the connection protocol that can be sent or received
+    #[derive(Debug, PartialEq, Clone, Copy)]
+    pub enum Chunks {
+        /// Integer with a defined size
+        Int { size: u8 },
+        /// Float with a constant size of 8 bits
+        Float,
+        /// Unsigned integer with a defined size
+        Uint { size: u8 },
+        /// Unicode data Split into size section(8 bytes) and data section(size bytes)
+        String,
+        /// Binary data split into size section(8 bytes) and data section(size bytes)
+        Binary,
         /// Boolean (1 byte)
         Bool,
         /// Any data (8 bytes)
         Any,
         /// Rest of the data (8 bytes per chunk)
         Rest,
+        /// Items described by the inner protocol, split into size section(8 bytes, length of all items in bytes) and the items
+        List(&'static [Chunks]),
+        /// Flag (1 byte) followed by the inner chunk if the flag is set
+        Optional(&'static Chunks),
+        /// Inner protocol written in place
+        Struct(&'static [Chunks]),
+    }
+
+    /// A type that is written as a single chunk
+    pub trait ChunkField: Sized {
+        /// The chunk describing the type
+        const CHUNK: Chunks;
+
+        /// Write the value as the current chunk
+        fn write_field(&self, writer: &mut ConnectionWriter);
+
+        /// Read the value from the current chunk
+        fn read_field(reader: &mut ConnectionReader<'_>) -> Result<Self, ReadError>;
+    }
+
+    /// A type with its own protocol, derive it with `#[derive(Chunked)]`
+    pub trait Chunked: Sized {
+        /// The protocol of the type, one chunk per field
+        const SCHEMA: &'static [Chunks];
+
+        /// Write every field of the type
+        fn write_chunks(&self, writer: &mut ConnectionWriter);
+
+        /// Read every field of the type
+        fn read_chunks(reader: &mut ConnectionReader<'_>) -> Result<Self, ReadError>;
+
+        /// Write the value as a standalone message
+        fn to_chunks(&self) -> Vec<u8> {
+            let mut writer = ConnectionWriter::new(Self::SCHEMA);
+            self.write_chunks(&mut writer);
+            writer.finalize()
+        }
+
+        /// Read a value written by `to_chunks`
+        fn from_chunks(bytes: &[u8]) -> Result<Self, ReadError> {
+            let mut reader = ConnectionReader::new(Self::SCHEMA, bytes);
+            let value = Self::read_chunks(&mut reader)?;
+            reader.try_finalize()?;
+            Ok(value)
+        }
+    }
+
+    macro_rules! uint_field {
+        ($($ty:ty => $size:expr),*) => {$(
+            impl ChunkField for $ty {
+                const CHUNK: Chunks = Chunks::Uint { size: $size };
+
+                fn write_field(&self, writer: &mut ConnectionWriter) {
+                    writer.write_uint(*self as u64);
+                }
+
+                fn read_field(reader: &mut ConnectionReader<'_>) -> Result<Self, ReadError> {
+                    let value = reader.try_read_uint()?;
+                    <$ty>::try_from(value).map_err(|_| reader.error(ReadErrorKind::InvalidValue))
+                }
+            }
+        )*};
+    }
+
+    macro_rules! int_field {
+        ($($ty:ty => $size:expr),*) => {$(
+            impl ChunkField for $ty {
+                const CHUNK: Chunks = Chunks::Int { size: $size };
+
+                fn write_field(&self, writer: &mut ConnectionWriter) {
+                    writer.write_int(*self as i64);
+                }
+
+                fn read_field(reader: &mut ConnectionReader<'_>) -> Result<Self, ReadError> {
+                    let value = reader.try_read_int()?;
+                    <$ty>::try_from(value).map_err(|_| reader.error(ReadErrorKind::InvalidValue))
+                }
+            }
+        )*};
+    }
+
+    uint_field!(u8 => 1, u16 => 2, u32 => 4, u64 => 8);
+    int_field!(i8 => 1, i16 => 2, i32 => 4, i64 => 8);
+
+    impl ChunkField for f64 {
+        const CHUNK: Chunks = Chunks::Float;
+
+        fn write_field(&self, writer: &mut ConnectionWriter) {
+            writer.write_float(*self);
+        }
+
+        fn read_field(reader: &mut ConnectionReader<'_>) -> Result<Self, ReadError> {
+            reader.try_read_float()
+        }
+    }
+
+    impl ChunkField for bool {
+        const CHUNK: Chunks = Chunks::Bool;
+
+        fn write_field(&self, writer: &mut ConnectionWriter) {
+            writer.write_bool(*self);
+        }
+
+        fn read_field(reader: &mut ConnectionReader<'_>) -> Result<Self, ReadError> {
+            reader.try_read_bool()
+        }
+    }
+
+    impl ChunkField for String {
+        const CHUNK: Chunks = Chunks::String;
+
+        fn write_field(&self, writer: &mut ConnectionWriter) {
+            writer.write_string(self);
+        }
+
+        fn read_field(reader: &mut ConnectionReader<'_>) -> Result<Self, ReadError> {
+            reader.try_read_string()
+        }
+    }
+
+    impl<T: ChunkField> ChunkField for Vec<T> {
+        const CHUNK: Chunks = Chunks::List(&[T::CHUNK]);
+
+        fn write_field(&self, writer: &mut ConnectionWriter) {
+            writer.write_list(self, |writer, item| item.write_field(writer));
+        }
+
+        fn read_field(reader: &mut ConnectionReader<'_>) -> Result<Self, ReadError> {
+            reader.try_read_list(T::read_field)
+        }
+    }
+
+    impl<T: ChunkField> ChunkField for Option<T> {
+        const CHUNK: Chunks = Chunks::Optional(&T::CHUNK);
+
+        fn write_field(&self, writer: &mut ConnectionWriter) {
+            writer.write_optional(self.as_ref(), |writer, value| value.write_field(writer));
+        }
+
+        fn read_field(reader: &mut ConnectionReader<'_>) -> Result<Self, ReadError> {
+            reader.try_read_optional(T::read_field)
+        }
+    }
+
+    impl<A: ChunkField, B: ChunkField> ChunkField for (A, B) {
+        const CHUNK: Chunks = Chunks::Struct(&[A::CHUNK, B::CHUNK]);
+
+        fn write_field(&self, writer: &mut ConnectionWriter) {
+            writer.write_struct(|writer| {
+                self.0.write_field(writer);
+                self.1.write_field(writer);
+            });
+        }
+
+        fn read_field(reader: &mut ConnectionReader<'_>) -> Result<Self, ReadError> {
+            reader.try_read_struct(|reader| Ok((A::read_field(reader)?, B::read_field(reader)?)))
+        }
+    }
+
+    impl ChunkField for PlayerStatus {
+        const CHUNK: Chunks = Chunks::Uint { size: 8 };
+
+        fn write_field(&self, writer: &mut ConnectionWriter) {
+            writer.write_uint(self.to_uint());
+        }
+
+        fn read_field(reader: &mut ConnectionReader<'_>) -> Result<Self, ReadError> {
+            let value = reader.try_read_uint()?;
+            PlayerStatus::try_from_uint(value).ok_or_else(|| reader.error(ReadErrorKind::InvalidValue))
+        }
     }
 
     /// Defines the status of a player
-    #[derive(Debug, PartialEq, Clone)]
+    #[derive(Debug, PartialEq, Clone, Default)]
     pub enum PlayerStatus {
         Online,
+        #[default]
         Offline,
         Away,
         Busy,
@@ -742,21 +1260,28 @@ pub mod connection_protocol {
     }
 
     impl PlayerStatus {
-        pub fn from_uint(value: u64) -> Self {
+        pub fn try_from_uint(value: u64) -> Option<Self> {
             match value {
-                0 => PlayerStatus::Online,
-                1 => PlayerStatus::Offline,
-                2 => PlayerStatus::Away,
-                3 => PlayerStatus::Busy,
-                4 => PlayerStatus::InGame {
+                0 => Some(PlayerStatus::Online),
+                1 => Some(PlayerStatus::Offline),
+                2 => Some(PlayerStatus::Away),
+                3 => Some(PlayerStatus::Busy),
+                4 => Some(PlayerStatus::InGame {
                     game: GameKind::Normal,
                     time: 0,
-                },
-                5 => PlayerStatus::InGame {
+                }),
+                5 => Some(PlayerStatus::InGame {
                     game: GameKind::Ranked,
                     time: 0,
-                },
-                _ => panic!("Invalid status"),
+                }),
+                _ => None,
+            }
+        }
+
+        pub fn from_uint(value: u64) -> Self {
+            match Self::try_from_uint(value) {
+                Some(status) => status,
+                None => panic!("Invalid status"),
             }
         }
 
@@ -781,31 +1306,216 @@ pub mod connection_protocol {
         Ranked,
     }
 
-    pub struct FriendSummary {
-        pub username: String,
-        pub quote: String,
-        pub status: PlayerStatus,
-    }
-
-    #[derive(Debug, PartialEq)]
+    #[derive(Debug, PartialEq, Clone)]
     pub enum Message {
+        /// First message of every connection, the server answers with its own `Hello`
+        /// containing only the capabilities both sides support
+        Hello {
+            protocol_version: u64,
+            client_name: String,
+            capabilities: Vec<String>,
+        },
         Login { username: String, password: Vec<u8> },
         Register { username: String, password: Vec<u8> },
         ClientData(ClientData),
+        /// Ask for the `ClientData` of the logged in user
+        GetClientData,
+        /// End the session and revoke all session tokens of the user,
+        /// the connection stays open for a new login
+        Logout,
+        /// Log in with the token from the `Ok` of an earlier `Login`
+        Resume { token: Vec<u8> },
+        /// Revokes all session tokens of the user
+        ChangePassword {
+            password: Vec<u8>,
+            new_password: Vec<u8>,
+        },
+        /// Pushed by the server when a friend logs in, disconnects or changes their status
+        FriendStatusChanged {
+            username: String,
+            status: PlayerStatus,
+        },
+        /// Ask `username` to be friends, accepts if they already asked
+        ///
+        /// Pushed by the server to the user that was asked, after login for requests that came
+        /// in while they were offline
+        FriendRequest { username: String },
+        /// Accept the request of `username`, pushed to them by the server
+        FriendAccept { username: String },
+        /// Decline the request of `username`, pushed to them by the server
+        FriendDecline { username: String },
+        /// End the friendship with `username` on both sides, pushed to them by the server
+        FriendRemove { username: String },
+        /// Replace the quote shown to friends
+        SetQuote { quote: String },
+        /// Choose between `Online`, `Away` and `Busy`, the server sets the others
+        SetStatus { status: PlayerStatus },
+        /// Pushed by the server when a friend changes their quote
+        FriendQuoteChanged { username: String, quote: String },
 
         Ok(u64, Option<Vec<u8>>),
-        Error(u64, Option<Vec<u8>>),
+        /// Error code with an optional human readable detail
+        Error(ErrorCode, Option<String>),
+    }
+
+    /// Why a request failed, sent in the id field of `Message::Error`
+    #[derive(Debug, PartialEq, Clone, Copy)]
+    pub enum ErrorCode {
+        /// Something went wrong on the server
+        Internal,
+        /// The protocol versions are incompatible or the handshake is missing
+        ProtocolMismatch,
+        /// The password does not match the account
+        InvalidCredentials,
+        /// No account with this username exists
+        UnknownUser,
+        /// The username is already taken
+        UserExists,
+        /// The username does not follow the rules
+        InvalidUsername,
+        /// The password does not follow the rules
+        InvalidPassword,
+        /// Too many attempts, try again in `retry_after` seconds (0 if unknown)
+        ///
+        /// note: the seconds are sent in the upper 32 bits of the code
+        RateLimited { retry_after: u32 },
+        /// The message could not be read or is not allowed right now
+        InvalidMessage,
+        /// The request needs a logged in user
+        NotLoggedIn,
+        /// The session token is unknown, expired or revoked
+        InvalidToken,
+        /// The server is going down, the connection is closed after this
+        ShuttingDown,
+        /// The quote does not follow the rules
+        InvalidQuote,
+        /// A code this build does not know yet
+        Unknown(u64),
+    }
+
+    impl ErrorCode {
+        pub fn from_uint(value: u64) -> Self {
+            if value & 0xffff_ffff == 7 {
+                return ErrorCode::RateLimited {
+                    retry_after: (value >> 32) as u32,
+                };
+            }
+            match value {
+                0 => ErrorCode::Internal,
+                1 => ErrorCode::ProtocolMismatch,
+                2 => ErrorCode::InvalidCredentials,
+                3 => ErrorCode::UnknownUser,
+                4 => ErrorCode::UserExists,
+                5 => ErrorCode::InvalidUsername,
+                6 => ErrorCode::InvalidPassword,
+                8 => ErrorCode::InvalidMessage,
+                9 => ErrorCode::NotLoggedIn,
+                10 => ErrorCode::InvalidToken,
+                11 => ErrorCode::ShuttingDown,
+                12 => ErrorCode::InvalidQuote,
+                value => ErrorCode::Unknown(value),
+            }
+        }
+
+        pub fn to_uint(&self) -> u64 {
+            match self {
+                ErrorCode::Internal => 0,
+                ErrorCode::ProtocolMismatch => 1,
+                ErrorCode::InvalidCredentials => 2,
+                ErrorCode::UnknownUser => 3,
+                ErrorCode::UserExists => 4,
+                ErrorCode::InvalidUsername => 5,
+                ErrorCode::InvalidPassword => 6,
+                ErrorCode::RateLimited { retry_after } => 7 | (*retry_after as u64) << 32,
+                ErrorCode::InvalidMessage => 8,
+                ErrorCode::NotLoggedIn => 9,
+                ErrorCode::InvalidToken => 10,
+                ErrorCode::ShuttingDown => 11,
+                ErrorCode::InvalidQuote => 12,
+                ErrorCode::Unknown(value) => *value,
+            }
+        }
+    }
+
+    impl std::fmt::Display for ErrorCode {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                ErrorCode::Internal => write!(f, "Internal server error"),
+                ErrorCode::ProtocolMismatch => write!(f, "Incompatible protocol version"),
+                ErrorCode::InvalidCredentials => write!(f, "Invalid login"),
+                ErrorCode::UnknownUser => write!(f, "User does not exist"),
+                ErrorCode::UserExists => write!(f, "User already exists"),
+                ErrorCode::InvalidUsername => write!(f, "Invalid username"),
+                ErrorCode::InvalidPassword => write!(f, "Invalid password"),
+                ErrorCode::RateLimited { retry_after: 0 } => write!(f, "Too many attempts"),
+                ErrorCode::RateLimited { retry_after } => {
+                    write!(f, "Too many attempts, try again in {retry_after} seconds")
+                }
+                ErrorCode::InvalidMessage => write!(f, "Invalid message"),
+                ErrorCode::NotLoggedIn => write!(f, "Not logged in"),
+                ErrorCode::InvalidToken => write!(f, "Session expired"),
+                ErrorCode::ShuttingDown => write!(f, "Server is shutting down"),
+                ErrorCode::InvalidQuote => write!(f, "Invalid quote"),
+                ErrorCode::Unknown(value) => write!(f, "Unknown error {value}"),
+            }
+        }
+    }
+
+    /// Id of a client request, the server echoes it in the response
+    pub type RequestId = u64;
+
+    /// Request id of messages that don't answer a request, like server pushes
+    pub const PUSH_ID: RequestId = 0;
+
+    /// A message together with the request it belongs to
+    #[derive(Debug, PartialEq)]
+    pub struct Envelope {
+        pub id: RequestId,
+        pub message: Message,
+    }
+
+    impl Envelope {
+        pub fn new(id: RequestId, message: Message) -> Self {
+            Envelope { id, message }
+        }
+
+        /// A message that doesn't belong to any request
+        pub fn push(message: Message) -> Self {
+            Self::new(PUSH_ID, message)
+        }
+
+        pub fn is_push(&self) -> bool {
+            self.id == PUSH_ID
+        }
+
+        pub fn to_bytes(&self) -> Vec<u8> {
+            self.message.to_frame(self.id)
+        }
+
+        pub fn from_bytes(bytes: &[u8]) -> Result<Self, MessageError> {
+            let mut reader = ConnectionReader::new(CONTAINER, bytes);
+            let head = reader.try_read_uint()?;
+            let id = reader.try_read_uint()?;
+            let body = reader.try_read_binary()?;
+            Ok(Envelope::new(id, Message::from_body(head, &body)?))
+        }
     }
 
     impl Message {
+        /// Frame the message as a push, see `Envelope` for requests and responses
         pub fn to_bytes(&self) -> Vec<u8> {
-            fn combine(head: u64, body: Vec<u8>) -> Vec<u8> {
+            self.to_frame(PUSH_ID)
+        }
+
+        fn to_frame(&self, id: RequestId) -> Vec<u8> {
+            let combine = |head: u64, body: Vec<u8>| -> Vec<u8> {
                 let mut writer = ConnectionWriter::new(CONTAINER).unchecked();
                 writer
                     .write_uint(head)
+                    .write_uint(id)
                     .write_binary(&body)
                     .finalize_unchecked()
-            }
+            };
             match self {
                 Message::Login { username, password } => {
                     let body = ConnectionWriter::new(LOGIN)
@@ -822,123 +1532,458 @@ pub mod connection_protocol {
                     combine(1, body)
                 }
                 Message::Ok(id, data) => {
-                    let mut writer = ConnectionWriter::new(STATUS);
-                    writer.write_uint(*id);
-                    match data {
-                        Some(data) => {
-                            writer.write_bool(true);
+                    let body = ConnectionWriter::new(STATUS)
+                        .write_uint(*id)
+                        .write_optional(data.as_ref(), |writer, data| {
                             writer.write_binary(data);
-                        }
-                        None => {
-                            writer.write_bool(false);
-                        }
-                    }
-                    combine(2, writer.finalize_unchecked())
+                        })
+                        .finalize();
+                    combine(2, body)
                 }
-                Message::Error(id, data) => {
-                    let mut writer = ConnectionWriter::new(STATUS);
-                    writer.write_uint(*id);
-                    match data {
-                        Some(data) => {
-                            writer.write_bool(true);
-                            writer.write_binary(data);
-                        }
-                        None => {
-                            writer.write_bool(false);
-                        }
-                    }
-                    combine(3, writer.finalize_unchecked())
+                Message::Error(code, detail) => {
+                    let body = ConnectionWriter::new(STATUS)
+                        .write_uint(code.to_uint())
+                        .write_optional(detail.as_ref(), |writer, detail| {
+                            writer.write_binary(detail.as_bytes());
+                        })
+                        .finalize();
+                    combine(3, body)
+                }
+                Message::ClientData(data) => combine(4, data.to_bytes()),
+                Message::Hello {
+                    protocol_version,
+                    client_name,
+                    capabilities,
+                } => {
+                    let body = ConnectionWriter::new(HELLO)
+                        .write_uint(*protocol_version)
+                        .write_string(client_name)
+                        .write_list(capabilities, |writer, cap| {
+                            writer.write_string(cap);
+                        })
+                        .finalize();
+                    combine(5, body)
+                }
+                Message::GetClientData => combine(6, Vec::new()),
+                Message::Logout => combine(7, Vec::new()),
+                Message::Resume { token } => {
+                    let body = ConnectionWriter::new(RESUME).write_binary(token).finalize();
+                    combine(8, body)
+                }
+                Message::ChangePassword {
+                    password,
+                    new_password,
+                } => {
+                    let body = ConnectionWriter::new(CHANGE_PASSWORD)
+                        .write_binary(password)
+                        .write_binary(new_password)
+                        .finalize();
+                    combine(9, body)
+                }
+                Message::FriendStatusChanged { username, status } => {
+                    let body = ConnectionWriter::new(FRIEND_STATUS)
+                        .write_string(username)
+                        .write_uint(status.to_uint())
+                        .finalize();
+                    combine(10, body)
+                }
+                Message::FriendRequest { username } => {
+                    combine(11, ConnectionWriter::new(FRIEND).write_string(username).finalize())
+                }
+                Message::FriendAccept { username } => {
+                    combine(12, ConnectionWriter::new(FRIEND).write_string(username).finalize())
+                }
+                Message::FriendDecline { username } => {
+                    combine(13, ConnectionWriter::new(FRIEND).write_string(username).finalize())
                 }
-                Message::ClientData(data) => {
-                    println!("good");
-                    let bin = data.to_bytes();
-                    println!("gooder");
-                    combine(4, bin)
+                Message::FriendRemove { username } => {
+                    combine(14, ConnectionWriter::new(FRIEND).write_string(username).finalize())
+                }
+                Message::SetQuote { quote } => {
+                    combine(15, ConnectionWriter::new(QUOTE).write_string(quote).finalize())
+                }
+                Message::SetStatus { status } => {
+                    let body = ConnectionWriter::new(SET_STATUS)
+                        .write_uint(status.to_uint())
+                        .finalize();
+                    combine(16, body)
+                }
+                Message::FriendQuoteChanged { username, quote } => {
+                    let body = ConnectionWriter::new(FRIEND_QUOTE)
+                        .write_string(username)
+                        .write_string(quote)
+                        .finalize();
+                    combine(17, body)
                 }
             }
         }
 
+        /// Parse a whole frame, dropping the request id
         pub fn from_bytes(bytes: &[u8]) -> Result<Self, MessageError> {
-            let mut reader = ConnectionReader::new(CONTAINER, bytes);
-            let head = reader.read_uint();
-            let body = reader.read_binary();
+            Envelope::from_bytes(bytes).map(|envelope| envelope.message)
+        }
+
+        /// Frame the message in the layout of the handshake, see `HANDSHAKE_CONTAINER`
+        pub fn to_handshake_bytes(&self) -> Vec<u8> {
+            let mut frame = self.to_frame(PUSH_ID);
+            // the head stays, the request id after it goes
+            frame.drain(8..16);
+            frame
+        }
+
+        /// Parse a whole frame in the layout of the handshake
+        pub fn from_handshake_bytes(bytes: &[u8]) -> Result<Self, MessageError> {
+            let mut reader = ConnectionReader::new(HANDSHAKE_CONTAINER, bytes);
+            let head = reader.try_read_uint()?;
+            let body = reader.try_read_binary()?;
+            Self::from_body(head, &body)
+        }
+
+        fn from_body(head: u64, body: &[u8]) -> Result<Self, MessageError> {
             match head {
                 0 => {
-                    println!("got login request");
-                    println!("body: {:x?}", body);
-                    let mut reader = ConnectionReader::new(LOGIN, &body);
-                    let username = reader.read_string();
-                    let password = reader.read_binary();
-                    println!("username: {username}");
+                    tracing::trace!("got login request");
+                    let mut reader = ConnectionReader::new(LOGIN, body);
+                    let username = reader.try_read_string()?;
+                    let password = reader.try_read_binary()?;
+                    tracing::trace!("username: {username}");
                     Ok(Message::Login { username, password })
                 }
                 1 => {
-                    println!("got register request");
-                    let mut reader = ConnectionReader::new(LOGIN, &body);
-                    let username = reader.read_string();
-                    let password = reader.read_binary();
+                    tracing::trace!("got register request");
+                    let mut reader = ConnectionReader::new(LOGIN, body);
+                    let username = reader.try_read_string()?;
+                    let password = reader.try_read_binary()?;
                     Ok(Message::Register { username, password })
                 }
                 2 => {
-                    println!("got ok response");
-                    let mut reader = ConnectionReader::new(STATUS, &body);
-                    let id = reader.read_uint();
-                    let has_data = reader.read_bool();
-                    let data = if has_data {
-                        Some(reader.read_binary())
-                    } else {
-                        None
-                    };
+                    tracing::trace!("got ok response");
+                    let mut reader = ConnectionReader::new(STATUS, body);
+                    let id = reader.try_read_uint()?;
+                    let data = reader.try_read_optional(|reader| reader.try_read_binary())?;
                     Ok(Message::Ok(id, data))
                 }
                 3 => {
-                    println!("got error response");
-                    let mut reader = ConnectionReader::new(STATUS, &body);
-                    let id = reader.read_uint();
-                    let has_data = reader.read_bool();
-                    let data = if has_data {
-                        Some(reader.read_binary())
-                    } else {
-                        None
-                    };
-                    Ok(Message::Error(id, data))
+                    tracing::trace!("got error response");
+                    let mut reader = ConnectionReader::new(STATUS, body);
+                    let code = ErrorCode::from_uint(reader.try_read_uint()?);
+                    let detail = reader.try_read_optional(|reader| reader.try_read_binary())?;
+                    let detail = detail.map(|detail| String::from_utf8_lossy(&detail).into_owned());
+                    Ok(Message::Error(code, detail))
                 }
                 4 => {
-                    println!("got client data");
-                    match ClientData::from_bytes(&body) {
-                        Ok(data) => Ok(Message::ClientData(data)),
-                        Err(e) => Err(e),
-                    }
+                    tracing::trace!("got client data");
+                    Ok(Message::ClientData(ClientData::from_bytes(body)?))
+                }
+                5 => {
+                    let mut reader = ConnectionReader::new(HELLO, body);
+                    let protocol_version = reader.try_read_uint()?;
+                    let client_name = reader.try_read_string()?;
+                    let capabilities = reader.try_read_list(|reader| reader.try_read_string())?;
+                    Ok(Message::Hello {
+                        protocol_version,
+                        client_name,
+                        capabilities,
+                    })
+                }
+                6 => Ok(Message::GetClientData),
+                7 => Ok(Message::Logout),
+                8 => {
+                    let mut reader = ConnectionReader::new(RESUME, body);
+                    let token = reader.try_read_binary()?;
+                    Ok(Message::Resume { token })
+                }
+                9 => {
+                    let mut reader = ConnectionReader::new(CHANGE_PASSWORD, body);
+                    let password = reader.try_read_binary()?;
+                    let new_password = reader.try_read_binary()?;
+                    Ok(Message::ChangePassword {
+                        password,
+                        new_password,
+                    })
+                }
+                10 => {
+                    let mut reader = ConnectionReader::new(FRIEND_STATUS, body);
+                    let username = reader.try_read_string()?;
+                    let status = PlayerStatus::try_from_uint(reader.try_read_uint()?)
+                        .ok_or(MessageError::InvalidMessageBody)?;
+                    Ok(Message::FriendStatusChanged { username, status })
+                }
+                11..=14 => {
+                    let username = ConnectionReader::new(FRIEND, body).try_read_string()?;
+                    Ok(match head {
+                        11 => Message::FriendRequest { username },
+                        12 => Message::FriendAccept { username },
+                        13 => Message::FriendDecline { username },
+                        _ => Message::FriendRemove { username },
+                    })
+                }
+                15 => {
+                    let quote = ConnectionReader::new(QUOTE, body).try_read_string()?;
+                    Ok(Message::SetQuote { quote })
+                }
+                16 => {
+                    let mut reader = ConnectionReader::new(SET_STATUS, body);
+                    let status = PlayerStatus::try_from_uint(reader.try_read_uint()?)
+                        .ok_or(MessageError::InvalidMessageBody)?;
+                    Ok(Message::SetStatus { status })
+                }
+                17 => {
+                    let mut reader = ConnectionReader::new(FRIEND_QUOTE, body);
+                    let username = reader.try_read_string()?;
+                    let quote = reader.try_read_string()?;
+                    Ok(Message::FriendQuoteChanged { username, quote })
                 }
                 _ => Err(MessageError::InvalidMessage),
             }
         }
 
-        pub async fn read_stream(stream: &mut TcpStream) -> Result<Self, MessageError> {
-            let mut data = Vec::new();
+        /// Read the next message from the stream
+        ///
+        /// Bytes that belong to the following messages are kept in `frames`,
+        /// so the same reader has to be passed to every call on one connection
+        pub async fn read_stream<R: AsyncRead + Unpin>(
+            stream: &mut R,
+            frames: &mut FrameReader,
+        ) -> Result<Self, MessageError> {
+            let frame = frames.read_frame(stream).await?;
+            match Message::from_bytes(&frame) {
+                Ok(message) => Ok(message),
+                Err(e) => {
+                    tracing::debug!("Failed to parse response: {:?}", e);
+                    Err(e)
+                }
+            }
+        }
+    }
+
+    /// Size of the `CONTAINER` header (head + request id + body size)
+    pub const FRAME_HEADER_SIZE: usize = 24;
+
+    /// Size of the `HANDSHAKE_CONTAINER` header (head + body size)
+    pub const HANDSHAKE_HEADER_SIZE: usize = 16;
+
+    /// Default limit for the body of a single frame (1 MiB)
+    pub const DEFAULT_MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+    /// Checks if `buf` starts with a complete `CONTAINER` frame
+    ///
+    /// Returns the length of the whole frame (header included) or `None` if more bytes are needed
+    pub fn frame_length(buf: &[u8], max_frame_size: usize) -> Result<Option<usize>, MessageError> {
+        frame_end(buf, FRAME_HEADER_SIZE, max_frame_size)
+    }
+
+    /// `frame_length` for a header of `header_size` bytes that ends with the body size
+    fn frame_end(
+        buf: &[u8],
+        header_size: usize,
+        max_frame_size: usize,
+    ) -> Result<Option<usize>, MessageError> {
+        if buf.len() < header_size {
+            return Ok(None);
+        }
+        let mut size = [0; 8];
+        size.copy_from_slice(&buf[header_size - 8..header_size]);
+        let size = u64::from_be_bytes(size);
+        if size > max_frame_size as u64 {
+            return Err(MessageError::FrameTooLarge {
+                size,
+                max: max_frame_size,
+            });
+        }
+        let total = header_size + size as usize;
+        if buf.len() < total {
+            return Ok(None);
+        }
+        Ok(Some(total))
+    }
+
+    /// Splits a byte stream into `CONTAINER` frames
+    ///
+    /// Anything read past the end of a frame stays buffered for the next one
+    #[derive(Debug)]
+    pub struct FrameReader {
+        buffer: Vec<u8>,
+        pub max_frame_size: usize,
+    }
+
+    impl Default for FrameReader {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl FrameReader {
+        /// Create a new frame reader with the default frame size limit
+        pub fn new() -> FrameReader {
+            Self::with_max_frame_size(DEFAULT_MAX_FRAME_SIZE)
+        }
+
+        /// Create a new frame reader that rejects bodies bigger than `max_frame_size`
+        pub fn with_max_frame_size(max_frame_size: usize) -> FrameReader {
+            FrameReader {
+                buffer: Vec::new(),
+                max_frame_size,
+            }
+        }
+
+        /// Add received bytes to the buffer
+        pub fn extend(&mut self, data: &[u8]) {
+            self.buffer.extend_from_slice(data);
+        }
+
+        /// Number of bytes waiting in the buffer
+        pub fn buffered(&self) -> usize {
+            self.buffer.len()
+        }
+
+        /// Take the next complete frame out of the buffer
+        pub fn next_frame(&mut self) -> Result<Option<Vec<u8>>, MessageError> {
+            match frame_length(&self.buffer, self.max_frame_size)? {
+                Some(len) => Ok(Some(self.buffer.drain(..len).collect())),
+                None => Ok(None),
+            }
+        }
+
+        /// Read from the stream until a complete frame is available
+        pub async fn read_frame<R: AsyncRead + Unpin>(
+            &mut self,
+            stream: &mut R,
+        ) -> Result<Vec<u8>, MessageError> {
             let mut buffer = [0; 1024];
             loop {
+                if let Some(frame) = self.next_frame()? {
+                    return Ok(frame);
+                }
                 match stream.read(&mut buffer).await {
-                    Ok(n) => {
-                        data.extend_from_slice(&buffer[..n]);
-                        if n < buffer.len() {
-                            break;
+                    Ok(0) => {
+                        if self.buffer.is_empty() {
+                            return Err(MessageError::ConnectionClosed);
                         }
+                        return Err(MessageError::Io(std::io::ErrorKind::UnexpectedEof));
                     }
+                    Ok(n) => self.extend(&buffer[..n]),
                     Err(e) => {
-                        eprintln!("Failed to read from stream: {:?}", e);
-                        return Err(MessageError::InvalidMessage);
+                        tracing::debug!("Failed to read from stream: {:?}", e);
+                        return Err(MessageError::Io(e.kind()));
                     }
                 }
             }
-            println!("Data: {:x?}", data);
-            match Message::from_bytes(&data) {
-                Ok(message) => Ok(message),
-                Err(e) => {
-                    eprintln!("Failed to parse response: {:?}", e);
-                    Err(MessageError::InvalidMessage)
-                }
+        }
+    }
+
+    /// A frame with a valid length whose content doesn't parse, the frames after it still can
+    #[derive(Debug, PartialEq)]
+    pub struct InvalidFrame {
+        /// Request the frame belongs to, `PUSH_ID` during the handshake
+        pub id: RequestId,
+        pub error: MessageError,
+    }
+
+    /// Encodes and decodes `Envelope`s as `CONTAINER` frames
+    ///
+    /// Wrap a socket in `Framed` to use it as a `Stream` of envelopes,
+    /// plain `Message`s can be sent as pushes. Only a broken length prefix ends the stream,
+    /// a frame that doesn't parse is an `InvalidFrame`
+    #[derive(Debug, Clone)]
+    pub struct MessageCodec {
+        pub max_frame_size: usize,
+        /// Frames use `HANDSHAKE_CONTAINER` until the `Hello`s are exchanged, the request id of
+        /// decoded envelopes is `PUSH_ID` meanwhile
+        pub handshake: bool,
+    }
+
+    /// A connection that sends and receives whole messages
+    pub type Connection = Framed<tokio::net::TcpStream, MessageCodec>;
+
+    impl Default for MessageCodec {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl MessageCodec {
+        /// Create a new codec with the default frame size limit
+        pub fn new() -> MessageCodec {
+            Self::with_max_frame_size(DEFAULT_MAX_FRAME_SIZE)
+        }
+
+        /// Create a new codec that rejects bodies bigger than `max_frame_size`
+        pub fn with_max_frame_size(max_frame_size: usize) -> MessageCodec {
+            MessageCodec {
+                max_frame_size,
+                handshake: false,
+            }
+        }
+    }
+
+    impl Decoder for MessageCodec {
+        type Item = Result<Envelope, InvalidFrame>;
+        type Error = MessageError;
+
+        fn decode(
+            &mut self,
+            src: &mut BytesMut,
+        ) -> Result<Option<Result<Envelope, InvalidFrame>>, MessageError> {
+            if self.handshake {
+                let Some(len) = frame_end(src, HANDSHAKE_HEADER_SIZE, self.max_frame_size)? else {
+                    return Ok(None);
+                };
+                let envelope = Message::from_handshake_bytes(&src.split_to(len))
+                    .map(Envelope::push)
+                    .map_err(|error| InvalidFrame { id: PUSH_ID, error });
+                return Ok(Some(envelope));
+            }
+            let Some(len) = frame_length(src, self.max_frame_size)? else {
+                return Ok(None);
+            };
+            let frame = src.split_to(len);
+            let envelope = Envelope::from_bytes(&frame).map_err(|error| {
+                // the request id comes right after the head, both are there in a whole frame
+                let id = u64::from_be_bytes(frame[8..16].try_into().unwrap());
+                InvalidFrame { id, error }
+            });
+            Ok(Some(envelope))
+        }
+    }
+
+    impl Encoder<Message> for MessageCodec {
+        type Error = MessageError;
+
+        fn encode(&mut self, item: Message, dst: &mut BytesMut) -> Result<(), MessageError> {
+            self.encode(&item, dst)
+        }
+    }
+
+    impl Encoder<&Message> for MessageCodec {
+        type Error = MessageError;
+
+        fn encode(&mut self, item: &Message, dst: &mut BytesMut) -> Result<(), MessageError> {
+            match self.handshake {
+                true => dst.extend_from_slice(&item.to_handshake_bytes()),
+                false => dst.extend_from_slice(&item.to_bytes()),
             }
+            Ok(())
+        }
+    }
+
+    impl Encoder<Envelope> for MessageCodec {
+        type Error = MessageError;
+
+        fn encode(&mut self, item: Envelope, dst: &mut BytesMut) -> Result<(), MessageError> {
+            self.encode(&item, dst)
+        }
+    }
+
+    impl Encoder<&Envelope> for MessageCodec {
+        type Error = MessageError;
+
+        fn encode(&mut self, item: &Envelope, dst: &mut BytesMut) -> Result<(), MessageError> {
+            match self.handshake {
+                true => dst.extend_from_slice(&item.message.to_handshake_bytes()),
+                false => dst.extend_from_slice(&item.to_bytes()),
+            }
+            Ok(())
         }
     }
 
@@ -946,16 +1991,53 @@ pub mod connection_protocol {
     pub enum MessageError {
         InvalidMessage,
         InvalidMessageBody,
+        /// The frame header announced a body bigger than the allowed maximum
+        FrameTooLarge { size: u64, max: usize },
+        /// The peer closed the connection between two frames
+        ConnectionClosed,
+        Io(std::io::ErrorKind),
+        /// The message does not match its protocol
+        Read(ReadError),
     }
 
-    #[derive(Debug, PartialEq)]
+    impl From<ReadError> for MessageError {
+        fn from(e: ReadError) -> Self {
+            MessageError::Read(e)
+        }
+    }
+
+    impl From<std::io::Error> for MessageError {
+        fn from(e: std::io::Error) -> Self {
+            MessageError::Io(e.kind())
+        }
+    }
+
+    impl std::fmt::Display for MessageError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                MessageError::InvalidMessage => write!(f, "invalid message"),
+                MessageError::InvalidMessageBody => write!(f, "invalid message body"),
+                MessageError::FrameTooLarge { size, max } => {
+                    write!(f, "frame of {size} bytes exceeds the limit of {max} bytes")
+                }
+                MessageError::ConnectionClosed => write!(f, "connection closed"),
+                MessageError::Io(kind) => write!(f, "io error: {kind}"),
+                MessageError::Read(e) => write!(f, "{e}"),
+            }
+        }
+    }
+
+    impl std::error::Error for MessageError {}
+
+    #[derive(Debug, PartialEq, Clone, Chunked)]
     pub struct Friend {
         pub username: String,
-        pub status: PlayerStatus,
         pub quote: String,
+        #[chunk(uint = 1)]
+        pub status: PlayerStatus,
     }
 
-    #[derive(Debug, PartialEq)]
+    #[derive(Debug, PartialEq, Clone, Chunked)]
     pub struct ClientData {
         pub username: String,
         pub friends: Vec<Friend>,
@@ -966,57 +2048,11 @@ pub mod connection_protocol {
 
     impl ClientData {
         pub fn to_bytes(&self) -> Vec<u8> {
-            let mut writer = ConnectionWriter::new(CLIENT_DATA);
-            writer.write_string(&self.username);
-            let mut friends = Vec::new();
-            for friend in &self.friends {
-                let mut writer = ConnectionWriter::new(FRIEND_SUMMARY);
-                let bin = writer
-                    .write_string(&friend.username)
-                    .write_string(&friend.quote)
-                    .write_uint(friend.status.to_uint())
-                    .finalize();
-                friends.extend_from_slice(&bin);
-            }
-            writer.write_binary(&friends);
-            writer.write_uint(self.funds);
-            writer.write_uint(self.status.to_uint());
-            writer.write_string(&self.quote);
-            writer.finalize()
+            self.to_chunks()
         }
 
         pub fn from_bytes(bytes: &[u8]) -> Result<Self, MessageError> {
-            let mut reader = ConnectionReader::new(CLIENT_DATA, bytes);
-            let username = reader.read_string();
-            let friends_bin = reader.read_binary();
-            let mut friends = Vec::new();
-            let mut cur_friends = &friends_bin[..];
-            while cur_friends.len() > 0 {
-                let mut reader = ConnectionReader::new(FRIEND_SUMMARY, cur_friends);
-                let username = reader.read_string();
-                let quote = reader.read_string();
-                let status = reader.read_uint();
-                friends.push(Friend {
-                    username,
-                    status: PlayerStatus::from_uint(status),
-                    quote,
-                });
-                cur_friends = &cur_friends[reader.current_byte..];
-            }
-            let funds = reader.read_uint();
-            println!("good");
-            let status = reader.read_uint();
-            println!("goood");
-            let quote = reader.read_string();
-            println!("gooood");
-            reader.finalize();
-            Ok(ClientData {
-                username,
-                friends,
-                funds,
-                status: PlayerStatus::from_uint(status),
-                quote,
-            })
+            Ok(Self::from_chunks(bytes)?)
         }
     }
 }
@@ -1039,8 +2075,13 @@ mod tests {
             login::validate("a", VALID_PASSWORD),
             login::LoginValidation::UsernameTooShort
         );
+        // the limits are inclusive, the server registers names of exactly `USERNAME_MAX`
         assert_eq!(
             login::validate("a".repeat(USERNAME_MAX).as_str(), VALID_PASSWORD),
+            login::LoginValidation::Valid
+        );
+        assert_eq!(
+            login::validate("a".repeat(USERNAME_MAX + 1).as_str(), VALID_PASSWORD),
             login::LoginValidation::UsernameTooLong
         );
         assert_eq!(
@@ -1052,7 +2093,7 @@ mod tests {
             login::LoginValidation::PasswordTooShort
         );
         assert_eq!(
-            login::validate(VALID_USERNAME, "a".repeat(PASSWORD_MAX).as_str()),
+            login::validate(VALID_USERNAME, "a".repeat(PASSWORD_MAX + 1).as_str()),
             login::LoginValidation::PasswordTooLong
         );
         assert_eq!(
@@ -1060,4 +2101,561 @@ mod tests {
             login::LoginValidation::PasswordContainsWhitespace
         );
     }
+
+    #[test]
+    fn test_frame_reader() {
+        use connection_protocol::*;
+
+        let ok = Message::Ok(0, None).to_bytes();
+        let err = Message::Error(ErrorCode::InvalidCredentials, Some("nope".to_string())).to_bytes();
+
+        // two messages arriving in one segment
+        let mut frames = FrameReader::new();
+        let mut both = ok.clone();
+        both.extend_from_slice(&err);
+        frames.extend(&both);
+        assert_eq!(frames.next_frame().unwrap(), Some(ok.clone()));
+        assert_eq!(frames.next_frame().unwrap(), Some(err.clone()));
+        assert_eq!(frames.next_frame().unwrap(), None);
+
+        // one message split into single byte segments
+        let mut frames = FrameReader::new();
+        for (i, byte) in err.iter().enumerate() {
+            assert_eq!(frames.next_frame().unwrap(), None, "frame done early at {i}");
+            frames.extend(&[*byte]);
+        }
+        assert_eq!(frames.next_frame().unwrap(), Some(err));
+        assert_eq!(frames.buffered(), 0);
+
+        // oversized body is rejected from the header alone
+        let big = Message::Error(ErrorCode::Internal, Some("x".repeat(64))).to_bytes();
+        let mut frames = FrameReader::with_max_frame_size(16);
+        frames.extend(&big[..FRAME_HEADER_SIZE]);
+        assert!(matches!(
+            frames.next_frame(),
+            Err(MessageError::FrameTooLarge { max: 16, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_read_stream_keeps_leftovers() {
+        use connection_protocol::*;
+
+        let mut data = Message::Ok(0, None).to_bytes();
+        data.extend(Message::Error(ErrorCode::Internal, None).to_bytes());
+        let mut stream = &data[..];
+        let mut frames = FrameReader::new();
+        assert_eq!(
+            Message::read_stream(&mut stream, &mut frames).await,
+            Ok(Message::Ok(0, None))
+        );
+        assert_eq!(
+            Message::read_stream(&mut stream, &mut frames).await,
+            Ok(Message::Error(ErrorCode::Internal, None))
+        );
+        assert_eq!(
+            Message::read_stream(&mut stream, &mut frames).await,
+            Err(MessageError::ConnectionClosed)
+        );
+    }
+
+    #[test]
+    fn test_reader_errors() {
+        use connection_protocol::*;
+
+        let login = Message::Login {
+            username: "admin".to_string(),
+            password: vec![1, 2, 3],
+        }
+        .to_bytes();
+
+        // every truncation is an error, never a panic
+        for len in 0..login.len() {
+            assert!(Message::from_bytes(&login[..len]).is_err());
+        }
+
+        let mut reader = ConnectionReader::new(LOGIN, &login[FRAME_HEADER_SIZE..]);
+        let err = reader.try_read_uint().unwrap_err();
+        assert_eq!(err.offset, 0);
+        assert_eq!(err.chunk, 0);
+        assert_eq!(err.expected, Some(Chunks::String));
+        assert_eq!(err.kind, ReadErrorKind::TypeMismatch { requested: "uint" });
+
+        let bad_utf8 = ConnectionWriter::new(LOGIN)
+            .unchecked()
+            .write_binary(&[0xff, 0xfe])
+            .finalize_unchecked();
+        let mut reader = ConnectionReader::new(LOGIN, &bad_utf8);
+        assert_eq!(
+            reader.try_read_string().unwrap_err().kind,
+            ReadErrorKind::InvalidUtf8
+        );
+
+        let mut reader = ConnectionReader::new(LOGIN, &[]);
+        reader.current_chunk = LOGIN.len();
+        assert_eq!(
+            reader.try_read_string().unwrap_err().kind,
+            ReadErrorKind::EndOfProtocol
+        );
+    }
+
+    #[test]
+    fn test_reader_small_ints() {
+        use connection_protocol::*;
+
+        const SMALL: &[Chunks] = &[Chunks::Uint { size: 1 }, Chunks::Int { size: 2 }];
+        let bytes = ConnectionWriter::new(SMALL)
+            .write_uint(200)
+            .write_int(-2)
+            .finalize();
+        assert_eq!(bytes, vec![200, 0xff, 0xfe]);
+        let mut reader = ConnectionReader::new(SMALL, &bytes);
+        assert_eq!(reader.try_read_uint(), Ok(200));
+        assert_eq!(reader.try_read_int(), Ok(-2));
+        assert_eq!(reader.try_finalize(), Ok(()));
+    }
+
+    #[test]
+    fn test_empty_messages() {
+        use connection_protocol::*;
+
+        for message in [Message::GetClientData, Message::Logout] {
+            let bytes = Envelope::new(3, message).to_bytes();
+            assert_eq!(bytes.len(), FRAME_HEADER_SIZE);
+            assert_eq!(Envelope::from_bytes(&bytes).unwrap().id, 3);
+        }
+        assert_eq!(
+            Message::from_bytes(&Message::Logout.to_bytes()),
+            Ok(Message::Logout)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_message_codec() {
+        use connection_protocol::*;
+        use futures::{SinkExt, StreamExt};
+        use tokio_util::codec::Framed;
+
+        let (client, server) = tokio::io::duplex(8);
+        let mut client = Framed::new(client, MessageCodec::new());
+        let mut server = Framed::new(server, MessageCodec::new());
+
+        let login = Message::Login {
+            username: "user".to_string(),
+            password: vec![7; 32],
+        };
+        let sent = tokio::spawn(async move {
+            client.send(Envelope::new(7, login)).await.unwrap();
+            client.send(Message::Ok(0, None)).await.unwrap();
+        });
+        assert_eq!(
+            server.next().await,
+            Some(Ok(Ok(Envelope::new(
+                7,
+                Message::Login {
+                    username: "user".to_string(),
+                    password: vec![7; 32],
+                }
+            ))))
+        );
+        // plain messages are sent as pushes
+        let push = server.next().await.unwrap().unwrap().unwrap();
+        assert!(push.is_push());
+        assert_eq!(push.message, Message::Ok(0, None));
+        sent.await.unwrap();
+        assert_eq!(server.next().await, None);
+    }
+
+    #[test]
+    fn test_codec_skips_invalid_frames() {
+        use bytes::BytesMut;
+        use connection_protocol::*;
+        use tokio_util::codec::Decoder;
+
+        let mut codec = MessageCodec::new();
+        // a head nobody knows, then a frame that is fine
+        let unknown = ConnectionWriter::new(CONTAINER)
+            .write_uint(999)
+            .write_uint(4)
+            .write_binary(&[1, 2, 3])
+            .finalize();
+        let mut buffer = BytesMut::from(&unknown[..]);
+        buffer.extend_from_slice(&Envelope::new(5, Message::Logout).to_bytes());
+
+        assert_eq!(
+            codec.decode(&mut buffer),
+            Ok(Some(Err(InvalidFrame {
+                id: 4,
+                error: MessageError::InvalidMessage
+            })))
+        );
+        assert_eq!(
+            codec.decode(&mut buffer),
+            Ok(Some(Ok(Envelope::new(5, Message::Logout))))
+        );
+        assert!(buffer.is_empty());
+
+        // without a usable length the next frame can't be found
+        let mut huge = Envelope::new(6, Message::Logout).to_bytes();
+        huge[16..24].copy_from_slice(&u64::MAX.to_be_bytes());
+        assert!(matches!(
+            codec.decode(&mut BytesMut::from(&huge[..])),
+            Err(MessageError::FrameTooLarge { .. })
+        ));
+    }
+
+    #[test]
+    fn test_hello() {
+        use connection_protocol::*;
+
+        let hello = Message::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            client_name: "test".to_string(),
+            capabilities: vec!["login".to_string(), "from-the-future".to_string()],
+        };
+        let decoded = Message::from_bytes(&hello.to_bytes()).unwrap();
+        assert_eq!(decoded, hello);
+
+        if let Message::Hello { capabilities, .. } = decoded {
+            assert_eq!(common_capabilities(&capabilities), vec!["login".to_string()]);
+        }
+        assert!(is_compatible(PROTOCOL_VERSION));
+        assert!(!is_compatible(PROTOCOL_VERSION + 1));
+        assert!(!is_compatible(MIN_PROTOCOL_VERSION - 1));
+    }
+
+    #[test]
+    fn test_handshake_layout() {
+        use connection_protocol::*;
+
+        // the Hello of a version 1 client, from before the request id
+        let body = ConnectionWriter::new(HELLO)
+            .write_uint(1)
+            .write_string("old")
+            .write_list(&["login"], |writer, cap| {
+                writer.write_string(cap);
+            })
+            .finalize();
+        let old_hello = ConnectionWriter::new(HANDSHAKE_CONTAINER)
+            .write_uint(5)
+            .write_binary(&body)
+            .finalize();
+        assert_eq!(
+            Message::from_handshake_bytes(&old_hello),
+            Ok(Message::Hello {
+                protocol_version: 1,
+                client_name: "old".to_string(),
+                capabilities: vec!["login".to_string()],
+            })
+        );
+
+        let refused = Message::Error(ErrorCode::ProtocolMismatch, None).to_handshake_bytes();
+        let mut reader = ConnectionReader::new(HANDSHAKE_CONTAINER, &refused);
+        assert_eq!(reader.try_read_uint(), Ok(3));
+        let body = reader.try_read_binary().unwrap();
+        assert_eq!(refused.len(), HANDSHAKE_HEADER_SIZE + body.len());
+        let mut reader = ConnectionReader::new(STATUS, &body);
+        assert_eq!(
+            ErrorCode::from_uint(reader.try_read_uint().unwrap()),
+            ErrorCode::ProtocolMismatch
+        );
+    }
+
+    #[test]
+    fn test_error_codes() {
+        use connection_protocol::*;
+
+        for value in 0..=9 {
+            assert_eq!(ErrorCode::from_uint(value).to_uint(), value);
+            assert_ne!(ErrorCode::from_uint(value), ErrorCode::Unknown(value));
+        }
+        // codes from a newer server survive the round trip
+        assert_eq!(ErrorCode::from_uint(1000), ErrorCode::Unknown(1000));
+
+        let error = Message::Error(ErrorCode::UnknownUser, None);
+        assert_eq!(Message::from_bytes(&error.to_bytes()).unwrap(), error);
+        let error = Message::Error(ErrorCode::UserExists, Some("taken".to_string()));
+        assert_eq!(Message::from_bytes(&error.to_bytes()).unwrap(), error);
+    }
+
+    #[test]
+    fn test_token_messages() {
+        use connection_protocol::*;
+
+        let resume = Message::Resume { token: vec![1; 32] };
+        assert_eq!(Message::from_bytes(&resume.to_bytes()).unwrap(), resume);
+        let change = Message::ChangePassword {
+            password: vec![1; 32],
+            new_password: vec![2; 32],
+        };
+        assert_eq!(Message::from_bytes(&change.to_bytes()).unwrap(), change);
+        assert_eq!(ErrorCode::InvalidToken.to_uint(), 10);
+        assert_eq!(ErrorCode::from_uint(10), ErrorCode::InvalidToken);
+    }
+
+    #[test]
+    fn test_rate_limited_code() {
+        use connection_protocol::*;
+
+        let limited = ErrorCode::RateLimited { retry_after: 90 };
+        assert_eq!(ErrorCode::from_uint(limited.to_uint()), limited);
+        assert_eq!(limited.to_uint() & 0xffff_ffff, 7);
+        let error = Message::Error(limited, None);
+        assert_eq!(Message::from_bytes(&error.to_bytes()).unwrap(), error);
+    }
+
+    #[test]
+    fn test_shutting_down_code() {
+        use connection_protocol::*;
+
+        assert_eq!(ErrorCode::ShuttingDown.to_uint(), 11);
+        let error = Message::Error(ErrorCode::ShuttingDown, None);
+        assert_eq!(Message::from_bytes(&error.to_bytes()).unwrap(), error);
+    }
+
+    #[test]
+    fn test_friend_status_messages() {
+        use connection_protocol::*;
+
+        let status = Message::FriendStatusChanged {
+            username: "friend".to_string(),
+            status: PlayerStatus::Away,
+        };
+        assert_eq!(Message::from_bytes(&status.to_bytes()).unwrap(), status);
+    }
+
+    #[test]
+    fn test_friend_request_messages() {
+        use connection_protocol::*;
+
+        let username = "friend".to_string();
+        for friend in [
+            Message::FriendRequest { username: username.clone() },
+            Message::FriendAccept { username: username.clone() },
+            Message::FriendDecline { username: username.clone() },
+            Message::FriendRemove { username },
+        ] {
+            assert_eq!(Message::from_bytes(&friend.to_bytes()).unwrap(), friend);
+        }
+    }
+
+    #[test]
+    fn test_profile_messages() {
+        use connection_protocol::*;
+
+        for profile in [
+            Message::SetQuote {
+                quote: "gg".to_string(),
+            },
+            Message::SetStatus {
+                status: PlayerStatus::Busy,
+            },
+            Message::FriendQuoteChanged {
+                username: "friend".to_string(),
+                quote: "gg".to_string(),
+            },
+        ] {
+            assert_eq!(Message::from_bytes(&profile.to_bytes()).unwrap(), profile);
+        }
+        assert_eq!(ErrorCode::InvalidQuote.to_uint(), 12);
+        let error = Message::Error(ErrorCode::InvalidQuote, Some("too long".to_string()));
+        assert_eq!(Message::from_bytes(&error.to_bytes()).unwrap(), error);
+    }
+
+    #[test]
+    fn test_composite_chunks() {
+        use connection_protocol::*;
+
+        const POINT: &[Chunks] = &[Chunks::Uint { size: 8 }, Chunks::Uint { size: 1 }];
+        const SHAPE: &[Chunks] = &[
+            Chunks::List(POINT),
+            Chunks::Optional(&Chunks::String),
+            Chunks::Struct(POINT),
+        ];
+
+        let points = vec![(1u64, 2u8), (3, 4)];
+        let bytes = ConnectionWriter::new(SHAPE)
+            .write_list(&points, |writer, (x, y)| {
+                writer.write_uint(*x).write_uint(*y as u64);
+            })
+            .write_optional(Some(&"name"), |writer, name| {
+                writer.write_string(name);
+            })
+            .write_struct(|writer| {
+                writer.write_uint(5).write_uint(6);
+            })
+            .finalize();
+
+        // lists keep the layout of the old hand packed binaries
+        let mut packed = Vec::new();
+        for (x, y) in &points {
+            packed.extend_from_slice(&x.to_be_bytes());
+            packed.push(*y);
+        }
+        let old = ConnectionWriter::new(&[Chunks::Binary])
+            .write_binary(&packed)
+            .finalize();
+        assert_eq!(&bytes[..old.len()], &old[..]);
+
+        let read_point = |reader: &mut ConnectionReader| -> Result<(u64, u8), ReadError> {
+            Ok((reader.try_read_uint()?, reader.try_read_uint()? as u8))
+        };
+        let mut reader = ConnectionReader::new(SHAPE, &bytes);
+        assert_eq!(reader.try_read_list(read_point), Ok(points));
+        assert_eq!(
+            reader.try_read_optional(|reader| reader.try_read_string()),
+            Ok(Some("name".to_string()))
+        );
+        assert_eq!(reader.try_read_struct(read_point), Ok((5, 6)));
+        assert_eq!(reader.try_finalize(), Ok(()));
+
+        let mut reader = ConnectionReader::new(SHAPE, &bytes);
+        while reader.current_chunk < SHAPE.len() {
+            reader.try_skip().unwrap();
+        }
+        assert_eq!(reader.current_byte, bytes.len());
+
+        // an item that does not fill the list is an error, not a panic
+        let mut broken = old.clone();
+        broken.truncate(old.len() - 1);
+        broken[7] -= 1;
+        let mut reader = ConnectionReader::new(SHAPE, &broken);
+        let err = reader.try_read_list(read_point).unwrap_err();
+        assert_eq!(err.expected, Some(Chunks::Uint { size: 1 }));
+        assert_eq!(reader.current_byte, 0);
+    }
+
+    #[test]
+    fn test_derive_chunked() {
+        use connection_protocol::*;
+
+        #[derive(Debug, PartialEq, Chunked)]
+        struct Inner(u16, Option<String>);
+
+        #[derive(Debug, PartialEq, Chunked)]
+        struct Outer {
+            name: String,
+            #[chunk(binary)]
+            blob: Vec<u8>,
+            #[chunk(uint = 1)]
+            small: u64,
+            #[chunk(skip)]
+            local: u32,
+            inner: Vec<Inner>,
+            pair: (i8, bool),
+        }
+
+        assert_eq!(
+            Outer::SCHEMA,
+            &[
+                Chunks::String,
+                Chunks::Binary,
+                Chunks::Uint { size: 1 },
+                Chunks::List(&[Chunks::Struct(&[
+                    Chunks::Uint { size: 2 },
+                    Chunks::Optional(&Chunks::String),
+                ])]),
+                Chunks::Struct(&[Chunks::Int { size: 1 }, Chunks::Bool]),
+            ]
+        );
+
+        let value = Outer {
+            name: "name".to_string(),
+            blob: vec![1, 2, 3],
+            small: 200,
+            local: 5,
+            inner: vec![Inner(1, None), Inner(2, Some("two".to_string()))],
+            pair: (-1, true),
+        };
+        let read = Outer::from_chunks(&value.to_chunks()).unwrap();
+        assert_eq!(read, Outer { local: 0, ..value });
+
+        // the derived client data keeps the hand written layout
+        let data = ClientData {
+            username: "user".to_string(),
+            friends: vec![Friend {
+                username: "friend".to_string(),
+                quote: "quote".to_string(),
+                status: PlayerStatus::Away,
+            }],
+            funds: 10,
+            status: PlayerStatus::Online,
+            quote: String::new(),
+        };
+        let mut expected = ConnectionWriter::new(DYNAMIC).unchecked();
+        let friend = ConnectionWriter::new(DYNAMIC)
+            .unchecked()
+            .write_string("friend")
+            .write_string("quote")
+            .finalize_unchecked();
+        expected
+            .write_string("user")
+            .write_binary(&[friend, vec![2]].concat())
+            .write_uint(10)
+            .write_uint(0)
+            .write_string("");
+        assert_eq!(data.to_bytes(), expected.finalize_unchecked());
+        assert_eq!(ClientData::from_bytes(&data.to_bytes()), Ok(data));
+    }
+
+    #[test]
+    fn test_chunk_serde() {
+        use connection_protocol::*;
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize, Chunked)]
+        struct Record {
+            name: String,
+            friends: Vec<u64>,
+            cards: Vec<(u64, u8)>,
+            nickname: Option<String>,
+            delta: i16,
+            ratio: f64,
+            active: bool,
+        }
+
+        let record = Record {
+            name: "admin".to_string(),
+            friends: vec![2, 3],
+            cards: vec![(1, 4)],
+            nickname: Some("boss".to_string()),
+            delta: -3,
+            ratio: 0.5,
+            active: true,
+        };
+        let bytes = to_chunks_bytes(&record).unwrap();
+        // same layout as the writer
+        assert_eq!(bytes, record.to_chunks());
+        assert_eq!(from_chunks_bytes::<Record>(&bytes), Ok(record));
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        enum Event {
+            Quit,
+            Move { x: u32, y: u32 },
+            Say(String),
+        }
+
+        let events = vec![
+            Event::Quit,
+            Event::Move { x: 1, y: 2 },
+            Event::Say("hi".to_string()),
+        ];
+        let bytes = to_chunks_bytes(&events).unwrap();
+        assert_eq!(from_chunks_bytes::<Vec<Event>>(&bytes), Ok(events));
+
+        // a map is a list of key value pairs
+        let map = std::collections::BTreeMap::from([(1u8, true), (2, false)]);
+        let bytes = to_chunks_bytes(&map).unwrap();
+        assert_eq!(bytes, vec![0, 0, 0, 0, 0, 0, 0, 4, 1, 1, 2, 0]);
+        assert_eq!(from_chunks_bytes(&bytes), Ok(map));
+
+        assert!(matches!(
+            from_chunks_bytes::<Vec<u64>>(&bytes[..10]),
+            Err(ChunkSerdeError::UnexpectedEnd { .. })
+        ));
+        assert!(matches!(
+            from_chunks_bytes::<u8>(&[1, 2]),
+            Err(ChunkSerdeError::TrailingBytes { offset: 1 })
+        ));
+    }
 }
@@ -1 +1 @@
-start cmd.exe /c "cd server && cargo run && pause"
\ No newline at end of file
+start cmd.exe /c "cargo run -p server && pause"
\ No newline at end of file
@@ -0,0 +1,80 @@
+//! Lookups in `db::Users` at different numbers of accounts
+//!
+//! Run with `cargo bench -p server`, lookups should take about as long at every size.
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use server::config::{Config, Storage};
+use server::db::{ServerState, Users};
+use server::store::UserStore;
+
+const SIZES: &[u64] = &[1_000, 100_000];
+
+/// Friends of the player whose client data is built
+const FRIENDS: u64 = 50;
+
+/// `store` with `count` users named `player{id}`
+///
+/// The records are not real password hashes, hashing would take longer than everything else
+fn fill(store: &mut dyn UserStore, count: u64) {
+    for i in 1..=count {
+        store.create(format!("player{i}"), vec![0; 32]);
+    }
+}
+
+fn lookups(c: &mut Criterion) {
+    let mut group = c.benchmark_group("users");
+    for &size in SIZES {
+        let mut users = Users::new();
+        fill(&mut users, size);
+        let last = format!("player{size}");
+        group.bench_with_input(BenchmarkId::new("get_id", size), &last, |b, name| {
+            b.iter(|| users.get_id(black_box(name)))
+        });
+        // what `get_id` did before the indexes
+        group.bench_with_input(BenchmarkId::new("get_id_scan", size), &last, |b, name| {
+            b.iter(|| {
+                let name = black_box(name);
+                users
+                    .logins()
+                    .iter()
+                    .find(|login| login.username == *name)
+                    .map(|login| login.player_id)
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("get_id_missing", size), &size, |b, _| {
+            b.iter(|| users.get_id(black_box("nobody")))
+        });
+        group.bench_with_input(BenchmarkId::new("get", size), &size, |b, &id| {
+            b.iter(|| users.get(black_box(id)))
+        });
+        group.bench_with_input(BenchmarkId::new("create", size), &size, |b, _| {
+            let mut i = 0;
+            b.iter(|| {
+                i += 1;
+                users.create(format!("new{i}"), vec![0; 32])
+            })
+        });
+    }
+    group.finish();
+}
+
+fn client_data(c: &mut Criterion) {
+    let mut group = c.benchmark_group("client_data");
+    for &size in SIZES {
+        let config = Config {
+            storage: Storage::Memory,
+            ..Default::default()
+        };
+        let mut state = ServerState::new(&config);
+        fill(state.users.as_mut(), size);
+        for friend in size - FRIENDS..size {
+            state.users.add_friend(size, friend);
+        }
+        group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, &id| {
+            b.iter(|| state.client_data(black_box(id)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, lookups, client_data);
+criterion_main!(benches);
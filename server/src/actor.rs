@@ -0,0 +1,195 @@
+//! The thread that owns the `ServerState`
+//!
+//! Sessions don't lock the state, they send closures to its owner with a `StateHandle`. The
+//! closures run one after the other, so each of them is atomic, and they run on a thread of
+//! their own, so file and SQLite writes never block the tokio workers. Slow work that needs no
+//! state, like hashing passwords, belongs outside the closures.
+//!
+//! A closure that panics may leave the state half changed, its indexes, journal and sessions
+//! no longer agree. The owner logs the panic and stops instead of serving that state, every
+//! later call fails with `StateError::Stopped` and the server exits, see `StateHandle::stopped`.
+//! What reached the store before the panic is loaded again on the next start.
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::thread::JoinHandle;
+
+use tokio::sync::{mpsc, oneshot};
+
+use crate::db::ServerState;
+
+/// Calls that can wait for the owner before senders have to wait too
+const QUEUE_SIZE: usize = 1024;
+
+type Job = Box<dyn FnOnce(&mut ServerState) + Send>;
+
+/// Why a call did not return
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StateError {
+    /// The owner thread is gone
+    Stopped,
+    /// The closure panicked, or the owner stopped before running it
+    Panicked,
+}
+
+impl std::fmt::Display for StateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StateError::Stopped => write!(f, "the server state is gone"),
+            StateError::Panicked => write!(f, "a change to the server state panicked"),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+/// Access to the `ServerState`, cheap to clone
+#[derive(Clone)]
+pub struct StateHandle {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl StateHandle {
+    /// Moves `state` to a new owner thread
+    ///
+    /// The thread returns the state once every handle is dropped, nothing if a call panicked
+    pub fn spawn(state: ServerState) -> (Self, JoinHandle<Option<ServerState>>) {
+        let (jobs, queue) = mpsc::channel(QUEUE_SIZE);
+        let owner = std::thread::Builder::new()
+            .name("server-state".to_string())
+            .spawn(move || own(state, queue))
+            .expect("Failed to start the server state thread");
+        (Self { jobs }, owner)
+    }
+
+    /// Runs `f` with the state to itself and returns what it returns
+    pub async fn call<R, F>(&self, f: F) -> Result<R, StateError>
+    where
+        F: FnOnce(&mut ServerState) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let (reply, result) = oneshot::channel();
+        let job: Job = Box::new(move |state| {
+            let _ = reply.send(f(state));
+        });
+        self.jobs.send(job).await.map_err(|_| StateError::Stopped)?;
+        // the owner only drops a job without running it when it panics
+        result.await.map_err(|_| StateError::Panicked)
+    }
+
+    /// Resolves when the owner stopped after a panic, calls fail from then on
+    pub async fn stopped(&self) {
+        self.jobs.closed().await
+    }
+}
+
+/// The loop of the owner thread
+fn own(mut state: ServerState, mut queue: mpsc::Receiver<Job>) -> Option<ServerState> {
+    while let Some(job) = queue.blocking_recv() {
+        if let Err(panic) = panic::catch_unwind(AssertUnwindSafe(|| job(&mut state))) {
+            tracing::error!(
+                "A change to the server state panicked, stopping it: {}",
+                panic_message(&*panic)
+            );
+            return None;
+        }
+    }
+    Some(state)
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    match panic.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => panic
+            .downcast_ref::<String>()
+            .map_or("unknown", String::as_str),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    use crate::config::{Config, Storage};
+
+    const TASKS: u64 = 16;
+    const ROUNDS: u64 = 200;
+
+    fn memory_state() -> ServerState {
+        ServerState::new(&Config {
+            storage: Storage::Memory,
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_calls_are_atomic() {
+        let (state, owner) = StateHandle::spawn(memory_state());
+        let id = state
+            .call(|state| state.users.create("bank".to_string(), vec![0; 32]))
+            .await
+            .unwrap()
+            .unwrap();
+
+        let tasks: Vec<_> = (0..TASKS)
+            .map(|task| {
+                let state = state.clone();
+                tokio::spawn(async move {
+                    let name = format!("player{task}");
+                    let created = state
+                        .call(move |state| state.users.create(name, vec![0; 32]))
+                        .await
+                        .unwrap();
+                    assert!(created.is_some());
+                    for _ in 0..ROUNDS {
+                        // a read and a write that would lose updates if they could interleave
+                        let deposited = state.call(move |state| {
+                            let funds = state.users.get(id).unwrap().funds;
+                            state.users.set_funds(id, funds + 1)
+                        });
+                        assert_eq!(deposited.await, Ok(true));
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        let (funds, count) = state
+            .call(move |state| (state.users.get(id).unwrap().funds, state.users.count()))
+            .await
+            .unwrap();
+        assert_eq!(funds, TASKS * ROUNDS);
+        assert_eq!(count, TASKS as usize + 1);
+
+        drop(state);
+        let state = tokio::task::spawn_blocking(move || owner.join().unwrap())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(state.users.count(), TASKS as usize + 1);
+    }
+
+    #[tokio::test]
+    async fn panic_stops_the_state() {
+        let (state, owner) = StateHandle::spawn(memory_state());
+        let panicked = state
+            .call(|state| {
+                state.users.create("half".to_string(), vec![0; 32]);
+                panic!("test")
+            })
+            .await;
+        assert_eq!(panicked, Err::<(), _>(StateError::Panicked));
+
+        tokio::time::timeout(Duration::from_secs(10), state.stopped())
+            .await
+            .unwrap();
+        let after = state.call(|state| state.users.count()).await;
+        assert_eq!(after, Err(StateError::Stopped));
+        let state = tokio::task::spawn_blocking(move || owner.join().unwrap())
+            .await
+            .unwrap();
+        assert!(state.is_none());
+    }
+}
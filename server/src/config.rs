@@ -0,0 +1,292 @@
+//! Server settings
+//!
+//! Settings come from the config file and the defaults below, see `server.example.toml` for
+//! the file format. Only `config`, `bind`, `db` and `log_level` also have a command line flag
+//! and an environment variable, the flag wins over the variable and both win over the file.
+//! Storage, intervals, seed accounts, policies and limits can only be set in the file.
+//!
+//! There are no admin accounts, the server has no admin role to give.
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use clap::{Parser, Subcommand};
+use serde::{Deserialize, Deserializer};
+
+use crate::limiter::LimitPolicy;
+use crate::policy::{QuotePolicy, UsernamePolicy};
+
+/// Read if it exists and `--config` is not given
+const DEFAULT_CONFIG: &str = "server.toml";
+
+#[derive(Parser, Debug, Default)]
+#[command(version, about = "verynoha game server")]
+pub struct Args {
+    /// Config file [default: server.toml if it exists]
+    #[arg(long, env = "VERYNOHA_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Address to listen on
+    #[arg(long, env = "VERYNOHA_BIND")]
+    pub bind: Option<String>,
+    /// Directory of the database files
+    #[arg(long, env = "VERYNOHA_DB")]
+    pub db: Option<PathBuf>,
+    /// Log level or filter, like `info` or `server=debug`
+    #[arg(long, env = "VERYNOHA_LOG")]
+    pub log_level: Option<String>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Runs instead of the server
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Upgrade the database to the current format and exit, stop the server first
+    Migrate {
+        /// Only show the migrations that would run
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bind: String,
+    pub db: PathBuf,
+    pub log_level: String,
+    /// Where the accounts are kept
+    pub storage: Storage,
+    /// Older copies of every database file that are kept
+    pub backups: usize,
+    /// How often changes are written to disk
+    #[serde(deserialize_with = "seconds")]
+    pub save_interval: Duration,
+    /// How long connected clients get to finish when the server is stopped
+    #[serde(deserialize_with = "seconds")]
+    pub shutdown_timeout: Duration,
+    /// Accounts created at startup if they don't exist yet, the only way to get a reserved name
+    ///
+    /// They are ordinary players, the server has no admin rights to give them
+    pub accounts: Vec<SeedAccount>,
+    pub usernames: UsernamePolicy,
+    pub quotes: QuotePolicy,
+    pub limits: Limits,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind: common::DEFAULT_SERVER_IP.to_string(),
+            db: PathBuf::from("db"),
+            log_level: "info".to_string(),
+            storage: Storage::File,
+            backups: 3,
+            save_interval: Duration::from_secs(5),
+            shutdown_timeout: Duration::from_secs(10),
+            accounts: Vec::new(),
+            usernames: UsernamePolicy::default(),
+            quotes: QuotePolicy::default(),
+            limits: Limits::default(),
+        }
+    }
+}
+
+/// Backends of `store::UserStore`
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Storage {
+    /// `users.txt` and its journal, see `db::Users`
+    File,
+    /// `users.sqlite`, see `sqlite::SqliteStore`
+    Sqlite,
+    /// Nothing is saved, for tests
+    Memory,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SeedAccount {
+    pub username: String,
+    pub password: String,
+}
+
+impl std::fmt::Debug for SeedAccount {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SeedAccount")
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Failed login limits, see `limiter`
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    pub ip: LimitPolicy,
+    pub username: LimitPolicy,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            ip: LimitPolicy::ip(),
+            username: LimitPolicy::username(),
+        }
+    }
+}
+
+/// Durations are written as whole seconds
+pub fn seconds<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    u64::deserialize(deserializer).map(Duration::from_secs)
+}
+
+impl Config {
+    /// Combine the flags with the config file
+    pub fn load(args: &Args) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut config = match &args.config {
+            Some(path) => Self::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG).exists() => Self::from_file(DEFAULT_CONFIG)?,
+            None => Self::default(),
+        };
+        if let Some(bind) = &args.bind {
+            config.bind = bind.clone();
+        }
+        if let Some(db) = &args.db {
+            config.db = db.clone();
+        }
+        if let Some(log_level) = &args.log_level {
+            config.log_level = log_level.clone();
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {e}", path.display()))?;
+        toml::from_str(&contents).map_err(|e| format!("Invalid {}: {e}", path.display()).into())
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if let Err(e) = self.bind.parse::<SocketAddr>() {
+            return Err(format!("Invalid bind address {:?}: {e}", self.bind));
+        }
+        for account in &self.accounts {
+            let validation = common::login::validate(&account.username, &account.password);
+            if !validation.is_valid() {
+                return Err(format!("Account {}: {validation}", account.username));
+            }
+            if account.password.to_lowercase().contains(&account.username.to_lowercase()) {
+                return Err(format!(
+                    "Account {}: the password can not contain the username",
+                    account.username
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn users_path(&self) -> PathBuf {
+        self.db.join("users.txt")
+    }
+
+    pub fn journal_path(&self) -> PathBuf {
+        self.db.join("users.journal")
+    }
+
+    pub fn sqlite_path(&self) -> PathBuf {
+        self.db.join("users.sqlite")
+    }
+
+    pub fn tokens_path(&self) -> PathBuf {
+        self.db.join("tokens.txt")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults_are_safe() {
+        let config = Config::default();
+        assert_eq!(config.validate(), Ok(()));
+        assert!(config.accounts.is_empty());
+        assert_eq!(config.bind, common::DEFAULT_SERVER_IP);
+    }
+
+    #[test]
+    fn file_and_flags() {
+        let config: Config = toml::from_str(
+            r#"
+            bind = "0.0.0.0:4000"
+            log_level = "debug"
+            storage = "sqlite"
+            shutdown_timeout = 3
+
+            [[accounts]]
+            username = "admin"
+            password = "correct-horse"
+
+            [usernames]
+            allow_unicode = false
+
+            [quotes]
+            max_length = 40
+
+            [limits.username]
+            window = 60
+            max_failures = 3
+            lockout = 10
+            max_lockout = 600
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.bind, "0.0.0.0:4000");
+        assert_eq!(config.db, PathBuf::from("db"));
+        assert_eq!(config.storage, Storage::Sqlite);
+        assert_eq!(config.shutdown_timeout, Duration::from_secs(3));
+        assert_eq!(config.accounts[0].username, "admin");
+        assert!(!config.usernames.allow_unicode);
+        assert_eq!(config.usernames.reserved, UsernamePolicy::default().reserved);
+        assert_eq!(config.quotes.max_length, 40);
+        assert_eq!(config.limits.username.lockout, Duration::from_secs(10));
+        assert_eq!(config.limits.ip.max_failures, LimitPolicy::ip().max_failures);
+        assert_eq!(config.validate(), Ok(()));
+
+        assert!(toml::from_str::<Config>("port = 3000").is_err());
+
+        // flags win over the file
+        let path = std::env::temp_dir().join(format!("verynoha-config-{}.toml", std::process::id()));
+        std::fs::write(&path, "bind = \"0.0.0.0:4000\"\ndb = \"data\"").unwrap();
+        let args = Args {
+            config: Some(path.clone()),
+            bind: Some("127.0.0.1:5000".to_string()),
+            ..Default::default()
+        };
+        let config = Config::load(&args);
+        std::fs::remove_file(&path).unwrap();
+        let config = config.unwrap();
+        assert_eq!(config.bind, "127.0.0.1:5000");
+        assert_eq!(config.db, PathBuf::from("data"));
+    }
+
+    #[test]
+    fn weak_seed_accounts() {
+        let mut config = Config::default();
+        config.accounts.push(SeedAccount {
+            username: "admin".to_string(),
+            password: "admin".to_string(),
+        });
+        assert!(config.validate().is_err());
+        config.accounts[0].password = "Admin123".to_string();
+        assert!(config.validate().is_err());
+        config.accounts[0].password = "hunter22".to_string();
+        assert_eq!(config.validate(), Ok(()));
+
+        config.bind = "localhost".to_string();
+        assert!(config.validate().is_err());
+    }
+}
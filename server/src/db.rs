@@ -1,33 +1,236 @@
-use std::io::{Read, Write};
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::path::Path;
 
-use common::connection_protocol::PlayerStatus;
+use crate::config::{Config, SeedAccount, Storage};
+use crate::journal::{Change, Journal};
+use crate::limiter::RateLimiter;
+use crate::migrations::{self, DbError, USERS_VERSION};
+use crate::policy::{QuotePolicy, UsernamePolicy};
+use crate::presence::{Pushes, SessionId, Sessions};
+use crate::sqlite::SqliteStore;
+use crate::storage::DbFile;
+use crate::store::UserStore;
+use crate::tokens::{self, Tokens};
+
+use common::connection_protocol::{
+    Chunked, ClientData, ConnectionReader, Friend, Message, PlayerStatus,
+};
 
 pub struct ServerState {
-    pub users: Users,
+    pub users: Box<dyn UserStore>,
+    pub username_policy: UsernamePolicy,
+    pub quote_policy: QuotePolicy,
+    pub tokens: Tokens,
+    pub limiter: RateLimiter,
+    /// Players that are not offline, kept out of the store since it only matters while running
+    statuses: HashMap<u64, PlayerStatus>,
+    /// Connections of logged in players
+    sessions: Sessions,
 }
 
 impl ServerState {
-    pub fn new() -> Self {
+    pub fn new(config: &Config) -> Self {
+        if config.storage != Storage::Memory {
+            if let Err(e) = std::fs::create_dir_all(&config.db) {
+                panic!(
+                    "Failed to create the database directory {}: {e}",
+                    config.db.display()
+                );
+            }
+        }
+        let (users, tokens): (Box<dyn UserStore>, _) = match config.storage {
+            Storage::File => (
+                Box::new(Users::load_db(
+                    DbFile::new(config.users_path(), config.backups),
+                    &config.journal_path(),
+                )),
+                Tokens::load_db(DbFile::new(config.tokens_path(), config.backups)),
+            ),
+            Storage::Sqlite => {
+                let path = config.sqlite_path();
+                let store = SqliteStore::open(&path)
+                    .unwrap_or_else(|e| panic!("Failed to open {}: {e}", path.display()));
+                (
+                    Box::new(store),
+                    Tokens::load_db(DbFile::new(config.tokens_path(), config.backups)),
+                )
+            }
+            Storage::Memory => (Box::new(Users::new()), Tokens::new()),
+        };
         Self {
-            users: Users::load_db(),
+            users,
+            username_policy: config.usernames.clone(),
+            quote_policy: config.quotes.clone(),
+            tokens,
+            limiter: RateLimiter::new(config.limits.ip.clone(), config.limits.username.clone()),
+            statuses: HashMap::new(),
+            sessions: Sessions::new(),
+        }
+    }
+
+    /// Create the configured accounts that don't exist yet
+    pub fn seed_accounts(&mut self, accounts: &[SeedAccount]) {
+        for account in accounts {
+            if self.users.get_id(&account.username).is_some() {
+                continue;
+            }
+            let password = common::login::hash_password(&account.password);
+            if self.users.add_login(account.username.clone(), password) {
+                tracing::info!("Created account {}", account.username);
+            } else {
+                tracing::warn!("Account {} clashes with an existing user", account.username);
+            }
+        }
+    }
+
+    /// A new session token for `player_id`, see `Message::Resume`
+    pub fn issue_token(&mut self, player_id: u64) -> Vec<u8> {
+        let token = self.tokens.issue(player_id, tokens::now());
+        self.tokens.mark_dirty();
+        token
+    }
+
+    pub fn resume(&self, token: &[u8]) -> Option<u64> {
+        self.tokens.resume(token, tokens::now())
+    }
+
+    /// Invalidates every token of `player_id` and saves right away, a revoked token must not
+    /// come back after a crash
+    pub fn revoke_tokens(&mut self, player_id: u64) {
+        self.tokens.revoke_all(player_id);
+        self.tokens.mark_dirty();
+        if let Err(e) = self.tokens.flush() {
+            // still dirty, the next flush tries again
+            tracing::error!("Failed to save the revoked tokens of player {player_id}: {e}");
+        }
+    }
+
+    /// Everything the client of `player_id` needs to know after logging in
+    pub fn client_data(&self, player_id: u64) -> Option<ClientData> {
+        let usr = self.users.get(player_id)?;
+        let friends = usr
+            .friends
+            .iter()
+            .filter_map(|friend_id| self.users.get(*friend_id))
+            .map(|friend| Friend {
+                status: self.status(friend.player_id),
+                username: friend.username.clone(),
+                quote: friend.quote.clone(),
+            })
+            .collect();
+        Some(ClientData {
+            username: usr.username.clone(),
+            funds: usr.funds,
+            quote: usr.quote.clone(),
+            friends,
+            status: self.status(player_id),
+        })
+    }
+
+    pub fn status(&self, player_id: u64) -> PlayerStatus {
+        self.statuses.get(&player_id).cloned().unwrap_or_default()
+    }
+
+    /// Changes the status of `player_id` and tells their connected friends
+    pub fn set_status(&mut self, player_id: u64, status: PlayerStatus) {
+        if self.status(player_id) == status {
+            return;
+        }
+        if status == PlayerStatus::Offline {
+            self.statuses.remove(&player_id);
+        } else {
+            self.statuses.insert(player_id, status.clone());
+        }
+        let Some(user) = self.users.get(player_id) else {
+            return;
+        };
+        let message = Message::FriendStatusChanged {
+            username: user.username.clone(),
+            status,
+        };
+        for &friend_id in &user.friends {
+            self.push(friend_id, &message);
         }
     }
+
+    /// Stores the quote of `player_id` and tells their connected friends, false if the store
+    /// did not take it
+    ///
+    /// The quote has to be checked with `quote_policy` first
+    pub fn set_quote(&mut self, player_id: u64, quote: String) -> bool {
+        let Some(user) = self.users.get(player_id) else {
+            return false;
+        };
+        if user.quote == quote {
+            return true;
+        }
+        let (username, friends) = (user.username.clone(), user.friends.clone());
+        if !self.users.set_quote(player_id, quote.clone()) {
+            return false;
+        }
+        let message = Message::FriendQuoteChanged { username, quote };
+        for friend_id in friends {
+            self.push(friend_id, &message);
+        }
+        true
+    }
+
+    /// Sends `message` to every connection of `player_id`, nothing if they are offline
+    pub fn push(&self, player_id: u64, message: &Message) {
+        self.sessions.push(player_id, message);
+    }
+
+    /// Registers a logged in connection of `player_id`, the first one sets them online
+    pub fn connect(&mut self, player_id: u64, pushes: Pushes) -> SessionId {
+        let session = self.sessions.add(player_id, pushes);
+        if self.status(player_id) == PlayerStatus::Offline {
+            self.set_status(player_id, PlayerStatus::Online);
+        }
+        session
+    }
+
+    /// Ends a connection of `player_id`, they are offline once the last one is gone
+    pub fn disconnect(&mut self, player_id: u64, session: SessionId) {
+        if self.sessions.remove(player_id, session) {
+            self.set_status(player_id, PlayerStatus::Offline);
+        }
+    }
+
+    /// Save the changes since the last flush
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.users.flush()?;
+        self.tokens.flush()
+    }
+
+    /// Sets everyone offline and saves the database, the last thing before the server exits
+    pub fn shutdown(&mut self) -> std::io::Result<()> {
+        self.statuses.clear();
+        self.users.close()?;
+        self.tokens.flush()
+    }
 }
 
-#[derive(Clone, Debug)]
+/// A user as stored in the database, see `DB_USER`
+#[derive(Clone, Debug, PartialEq, Chunked)]
 pub struct UsersInfo {
     pub username: String,
+    #[chunk(binary)]
     pub password: Vec<u8>,
     pub player_id: u64,
     pub friends: Vec<u64>,
     pub quote: String,
     pub funds: u64,
-    pub status: PlayerStatus,
     pub card_collection: Vec<(u64, u8)>,
+    /// Players that asked this one to be friends
+    pub friend_requests: Vec<u64>,
+    /// Players this one asked to be friends
+    pub sent_requests: Vec<u64>,
 }
 
 impl UsersInfo {
-    fn new(username: String, password: Vec<u8>, id: u64) -> Self {
+    pub fn new(username: String, password: Vec<u8>, id: u64) -> Self {
         Self {
             username,
             password,
@@ -35,151 +238,759 @@ impl UsersInfo {
             friends: Vec::new(),
             quote: String::new(),
             funds: 0,
-            status: PlayerStatus::Offline,
             card_collection: Vec::new(),
+            friend_requests: Vec::new(),
+            sent_requests: Vec::new(),
         }
     }
 }
 
+/// The `Storage::File` backend, a snapshot with a journal of the changes since
+///
+/// Without a file it keeps everything in memory, that is `Storage::Memory`
 pub struct Users {
-    pub logins: Vec<UsersInfo>,
+    /// In the order they were added, the order of the snapshot
+    logins: Vec<UsersInfo>,
+    /// Position in `logins` of every player id
+    by_id: HashMap<u64, usize>,
+    /// Player id of every username, see `username_key`
+    by_name: HashMap<String, u64>,
+    /// Id of the next new user
+    next_id: u64,
+    /// The snapshot, `None` keeps everything in memory
+    file: Option<DbFile>,
+    /// Changes since the snapshot was written
+    journal: Option<Journal>,
+    /// A change could not be journaled, the next flush has to write a snapshot
+    dirty: bool,
+    /// Of the snapshot last read or written, see `migrations::Header`
+    generation: u64,
 }
 
-impl Users {
-    pub fn new() -> Self {
-        Self {
-            logins: Vec::new(),
+/// Journal entries that trigger writing a new snapshot
+const COMPACT_AFTER: usize = 1000;
+
+/// Adds `id` to `ids` unless it is there already
+fn add_once(ids: &mut Vec<u64>, id: u64) {
+    if !ids.contains(&id) {
+        ids.push(id);
+    }
+}
+
+/// Usernames are unique in any letter case
+fn username_key(username: &str) -> String {
+    username.to_lowercase()
+}
+
+impl UserStore for Users {
+    fn create(&mut self, username: String, password: Vec<u8>) -> Option<u64> {
+        if self.by_name.contains_key(&username_key(&username)) {
+            return None;
         }
+        let id = self.next_id;
+        self.record(Change::AddUser(UsersInfo::new(username, password, id)));
+        Some(id)
+    }
+
+    fn get(&self, id: u64) -> Option<Cow<'_, UsersInfo>> {
+        Users::get(self, id).map(Cow::Borrowed)
     }
 
-    pub fn add_login(&mut self, name: String, password: Vec<u8>) -> bool {
-        if self.logins.iter().any(|login| login.username == name) {
+    fn get_id(&self, username: &str) -> Option<u64> {
+        let id = *self.by_name.get(&username_key(username))?;
+        self.get(id)
+            .filter(|login| login.username == username)
+            .map(|login| login.player_id)
+    }
+
+    fn count(&self) -> usize {
+        self.logins.len()
+    }
+
+    fn set_password_record(&mut self, id: u64, password: Vec<u8>) -> bool {
+        if self.get(id).is_none() {
             return false;
         }
-        let id = self.logins.iter().map(|login| login.player_id).max().unwrap_or(0) + 1;
-        let login = UsersInfo::new(name, password, id);
-        self.logins.push(login);
-        self.save_db();
+        self.record(Change::SetPassword {
+            player_id: id,
+            password,
+        });
         true
     }
 
-    pub fn validate(&self, username: &str, password: &[u8]) -> Option<u64> {
-        for login in &self.logins {
-            if login.username == username && login.password == password {
-                return Some(login.player_id);
-            }
+    fn set_funds(&mut self, id: u64, funds: u64) -> bool {
+        if self.get(id).is_none() {
+            return false;
         }
-        None
+        self.record(Change::SetFunds {
+            player_id: id,
+            funds,
+        });
+        true
     }
 
-    pub fn get_id(&self, username: &str) -> Option<u64> {
-        for login in &self.logins {
-            if login.username == username {
-                return Some(login.player_id);
-            }
+    fn add_friend(&mut self, id: u64, friend_id: u64) -> bool {
+        if id == friend_id || self.get(id).is_none() || self.get(friend_id).is_none() {
+            return false;
         }
-        None
+        self.record(Change::AddFriend {
+            player_id: id,
+            friend_id,
+        });
+        true
     }
 
-    pub fn get_username(&self, id: u64) -> Option<String> {
-        for login in &self.logins {
-            if login.player_id == id {
-                return Some(login.username.clone());
-            }
+    fn befriend(&mut self, id: u64, friend_id: u64) -> bool {
+        if id == friend_id || self.get(id).is_none() || self.get(friend_id).is_none() {
+            return false;
+        }
+        self.record(Change::Befriend {
+            player_id: id,
+            friend_id,
+        });
+        true
+    }
+
+    fn unfriend(&mut self, id: u64, friend_id: u64) -> bool {
+        if self.get(id).is_none() || self.get(friend_id).is_none() {
+            return false;
+        }
+        self.record(Change::Unfriend {
+            player_id: id,
+            friend_id,
+        });
+        true
+    }
+
+    fn add_friend_request(&mut self, from: u64, to: u64) -> bool {
+        if from == to || self.get(from).is_none() || self.get(to).is_none() {
+            return false;
+        }
+        self.record(Change::AddFriendRequest { from, to });
+        true
+    }
+
+    fn remove_friend_request(&mut self, from: u64, to: u64) -> bool {
+        if self.get(from).is_none() || self.get(to).is_none() {
+            return false;
+        }
+        self.record(Change::RemoveFriendRequest { from, to });
+        true
+    }
+
+    fn set_quote(&mut self, id: u64, quote: String) -> bool {
+        if self.get(id).is_none() {
+            return false;
+        }
+        self.record(Change::SetQuote {
+            player_id: id,
+            quote,
+        });
+        true
+    }
+
+    fn set_card_count(&mut self, id: u64, card_id: u64, count: u8) -> bool {
+        if self.get(id).is_none() {
+            return false;
+        }
+        self.record(Change::SetCardCount {
+            player_id: id,
+            card_id,
+            count,
+        });
+        true
+    }
+
+    /// A long journal is compacted into a new snapshot
+    fn flush(&mut self) -> std::io::Result<()> {
+        match &self.journal {
+            Some(journal) if !self.dirty && journal.len() < COMPACT_AFTER => journal.sync(),
+            _ => self.compact(),
+        }
+    }
+
+    fn close(&mut self) -> std::io::Result<()> {
+        self.compact()
+    }
+}
+
+impl Default for Users {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Users {
+    pub fn new() -> Self {
+        Self {
+            logins: Vec::new(),
+            by_id: HashMap::new(),
+            by_name: HashMap::new(),
+            next_id: 1,
+            file: None,
+            journal: None,
+            dirty: false,
+            generation: 0,
         }
-        None
     }
 
     pub fn get(&self, id: u64) -> Option<&UsersInfo> {
-        self.logins.iter().find(|login| login.player_id == id)
+        self.by_id.get(&id).map(|&i| &self.logins[i])
     }
 
+    /// Changes made here are not journaled, the next flush writes a snapshot with them
+    ///
+    /// The username and the player id are the keys of the indexes, they must stay the same
     pub fn get_mut(&mut self, id: u64) -> Option<&mut UsersInfo> {
-        self.logins.iter_mut().find(|login| login.player_id == id)
-    }
-
-    pub fn from_bytes(bytes: &[u8]) -> Self {
-        let mut logins = Vec::new();
-        let mut bytes = bytes;
-        loop {
-            if bytes.len() == 0 {
-                break;
-            }
-            let mut reader = common::connection_protocol::ConnectionReader::new(common::connection_protocol::DB_USER, &bytes);
-            println!("bytes: {:?}", bytes.len());
-            let username = reader.read_string();
-            let password = reader.read_binary();
-            let id = reader.read_uint();
-            let mut friends = Vec::new();
-            let chunks = reader.read_binary();
-            println!("chunks: {:?}", chunks.len());
-            for chunk in chunks.chunks(8) {
-                friends.push(u64::from_be_bytes(chunk[0..8].try_into().unwrap()));
-            }
-            let quote = reader.read_string();
-            let funds = reader.read_uint();
-            let status = PlayerStatus::Offline;
-            let mut card_collection = Vec::new();
-            for chunk in reader.read_binary().chunks(9) {
-                let mut reader = common::connection_protocol::ConnectionReader::new(common::connection_protocol::DB_OWNED_CARD, chunk);
-                let card_id = reader.read_uint();
-                let amount = reader.read_uint() as u8;
-                card_collection.push((card_id, amount));
-            }
-            logins.push(UsersInfo {
-                username,
+        let &i = self.by_id.get(&id)?;
+        self.dirty = true;
+        Some(&mut self.logins[i])
+    }
+
+    /// Every user, in the order of the snapshot
+    pub fn logins(&self) -> &[UsersInfo] {
+        &self.logins
+    }
+
+    fn find_mut(&mut self, id: u64) -> Option<&mut UsersInfo> {
+        self.by_id.get(&id).map(|&i| &mut self.logins[i])
+    }
+
+    /// Adds `login` to the indexes, a user whose id is taken is ignored
+    fn insert(&mut self, login: UsersInfo) {
+        if self.by_id.contains_key(&login.player_id) {
+            return;
+        }
+        let key = username_key(&login.username);
+        if let Some(other) = self.by_name.get(&key) {
+            // only possible in databases from before names were compared in any case
+            tracing::warn!(
+                "{} and player {other} only differ in case, only the first can log in",
+                login.username
+            );
+        } else {
+            self.by_name.insert(key, login.player_id);
+        }
+        self.next_id = self.next_id.max(login.player_id + 1);
+        self.by_id.insert(login.player_id, self.logins.len());
+        self.logins.push(login);
+    }
+
+    /// Journal `change`, then apply it
+    fn record(&mut self, change: Change) {
+        if let Some(journal) = &mut self.journal {
+            if let Err(e) = journal.append(&change) {
+                tracing::error!("Failed to append to {}: {e}", journal.path().display());
+                self.dirty = true;
+            }
+        }
+        self.apply(change);
+    }
+
+    /// Applying a change twice is the same as applying it once, see `journal`
+    fn apply(&mut self, change: Change) {
+        match change {
+            Change::AddUser(login) => self.insert(login),
+            Change::SetPassword {
+                player_id,
                 password,
-                player_id: id,
-                friends,
-                quote,
-                funds,
-                status,
-                card_collection,
-            });
-            bytes = &bytes[reader.current_byte..];
+            } => {
+                if let Some(login) = self.find_mut(player_id) {
+                    login.password = password;
+                }
+            }
+            Change::SetFunds { player_id, funds } => {
+                if let Some(login) = self.find_mut(player_id) {
+                    login.funds = funds;
+                }
+            }
+            Change::SetQuote { player_id, quote } => {
+                if let Some(login) = self.find_mut(player_id) {
+                    login.quote = quote;
+                }
+            }
+            Change::AddFriend {
+                player_id,
+                friend_id,
+            } => {
+                if let Some(login) = self.find_mut(player_id) {
+                    add_once(&mut login.friends, friend_id);
+                }
+            }
+            Change::SetCardCount {
+                player_id,
+                card_id,
+                count,
+            } => {
+                let Some(login) = self.find_mut(player_id) else {
+                    return;
+                };
+                let cards = &mut login.card_collection;
+                match (cards.iter().position(|(id, _)| *id == card_id), count) {
+                    (Some(i), 0) => {
+                        cards.remove(i);
+                    }
+                    (Some(i), count) => cards[i].1 = count,
+                    (None, 0) => {}
+                    (None, count) => cards.push((card_id, count)),
+                }
+            }
+            Change::Befriend {
+                player_id,
+                friend_id,
+            } => {
+                for (id, other) in [(player_id, friend_id), (friend_id, player_id)] {
+                    if let Some(login) = self.find_mut(id) {
+                        add_once(&mut login.friends, other);
+                        login.friend_requests.retain(|&from| from != other);
+                        login.sent_requests.retain(|&to| to != other);
+                    }
+                }
+            }
+            Change::Unfriend {
+                player_id,
+                friend_id,
+            } => {
+                for (id, other) in [(player_id, friend_id), (friend_id, player_id)] {
+                    if let Some(login) = self.find_mut(id) {
+                        login.friends.retain(|&friend| friend != other);
+                    }
+                }
+            }
+            Change::AddFriendRequest { from, to } => {
+                if let Some(login) = self.find_mut(from) {
+                    add_once(&mut login.sent_requests, to);
+                }
+                if let Some(login) = self.find_mut(to) {
+                    add_once(&mut login.friend_requests, from);
+                }
+            }
+            Change::RemoveFriendRequest { from, to } => {
+                if let Some(login) = self.find_mut(from) {
+                    login.sent_requests.retain(|&id| id != to);
+                }
+                if let Some(login) = self.find_mut(to) {
+                    login.friend_requests.retain(|&id| id != from);
+                }
+            }
         }
-        Self {
-            logins,
+    }
+
+    /// Reads a whole `users.txt`, older layouts are migrated
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DbError> {
+        let (header, records) = migrations::split_header(bytes);
+        let records = migrations::upgrade(header.version, records.to_vec())?;
+        let mut users = Self::new();
+        users.generation = header.generation;
+        let mut reader = ConnectionReader::new(UsersInfo::SCHEMA, &records);
+        while reader.current_byte < records.len() {
+            reader.current_chunk = 0;
+            users.insert(UsersInfo::read_chunks(&mut reader)?);
+            reader.try_finalize()?;
         }
+        Ok(users)
     }
 
-    pub fn load_db() -> Self {
-        let mut file = std::fs::File::open("../db/users.txt").unwrap();
-        let mut contents = Vec::new();
-        file.read_to_end(&mut contents).unwrap();
-        Self::from_bytes(&contents)
+    /// Loads the snapshot in `file` and replays the journal on top of it
+    ///
+    /// Nothing saved yet is an empty database. A corrupt database without a good backup stops
+    /// the server instead of starting empty. A database in an older layout is written back in the
+    /// current one right away, the old file is kept as the first backup.
+    ///
+    /// The journal is only replayed on the snapshot of its generation. Changes for a newer
+    /// snapshot than the loaded backup would miss the users they refer to, so they stop the
+    /// server before anything is written.
+    pub fn load_db(file: DbFile, journal: &Path) -> Self {
+        let loaded = file.read(|bytes| {
+            let version = migrations::split_header(bytes).0.version;
+            Self::from_bytes(bytes).map(|users| (users, version))
+        });
+        let (mut users, version) = match loaded {
+            Ok(Some(loaded)) => loaded,
+            Ok(None) => {
+                tracing::warn!(
+                    "No user database at {}, starting empty",
+                    file.path().display()
+                );
+                (Self::new(), USERS_VERSION)
+            }
+            Err(e) => panic!("Failed to load the user database: {e}"),
+        };
+        let (mut journal, changes) = Journal::open(journal, users.generation)
+            .unwrap_or_else(|e| panic!("Failed to open {}: {e}", journal.display()));
+        match journal.generation().cmp(&users.generation) {
+            Ordering::Equal => {
+                if !changes.is_empty() {
+                    tracing::info!(
+                        "Replaying {} changes from {}",
+                        changes.len(),
+                        journal.path().display()
+                    );
+                }
+                for change in changes {
+                    users.apply(change);
+                }
+            }
+            // a crash after writing the snapshot, before the journal was cleared
+            Ordering::Less => {
+                tracing::info!(
+                    "Dropping {} changes from {}, the snapshot already has them",
+                    changes.len(),
+                    journal.path().display()
+                );
+                if let Err(e) = journal.clear(users.generation) {
+                    panic!("Failed to clear {}: {e}", journal.path().display());
+                }
+            }
+            // the journal goes on top of a newer snapshot than the backup that was loaded
+            Ordering::Greater if !changes.is_empty() => panic!(
+                "{} belongs to snapshot {} of {} but snapshot {} was loaded, \
+                 restore the newer snapshot or move the journal away",
+                journal.path().display(),
+                journal.generation(),
+                file.path().display(),
+                users.generation
+            ),
+            Ordering::Greater => {
+                tracing::warn!(
+                    "Changes since snapshot {} of {} are lost",
+                    users.generation,
+                    file.path().display()
+                );
+                if let Err(e) = journal.clear(users.generation) {
+                    panic!("Failed to clear {}: {e}", journal.path().display());
+                }
+            }
+        }
+        let migrate = version < USERS_VERSION || journal.version() < USERS_VERSION;
+        users.file = Some(file);
+        users.journal = Some(journal);
+        if migrate {
+            tracing::info!("Migrating the user database from version {version} to {USERS_VERSION}");
+            if let Err(e) = users.compact() {
+                panic!("Failed to save the migrated user database: {e}");
+            }
+        }
+        users
     }
 
     pub fn to_bytes(&self) -> Vec<u8> {
-        let mut buffer = Vec::new();
+        let mut buffer = migrations::header(self.generation);
         for login in &self.logins {
-            let mut writer = common::connection_protocol::ConnectionWriter::new(common::connection_protocol::DB_USER);
-            writer.write_string(&login.username);
-            writer.write_binary(&login.password);
-            writer.write_uint(login.player_id);
-            let mut friends = Vec::new();
-            for friend in &login.friends {
-                friends.extend_from_slice(&friend.to_be_bytes());
-            }
-            writer.write_binary(&friends);
-            writer.write_string(&login.quote);
-            writer.write_uint(login.funds);
-            let mut card_collection = Vec::new();
-            for (card_id, amount) in &login.card_collection {
-                let mut writer = common::connection_protocol::ConnectionWriter::new(common::connection_protocol::DB_OWNED_CARD);
-                writer.write_uint(*card_id);
-                writer.write_uint(*amount as u64);
-                card_collection.extend(writer.finalize());
-            }
-            writer.write_binary(&card_collection);
-            buffer.extend(writer.finalize());
+            buffer.extend(login.to_chunks());
         }
         buffer
     }
 
-    pub fn save_db(&self) {
-        let mut file = std::fs::File::create("../db/users.txt").unwrap();
-        file.write_all(&self.to_bytes()).unwrap();
+    pub fn save_db(&self) -> std::io::Result<()> {
+        match &self.file {
+            Some(file) => file.write(&self.to_bytes()),
+            None => Ok(()),
+        }
+    }
+
+    /// Changes the snapshot doesn't have yet
+    pub fn has_unsaved_changes(&self) -> bool {
+        self.dirty
+            || self
+                .journal
+                .as_ref()
+                .is_some_and(|journal| !journal.is_empty())
+    }
+
+    /// Write the snapshot and empty the journal
+    pub fn compact(&mut self) -> std::io::Result<()> {
+        self.generation += 1;
+        self.save_db()?;
+        if let Some(journal) = &mut self.journal {
+            if let Err(e) = journal.clear(self.generation) {
+                // the journal still belongs to the old snapshot, a restart would drop what is
+                // appended to it from now on
+                self.dirty = true;
+                return Err(e);
+            }
+        }
+        self.dirty = false;
+        Ok(())
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn schema_matches_db_user() {
+        assert_eq!(UsersInfo::SCHEMA, common::connection_protocol::DB_USER);
+    }
+
+    #[test]
+    fn users_roundtrip() {
+        let mut user = UsersInfo::new("user".to_string(), vec![1; 32], 7);
+        user.friends = vec![1, 2];
+        user.quote = "hi".to_string();
+        user.funds = 100;
+        user.card_collection = vec![(3, 4), (5, 6)];
+        let mut users = Users::new();
+        users.insert(user.clone());
+        users.insert(UsersInfo::new("test".to_string(), vec![2; 32], 8));
+        let read = Users::from_bytes(&users.to_bytes()).unwrap();
+        assert_eq!(read.logins.len(), 2);
+        assert_eq!(read.logins[0].friends, user.friends);
+        assert_eq!(read.logins[0].card_collection, user.card_collection);
+        assert_eq!(read.logins[1].username, "test");
+        assert_eq!(read.get_id("test"), Some(8));
+        assert_eq!(read.get_id("Test"), None);
+        let mut read = read;
+        // ids continue after the highest one
+        assert_eq!(read.create("TEST".to_string(), vec![3; 32]), None);
+        assert_eq!(read.create("new".to_string(), vec![3; 32]), Some(9));
+        assert_eq!(read.get_username(9).as_deref(), Some("new"));
+    }
+
+    #[test]
+    fn status_changes_reach_connected_friends() {
+        let mut state = ServerState::new(&Config {
+            storage: Storage::Memory,
+            ..Default::default()
+        });
+        let player = state
+            .users
+            .create("player".to_string(), vec![0; 32])
+            .unwrap();
+        let friend = state
+            .users
+            .create("friend".to_string(), vec![0; 32])
+            .unwrap();
+        let stranger = state
+            .users
+            .create("stranger".to_string(), vec![0; 32])
+            .unwrap();
+        state.users.add_friend(player, friend);
+        state.users.add_friend(friend, player);
+
+        let (friend_pushes, mut friend_queue) = tokio::sync::mpsc::unbounded_channel();
+        let (stranger_pushes, mut stranger_queue) = tokio::sync::mpsc::unbounded_channel();
+        let (player_pushes, _player_queue) = tokio::sync::mpsc::unbounded_channel();
+        state.connect(friend, friend_pushes);
+        state.connect(stranger, stranger_pushes);
+        let first = state.connect(player, player_pushes.clone());
+        let second = state.connect(player, player_pushes);
+        state.set_status(player, PlayerStatus::Away);
+        state.set_status(player, PlayerStatus::Away);
+        // still connected on the second session
+        state.disconnect(player, first);
+        let away = state.status(player);
+        state.disconnect(player, second);
+
+        let pushed: Vec<_> = std::iter::from_fn(|| friend_queue.try_recv().ok()).collect();
+        let changed = |status| Message::FriendStatusChanged {
+            username: "player".to_string(),
+            status,
+        };
+        assert_eq!(
+            pushed,
+            [
+                changed(PlayerStatus::Online),
+                changed(PlayerStatus::Away),
+                changed(PlayerStatus::Offline)
+            ]
+        );
+        assert!(stranger_queue.try_recv().is_err());
+        assert_eq!(away, PlayerStatus::Away);
+        assert_eq!(state.status(player), PlayerStatus::Offline);
+    }
+
+    #[test]
+    fn quote_changes_reach_connected_friends() {
+        let mut state = ServerState::new(&Config {
+            storage: Storage::Memory,
+            ..Default::default()
+        });
+        let player = state
+            .users
+            .create("player".to_string(), vec![0; 32])
+            .unwrap();
+        let friend = state
+            .users
+            .create("friend".to_string(), vec![0; 32])
+            .unwrap();
+        state.users.befriend(player, friend);
+        let (friend_pushes, mut friend_queue) = tokio::sync::mpsc::unbounded_channel();
+        state.connect(friend, friend_pushes);
+
+        assert!(state.set_quote(player, "gone fishing".to_string()));
+        assert!(state.set_quote(player, "gone fishing".to_string()));
+        assert!(!state.set_quote(1000, "nobody".to_string()));
+        assert_eq!(state.users.get(player).unwrap().quote, "gone fishing");
+        let pushed: Vec<_> = std::iter::from_fn(|| friend_queue.try_recv().ok()).collect();
+        assert_eq!(
+            pushed,
+            [Message::FriendQuoteChanged {
+                username: "player".to_string(),
+                quote: "gone fishing".to_string()
+            }]
+        );
+    }
+
+    #[test]
+    fn revoked_tokens_stay_revoked_after_a_restart() {
+        let dir = std::env::temp_dir().join(format!("verynoha-revoke-{}", std::process::id()));
+        let config = Config {
+            db: dir.clone(),
+            ..Default::default()
+        };
+        let mut state = ServerState::new(&config);
+        let id = state
+            .users
+            .create("player".to_string(), vec![0; 32])
+            .unwrap();
+        let token = state.issue_token(id);
+        state.flush().unwrap();
+        state.revoke_tokens(id);
+        // no flush, like a crash right after the logout
+        let reloaded = Tokens::load_db(DbFile::new(config.tokens_path(), config.backups));
+        drop(state);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(reloaded.resume(&token, tokens::now()), None);
+    }
+
+    #[test]
+    fn journal_replay_and_compaction() {
+        let dir = std::env::temp_dir().join(format!("verynoha-db-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = || DbFile::new(dir.join("users.txt"), 1);
+        let journal = dir.join("users.journal");
+
+        let mut users = Users::load_db(file(), &journal);
+        crate::store::tests::check_store(&mut users);
+        let id = users.get_id("user").unwrap();
+
+        // no snapshot yet, everything comes from the journal
+        let replayed = Users::load_db(file(), &journal);
+        users.compact().unwrap();
+        let journal_len = std::fs::metadata(&journal).unwrap().len();
+        let compacted = Users::load_db(file(), &journal);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // only the header is left
+        assert_eq!(journal_len, migrations::header(0).len() as u64);
+        for mut read in [replayed, compacted] {
+            assert_eq!(read.logins.len(), 3);
+            assert_eq!(read.get(id).unwrap().funds, 300);
+            let other = read.get_id("other").unwrap();
+            assert_eq!(read.get(other).unwrap(), users.get(other).unwrap());
+            assert_eq!(read.get(id).unwrap().card_collection, vec![(9, 5)]);
+            assert_eq!(read.validate("user", &[4; 32]), Some(id));
+        }
+    }
+
+    #[test]
+    fn journal_only_goes_on_its_own_snapshot() {
+        let dir = std::env::temp_dir().join(format!("verynoha-generation-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = || DbFile::new(dir.join("users.txt"), 2);
+        let journal = dir.join("users.journal");
+
+        let mut users = Users::load_db(file(), &journal);
+        let first = users.create("first".to_string(), vec![1; 32]).unwrap();
+        users.compact().unwrap();
+        users.set_funds(first, 50);
+        // a crash after the snapshot was written, before the journal was cleared
+        let stale = std::fs::read(&journal).unwrap();
+        users.compact().unwrap();
+        drop(users);
+        std::fs::write(&journal, &stale).unwrap();
+        let mut users = Users::load_db(file(), &journal);
+        let dropped = std::fs::metadata(&journal).unwrap().len();
+        let funds = users.get(first).unwrap().funds;
+
+        // a user only the newest snapshot has, and a change about them in the journal
+        let second = users.create("second".to_string(), vec![2; 32]).unwrap();
+        users.compact().unwrap();
+        users.befriend(first, second);
+        drop(users);
+        std::fs::write(dir.join("users.txt"), "broken").unwrap();
+        let before =
+            [file().backup_path(1), journal.clone()].map(|path| std::fs::read(path).unwrap());
+        let loaded = std::panic::catch_unwind(|| Users::load_db(file(), &journal));
+        let after =
+            [file().backup_path(1), journal.clone()].map(|path| std::fs::read(path).unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(dropped, migrations::header(0).len() as u64);
+        assert_eq!(funds, 50);
+        let panic = loaded.err().unwrap();
+        assert!(panic
+            .downcast_ref::<String>()
+            .unwrap()
+            .contains("restore the newer snapshot"));
+        assert_eq!(before, after);
+    }
+
+    #[test]
+    fn changes_through_get_mut_are_saved() {
+        let dir = std::env::temp_dir().join(format!("verynoha-get-mut-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = || DbFile::new(dir.join("users.txt"), 1);
+        let journal = dir.join("users.journal");
+
+        let mut users = Users::load_db(file(), &journal);
+        let id = users.create("user".to_string(), vec![1; 32]).unwrap();
+        users.compact().unwrap();
+        users.get_mut(id).unwrap().funds = 70;
+        assert_eq!(users.get(id).unwrap().funds, 70);
+        users.flush().unwrap();
+        drop(users);
+        let users = Users::load_db(file(), &journal);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(users.get(id).unwrap().funds, 70);
+        assert_eq!(users.get_id("user"), Some(id));
+    }
+
+    #[test]
+    fn failed_journal_clear_compacts_again() {
+        let dir = std::env::temp_dir().join(format!("verynoha-clear-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = || DbFile::new(dir.join("users.txt"), 1);
+        let journal = dir.join("users.journal");
+
+        let mut users = Users::load_db(file(), &journal);
+        let id = users.create("user".to_string(), vec![1; 32]).unwrap();
+        users.compact().unwrap();
+        users.set_funds(id, 50);
+        // the snapshot is written, the journal can't be cleared
+        let read_only = std::fs::File::open(&journal).unwrap();
+        let writable = users.journal.as_mut().unwrap().swap_file(read_only);
+        let compacted = users.compact();
+        users.journal.as_mut().unwrap().swap_file(writable);
+        // appended to the journal of the previous snapshot
+        users.set_funds(id, 60);
+        users.flush().unwrap();
+        drop(users);
+        let users = Users::load_db(file(), &journal);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(compacted.is_err());
+        assert_eq!(users.get(id).unwrap().funds, 60);
+    }
+
+    #[test]
+    fn headerless_database_is_migrated() {
+        let dir = std::env::temp_dir().join(format!("verynoha-migrate-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = DbFile::new(dir.join("users.txt"), 1);
+        let legacy = migrations::tests::v1_user("user", 1);
+        std::fs::write(file.path(), &legacy).unwrap();
+
+        let users = Users::load_db(file.clone(), &dir.join("users.journal"));
+        let saved = std::fs::read(file.path()).unwrap();
+        let backup = std::fs::read(file.backup_path(1)).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(users.logins[0].username, "user");
+        assert_eq!(migrations::split_header(&saved).0.version, USERS_VERSION);
+        assert_eq!(backup, legacy);
+    }
+}
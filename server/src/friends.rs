@@ -0,0 +1,217 @@
+//! Friend requests and the friendships they turn into
+//!
+//! A request is stored with the sender and the receiver until it is answered, a friendship
+//! always goes both ways. The other player hears about every step as a push, requests that
+//! came in while they were offline are pushed after they log in.
+use common::connection_protocol::{ErrorCode, Message};
+
+use crate::db::{ServerState, UsersInfo};
+
+/// Why a friend request or an answer to one was refused
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FriendError {
+    UnknownUser,
+    Yourself,
+    AlreadyFriends,
+    NotFriends,
+    /// There is no request from that player to answer
+    NoRequest,
+    /// The store did not take the change
+    Failed,
+}
+
+impl std::fmt::Display for FriendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FriendError::UnknownUser => write!(f, "No such user"),
+            FriendError::Yourself => write!(f, "That is you"),
+            FriendError::AlreadyFriends => write!(f, "Already friends"),
+            FriendError::NotFriends => write!(f, "Not friends"),
+            FriendError::NoRequest => write!(f, "No friend request from that user"),
+            FriendError::Failed => write!(f, "Failed to save the change"),
+        }
+    }
+}
+
+impl FriendError {
+    /// The answer to the request that failed
+    pub fn to_message(self) -> Message {
+        let code = match self {
+            FriendError::UnknownUser => ErrorCode::UnknownUser,
+            FriendError::Failed => ErrorCode::Internal,
+            _ => ErrorCode::InvalidMessage,
+        };
+        Message::Error(code, Some(self.to_string()))
+    }
+}
+
+impl ServerState {
+    /// `player_id` asks `username` to be friends, if they asked first it is accepted instead
+    ///
+    /// Asking again is not an error, the other player only hears about it once
+    pub fn request_friend(&mut self, player_id: u64, username: &str) -> Result<(), FriendError> {
+        let (player, other) = self.friend_pair(player_id, username)?;
+        if player.friends.contains(&other.player_id) {
+            return Err(FriendError::AlreadyFriends);
+        }
+        if player.friend_requests.contains(&other.player_id) {
+            return self.accept_friend(player_id, username);
+        }
+        if player.sent_requests.contains(&other.player_id) {
+            return Ok(());
+        }
+        if !self.users.add_friend_request(player_id, other.player_id) {
+            return Err(FriendError::Failed);
+        }
+        let request = Message::FriendRequest {
+            username: player.username,
+        };
+        self.push(other.player_id, &request);
+        Ok(())
+    }
+
+    pub fn accept_friend(&mut self, player_id: u64, username: &str) -> Result<(), FriendError> {
+        let (player, other) = self.friend_pair(player_id, username)?;
+        if !player.friend_requests.contains(&other.player_id) {
+            return Err(FriendError::NoRequest);
+        }
+        if !self.users.befriend(player_id, other.player_id) {
+            return Err(FriendError::Failed);
+        }
+        let accepted = Message::FriendAccept {
+            username: player.username,
+        };
+        self.push(other.player_id, &accepted);
+        Ok(())
+    }
+
+    pub fn decline_friend(&mut self, player_id: u64, username: &str) -> Result<(), FriendError> {
+        let (player, other) = self.friend_pair(player_id, username)?;
+        if !player.friend_requests.contains(&other.player_id) {
+            return Err(FriendError::NoRequest);
+        }
+        if !self.users.remove_friend_request(other.player_id, player_id) {
+            return Err(FriendError::Failed);
+        }
+        let declined = Message::FriendDecline {
+            username: player.username,
+        };
+        self.push(other.player_id, &declined);
+        Ok(())
+    }
+
+    pub fn remove_friend(&mut self, player_id: u64, username: &str) -> Result<(), FriendError> {
+        let (player, other) = self.friend_pair(player_id, username)?;
+        if !player.friends.contains(&other.player_id) {
+            return Err(FriendError::NotFriends);
+        }
+        if !self.users.unfriend(player_id, other.player_id) {
+            return Err(FriendError::Failed);
+        }
+        let removed = Message::FriendRemove {
+            username: player.username,
+        };
+        self.push(other.player_id, &removed);
+        Ok(())
+    }
+
+    /// Pushes the requests `player_id` has not answered yet, after they logged in
+    pub fn push_friend_requests(&self, player_id: u64) {
+        let Some(player) = self.users.get(player_id) else {
+            return;
+        };
+        for &from in &player.friend_requests {
+            if let Some(username) = self.users.get_username(from) {
+                self.push(player_id, &Message::FriendRequest { username });
+            }
+        }
+    }
+
+    /// The user `player_id` and the user called `username`
+    fn friend_pair(
+        &self,
+        player_id: u64,
+        username: &str,
+    ) -> Result<(UsersInfo, UsersInfo), FriendError> {
+        let player = self.users.get(player_id).ok_or(FriendError::Failed)?;
+        let other = self
+            .users
+            .get_id(username)
+            .and_then(|id| self.users.get(id))
+            .ok_or(FriendError::UnknownUser)?;
+        if other.player_id == player_id {
+            return Err(FriendError::Yourself);
+        }
+        Ok((player.into_owned(), other.into_owned()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, Storage};
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn requests_become_mutual_friendships() {
+        let mut state = ServerState::new(&Config {
+            storage: Storage::Memory,
+            ..Default::default()
+        });
+        let alice = state.users.create("alice".to_string(), vec![0; 32]).unwrap();
+        let bob = state.users.create("bob".to_string(), vec![0; 32]).unwrap();
+        let carol = state.users.create("carol".to_string(), vec![0; 32]).unwrap();
+        let (pushes, mut alice_queue) = mpsc::unbounded_channel();
+        state.connect(alice, pushes);
+
+        // bob is offline, the request is pushed once they log in
+        assert_eq!(state.request_friend(alice, "bob"), Ok(()));
+        assert_eq!(state.request_friend(alice, "bob"), Ok(()));
+        assert_eq!(state.request_friend(alice, "alice"), Err(FriendError::Yourself));
+        assert_eq!(state.request_friend(alice, "nobody"), Err(FriendError::UnknownUser));
+        assert_eq!(state.accept_friend(alice, "bob"), Err(FriendError::NoRequest));
+        let (pushes, mut bob_queue) = mpsc::unbounded_channel();
+        state.connect(bob, pushes);
+        state.push_friend_requests(bob);
+        assert_eq!(
+            bob_queue.try_recv().ok(),
+            Some(Message::FriendRequest {
+                username: "alice".to_string()
+            })
+        );
+
+        assert_eq!(state.accept_friend(bob, "alice"), Ok(()));
+        assert_eq!(state.request_friend(bob, "alice"), Err(FriendError::AlreadyFriends));
+        let (alice_info, bob_info) = (state.users.get(alice).unwrap(), state.users.get(bob).unwrap());
+        assert_eq!((&alice_info.friends, &bob_info.friends), (&vec![bob], &vec![alice]));
+        assert!(alice_info.sent_requests.is_empty() && bob_info.friend_requests.is_empty());
+
+        // asking someone who already asked is accepting
+        assert_eq!(state.request_friend(carol, "alice"), Ok(()));
+        assert_eq!(state.request_friend(alice, "carol"), Ok(()));
+        assert_eq!(state.users.get(carol).unwrap().friends, vec![alice]);
+
+        assert_eq!(state.remove_friend(bob, "alice"), Ok(()));
+        assert_eq!(state.remove_friend(bob, "alice"), Err(FriendError::NotFriends));
+        assert_eq!(state.users.get(alice).unwrap().friends, vec![carol]);
+        assert_eq!(state.request_friend(bob, "carol"), Ok(()));
+        assert_eq!(state.decline_friend(carol, "bob"), Ok(()));
+        assert!(state.users.get(bob).unwrap().sent_requests.is_empty());
+
+        let pushed: Vec<_> = std::iter::from_fn(|| alice_queue.try_recv().ok()).collect();
+        assert_eq!(
+            pushed,
+            [
+                Message::FriendAccept {
+                    username: "bob".to_string()
+                },
+                Message::FriendRequest {
+                    username: "carol".to_string()
+                },
+                Message::FriendRemove {
+                    username: "bob".to_string()
+                },
+            ]
+        );
+    }
+}
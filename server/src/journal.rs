@@ -0,0 +1,493 @@
+//! Append-only log of the changes to the user database
+//!
+//! Every change is appended when it happens, the database is the last snapshot (`users.txt`)
+//! with the journal replayed on top. `Users::flush` compacts the journal into a new snapshot
+//! once it gets long. Changes are idempotent, a crash between writing the snapshot and clearing
+//! the journal only replays changes the snapshot already has.
+//!
+//! The journal starts with the header of `migrations`, users added in an older layout are
+//! migrated while replaying. The generation in the header is the one of the snapshot the
+//! entries go on top of, see `Users::load_db`.
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::db::UsersInfo;
+use crate::migrations::{self, DbError, Header, USERS_VERSION};
+use common::connection_protocol::{
+    Chunked, Chunks, ConnectionReader, ConnectionWriter, MessageError,
+};
+
+/// Every entry, the length of the body shows if the last entry was cut off by a crash
+const ENTRY: &[Chunks] = &[
+    // kind of change
+    Chunks::Uint { size: 1 },
+    // body
+    Chunks::Binary,
+];
+
+const SET_PASSWORD: &[Chunks] = &[
+    // player id
+    Chunks::Uint { size: 8 },
+    // password record, see `password`
+    Chunks::Binary,
+];
+
+const SET_FUNDS: &[Chunks] = &[
+    // player id
+    Chunks::Uint { size: 8 },
+    // funds
+    Chunks::Uint { size: 8 },
+];
+
+const ADD_FRIEND: &[Chunks] = &[
+    // player id
+    Chunks::Uint { size: 8 },
+    // friend id
+    Chunks::Uint { size: 8 },
+];
+
+const FRIEND_REQUEST: &[Chunks] = &[
+    // player id of the sender
+    Chunks::Uint { size: 8 },
+    // player id of the receiver
+    Chunks::Uint { size: 8 },
+];
+
+const SET_QUOTE: &[Chunks] = &[
+    // player id
+    Chunks::Uint { size: 8 },
+    // quote
+    Chunks::String,
+];
+
+const SET_CARD_COUNT: &[Chunks] = &[
+    // player id
+    Chunks::Uint { size: 8 },
+    // card id
+    Chunks::Uint { size: 8 },
+    // amount
+    Chunks::Uint { size: 1 },
+];
+
+/// A single change to the user database
+#[derive(Debug, Clone)]
+pub enum Change {
+    AddUser(UsersInfo),
+    /// `password` is the stored record, not the hash sent by the client
+    SetPassword { player_id: u64, password: Vec<u8> },
+    SetFunds { player_id: u64, funds: u64 },
+    AddFriend { player_id: u64, friend_id: u64 },
+    /// A count of 0 removes the card from the collection
+    SetCardCount { player_id: u64, card_id: u64, count: u8 },
+    /// Friends in both directions, the requests between them are dropped
+    Befriend { player_id: u64, friend_id: u64 },
+    /// No longer friends in either direction
+    Unfriend { player_id: u64, friend_id: u64 },
+    /// Stored with the sender and the receiver
+    AddFriendRequest { from: u64, to: u64 },
+    RemoveFriendRequest { from: u64, to: u64 },
+    SetQuote { player_id: u64, quote: String },
+}
+
+impl Change {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let (kind, body) = match self {
+            Change::AddUser(user) => (0, user.to_chunks()),
+            Change::SetPassword {
+                player_id,
+                password,
+            } => {
+                let body = ConnectionWriter::new(SET_PASSWORD)
+                    .write_uint(*player_id)
+                    .write_binary(password)
+                    .finalize();
+                (1, body)
+            }
+            Change::SetFunds { player_id, funds } => {
+                let body = ConnectionWriter::new(SET_FUNDS)
+                    .write_uint(*player_id)
+                    .write_uint(*funds)
+                    .finalize();
+                (2, body)
+            }
+            Change::AddFriend {
+                player_id,
+                friend_id,
+            } => {
+                let body = ConnectionWriter::new(ADD_FRIEND)
+                    .write_uint(*player_id)
+                    .write_uint(*friend_id)
+                    .finalize();
+                (3, body)
+            }
+            Change::SetCardCount {
+                player_id,
+                card_id,
+                count,
+            } => {
+                let body = ConnectionWriter::new(SET_CARD_COUNT)
+                    .write_uint(*player_id)
+                    .write_uint(*card_id)
+                    .write_uint(u64::from(*count))
+                    .finalize();
+                (4, body)
+            }
+            Change::Befriend {
+                player_id,
+                friend_id,
+            } => {
+                let body = ConnectionWriter::new(ADD_FRIEND)
+                    .write_uint(*player_id)
+                    .write_uint(*friend_id)
+                    .finalize();
+                (5, body)
+            }
+            Change::Unfriend {
+                player_id,
+                friend_id,
+            } => {
+                let body = ConnectionWriter::new(ADD_FRIEND)
+                    .write_uint(*player_id)
+                    .write_uint(*friend_id)
+                    .finalize();
+                (6, body)
+            }
+            Change::AddFriendRequest { from, to } => {
+                let body = ConnectionWriter::new(FRIEND_REQUEST)
+                    .write_uint(*from)
+                    .write_uint(*to)
+                    .finalize();
+                (7, body)
+            }
+            Change::RemoveFriendRequest { from, to } => {
+                let body = ConnectionWriter::new(FRIEND_REQUEST)
+                    .write_uint(*from)
+                    .write_uint(*to)
+                    .finalize();
+                (8, body)
+            }
+            Change::SetQuote { player_id, quote } => {
+                let body = ConnectionWriter::new(SET_QUOTE)
+                    .write_uint(*player_id)
+                    .write_string(quote)
+                    .finalize();
+                (9, body)
+            }
+        };
+        ConnectionWriter::new(ENTRY)
+            .write_uint(kind)
+            .write_binary(&body)
+            .finalize()
+    }
+
+    /// Read the entry at the position of `reader`, written in layout `version`
+    fn read(reader: &mut ConnectionReader<'_>, version: u32) -> Result<Self, DbError> {
+        reader.current_chunk = 0;
+        let kind = reader.try_read_uint()?;
+        let body = reader.try_read_binary()?;
+        reader.try_finalize()?;
+        match kind {
+            0 => {
+                let body = migrations::upgrade(version, body)?;
+                Ok(Change::AddUser(UsersInfo::from_chunks(&body)?))
+            }
+            1 => {
+                let mut reader = ConnectionReader::new(SET_PASSWORD, &body);
+                let player_id = reader.try_read_uint()?;
+                let password = reader.try_read_binary()?;
+                Ok(Change::SetPassword {
+                    player_id,
+                    password,
+                })
+            }
+            2 => {
+                let mut reader = ConnectionReader::new(SET_FUNDS, &body);
+                let player_id = reader.try_read_uint()?;
+                let funds = reader.try_read_uint()?;
+                Ok(Change::SetFunds { player_id, funds })
+            }
+            3 => {
+                let mut reader = ConnectionReader::new(ADD_FRIEND, &body);
+                let player_id = reader.try_read_uint()?;
+                let friend_id = reader.try_read_uint()?;
+                Ok(Change::AddFriend {
+                    player_id,
+                    friend_id,
+                })
+            }
+            4 => {
+                let mut reader = ConnectionReader::new(SET_CARD_COUNT, &body);
+                let player_id = reader.try_read_uint()?;
+                let card_id = reader.try_read_uint()?;
+                let count = reader.try_read_uint()? as u8;
+                Ok(Change::SetCardCount {
+                    player_id,
+                    card_id,
+                    count,
+                })
+            }
+            5 | 6 => {
+                let mut reader = ConnectionReader::new(ADD_FRIEND, &body);
+                let player_id = reader.try_read_uint()?;
+                let friend_id = reader.try_read_uint()?;
+                Ok(match kind {
+                    5 => Change::Befriend {
+                        player_id,
+                        friend_id,
+                    },
+                    _ => Change::Unfriend {
+                        player_id,
+                        friend_id,
+                    },
+                })
+            }
+            7 | 8 => {
+                let mut reader = ConnectionReader::new(FRIEND_REQUEST, &body);
+                let from = reader.try_read_uint()?;
+                let to = reader.try_read_uint()?;
+                Ok(match kind {
+                    7 => Change::AddFriendRequest { from, to },
+                    _ => Change::RemoveFriendRequest { from, to },
+                })
+            }
+            9 => {
+                let mut reader = ConnectionReader::new(SET_QUOTE, &body);
+                let player_id = reader.try_read_uint()?;
+                let quote = reader.try_read_string()?;
+                Ok(Change::SetQuote { player_id, quote })
+            }
+            _ => Err(MessageError::InvalidMessage.into()),
+        }
+    }
+}
+
+/// The open journal file
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+    /// Entries since the last compaction
+    len: usize,
+    /// Layout of the entries in the file
+    version: u32,
+    /// Generation of the snapshot the entries belong to
+    generation: u64,
+}
+
+impl Journal {
+    /// Opens the journal at `path` and returns the changes in it, a new journal belongs to the
+    /// snapshot of `generation`
+    ///
+    /// An entry cut off by a crash while appending is dropped together with everything after it
+    pub fn open(path: &Path, generation: u64) -> io::Result<(Self, Vec<Change>)> {
+        let contents = match std::fs::read(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        // a crash while the header was written, before any entry
+        let torn = migrations::is_torn_header(&contents);
+        if torn {
+            tracing::warn!("Rewriting the damaged header of {}", path.display());
+        }
+        let fresh = contents.is_empty() || torn;
+        let (header, entries) = match migrations::split_header(&contents) {
+            _ if fresh => {
+                let header = Header {
+                    version: USERS_VERSION,
+                    generation,
+                };
+                (header, &contents[..0])
+            }
+            // journals from before the header have the layout of version 1
+            (Header { version: 0, .. }, entries) => {
+                let header = Header {
+                    version: 1,
+                    generation: 0,
+                };
+                (header, entries)
+            }
+            split => split,
+        };
+        let Header {
+            version,
+            generation,
+        } = header;
+        if version > USERS_VERSION {
+            let e = DbError::UnknownVersion(version);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string()));
+        }
+        let header_len = contents.len() - entries.len();
+
+        let mut changes = Vec::new();
+        let mut reader = ConnectionReader::new(ENTRY, entries);
+        let mut valid = 0;
+        while valid < entries.len() {
+            match Change::read(&mut reader, version) {
+                Ok(change) => {
+                    changes.push(change);
+                    valid = reader.current_byte;
+                }
+                Err(e) => {
+                    tracing::warn!(
+                        "Dropping {} damaged bytes at the end of {}: {e}",
+                        entries.len() - valid,
+                        path.display()
+                    );
+                    break;
+                }
+            }
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        if fresh {
+            file.set_len(0)?;
+            file.write_all(&migrations::header(generation))?;
+        } else if valid < entries.len() {
+            file.set_len((header_len + valid) as u64)?;
+        }
+        let journal = Self {
+            path: path.to_path_buf(),
+            file,
+            len: changes.len(),
+            version,
+            generation,
+        };
+        Ok((journal, changes))
+    }
+
+    pub fn append(&mut self, change: &Change) -> io::Result<()> {
+        self.file.write_all(&change.to_bytes())?;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Make the appended changes survive a crash
+    pub fn sync(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    /// Entries since the last `clear`
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Forget every entry, after they were written to the snapshot of `generation`
+    pub fn clear(&mut self, generation: u64) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.write_all(&migrations::header(generation))?;
+        self.file.sync_all()?;
+        self.len = 0;
+        self.version = USERS_VERSION;
+        self.generation = generation;
+        Ok(())
+    }
+
+    /// Layout of the entries, older than `USERS_VERSION` until the next `clear`
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Generation of the snapshot the entries go on top of
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Writes go to `file` from now on, the old one is returned
+    #[cfg(test)]
+    pub fn swap_file(&mut self, file: File) -> File {
+        std::mem::replace(&mut self.file, file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replay_and_torn_entry() {
+        let path = std::env::temp_dir().join(format!("verynoha-journal-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let (mut journal, changes) = Journal::open(&path, 0).unwrap();
+        assert!(changes.is_empty());
+        journal
+            .append(&Change::SetFunds {
+                player_id: 1,
+                funds: 500,
+            })
+            .unwrap();
+        journal
+            .append(&Change::SetCardCount {
+                player_id: 1,
+                card_id: 7,
+                count: 3,
+            })
+            .unwrap();
+        drop(journal);
+
+        // a crash in the middle of the third entry
+        let torn = Change::AddFriend {
+            player_id: 1,
+            friend_id: 2,
+        }
+        .to_bytes();
+        let mut contents = std::fs::read(&path).unwrap();
+        let len = contents.len();
+        contents.extend(&torn[..torn.len() - 3]);
+        std::fs::write(&path, &contents).unwrap();
+
+        let (journal, changes) = Journal::open(&path, 0).unwrap();
+        let truncated = std::fs::metadata(&path).unwrap().len();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(journal.len(), 2);
+        assert_eq!(truncated, len as u64);
+        assert!(matches!(
+            changes[0],
+            Change::SetFunds {
+                player_id: 1,
+                funds: 500
+            }
+        ));
+        assert!(matches!(
+            changes[1],
+            Change::SetCardCount {
+                player_id: 1,
+                card_id: 7,
+                count: 3
+            }
+        ));
+    }
+
+    #[test]
+    fn torn_header_is_rewritten() {
+        let path = std::env::temp_dir().join(format!("verynoha-torn-{}", std::process::id()));
+        let header = migrations::header(5);
+        for torn in [&header[..2], &header[..header.len() - 3]] {
+            std::fs::write(&path, torn).unwrap();
+            let (mut journal, changes) = Journal::open(&path, 5).unwrap();
+            assert!(changes.is_empty());
+            journal
+                .append(&Change::SetFunds {
+                    player_id: 1,
+                    funds: 500,
+                })
+                .unwrap();
+            drop(journal);
+
+            let (journal, changes) = Journal::open(&path, 5).unwrap();
+            assert_eq!(journal.generation(), 5);
+            assert_eq!(journal.version(), USERS_VERSION);
+            assert_eq!(changes.len(), 1);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,16 @@
+//! The game server, `main.rs` only starts it
+pub mod actor;
+pub mod config;
+pub mod db;
+pub mod friends;
+pub mod journal;
+pub mod limiter;
+pub mod migrations;
+pub mod password;
+pub mod policy;
+pub mod presence;
+pub mod session;
+pub mod sqlite;
+pub mod storage;
+pub mod store;
+pub mod tokens;
@@ -0,0 +1,261 @@
+//! Limits failed logins per IP address and per username
+//!
+//! Failures are counted in a sliding window. Too many failures lock the key out, every lockout
+//! in a row doubles the time until the key is allowed again.
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::config::seconds;
+
+/// Limits for one kind of key
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LimitPolicy {
+    /// How far back failures are counted
+    #[serde(deserialize_with = "seconds")]
+    pub window: Duration,
+    /// Failures inside the window that trigger a lockout
+    pub max_failures: usize,
+    /// Length of the first lockout
+    #[serde(deserialize_with = "seconds")]
+    pub lockout: Duration,
+    /// Upper bound of the doubled lockouts
+    #[serde(deserialize_with = "seconds")]
+    pub max_lockout: Duration,
+}
+
+impl LimitPolicy {
+    /// Limits for a single account
+    pub fn username() -> Self {
+        Self {
+            window: Duration::from_secs(5 * 60),
+            max_failures: 5,
+            lockout: Duration::from_secs(30),
+            max_lockout: Duration::from_secs(60 * 60),
+        }
+    }
+
+    /// Limits for one address, higher since players can share an address
+    pub fn ip() -> Self {
+        Self {
+            window: Duration::from_secs(5 * 60),
+            max_failures: 20,
+            lockout: Duration::from_secs(60),
+            max_lockout: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Entry {
+    failures: VecDeque<Instant>,
+    /// Lockouts in a row, forgotten after `max_lockout` without one
+    lockouts: u32,
+    locked_until: Option<Instant>,
+}
+
+impl Entry {
+    fn retry_after(&self, now: Instant) -> Option<Duration> {
+        self.locked_until
+            .filter(|until| *until > now)
+            .map(|until| until - now)
+    }
+
+    fn fail(&mut self, policy: &LimitPolicy, now: Instant) {
+        if let Some(until) = self.locked_until {
+            if now >= until + policy.max_lockout {
+                self.lockouts = 0;
+                self.locked_until = None;
+            }
+        }
+        while let Some(first) = self.failures.front() {
+            if now.duration_since(*first) < policy.window {
+                break;
+            }
+            self.failures.pop_front();
+        }
+        self.failures.push_back(now);
+        if self.failures.len() >= policy.max_failures {
+            let lockout = policy
+                .lockout
+                .saturating_mul(2u32.saturating_pow(self.lockouts))
+                .min(policy.max_lockout);
+            self.lockouts += 1;
+            self.locked_until = Some(now + lockout);
+            self.failures.clear();
+        }
+    }
+
+    /// Nothing left worth remembering
+    fn is_stale(&self, policy: &LimitPolicy, now: Instant) -> bool {
+        let failures_expired = self
+            .failures
+            .back()
+            .is_none_or(|last| now.duration_since(*last) >= policy.window);
+        let lockout_forgotten = self
+            .locked_until
+            .is_none_or(|until| now >= until + policy.max_lockout);
+        failures_expired && lockout_forgotten
+    }
+}
+
+/// Entries of one kind of key
+#[derive(Debug)]
+struct Limits<K> {
+    policy: LimitPolicy,
+    entries: HashMap<K, Entry>,
+}
+
+/// Entries kept before stale ones are removed
+const PRUNE_AT: usize = 1024;
+
+impl<K: std::hash::Hash + Eq> Limits<K> {
+    fn new(policy: LimitPolicy) -> Self {
+        Self {
+            policy,
+            entries: HashMap::new(),
+        }
+    }
+
+    fn retry_after(&self, key: &K, now: Instant) -> Option<Duration> {
+        self.entries.get(key)?.retry_after(now)
+    }
+
+    fn fail(&mut self, key: K, now: Instant) {
+        if self.entries.len() >= PRUNE_AT {
+            let policy = &self.policy;
+            self.entries.retain(|_, entry| !entry.is_stale(policy, now));
+        }
+        self.entries.entry(key).or_default().fail(&self.policy, now);
+    }
+}
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    ips: Limits<IpAddr>,
+    usernames: Limits<String>,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(LimitPolicy::ip(), LimitPolicy::username())
+    }
+}
+
+impl RateLimiter {
+    pub fn new(ip: LimitPolicy, username: LimitPolicy) -> Self {
+        Self {
+            ips: Limits::new(ip),
+            usernames: Limits::new(username),
+        }
+    }
+
+    /// Time until the next attempt is allowed, `None` if it is allowed now
+    pub fn retry_after(&self, ip: IpAddr, username: &str, now: Instant) -> Option<Duration> {
+        let ip = self.ips.retry_after(&ip, now);
+        let username = self.usernames.retry_after(&username.to_lowercase(), now);
+        ip.max(username)
+    }
+
+    pub fn failed(&mut self, ip: IpAddr, username: &str, now: Instant) {
+        self.ips.fail(ip, now);
+        self.usernames.fail(username.to_lowercase(), now);
+    }
+
+    /// A correct password forgives the earlier failures of the account
+    pub fn succeeded(&mut self, username: &str) {
+        if let Some(entry) = self.usernames.entries.get_mut(&username.to_lowercase()) {
+            entry.failures.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(max_failures: usize) -> LimitPolicy {
+        LimitPolicy {
+            window: Duration::from_secs(60),
+            max_failures,
+            lockout: Duration::from_secs(10),
+            max_lockout: Duration::from_secs(25),
+        }
+    }
+
+    #[test]
+    fn username_lockout_doubles() {
+        let mut limiter = RateLimiter::new(policy(100), policy(3));
+        let ip: IpAddr = [127, 0, 0, 1].into();
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        limiter.failed(ip, "admin", at(0));
+        limiter.failed(ip, "admin", at(1));
+        assert_eq!(limiter.retry_after(ip, "admin", at(1)), None);
+        limiter.failed(ip, "Admin", at(2));
+        assert_eq!(
+            limiter.retry_after(ip, "ADMIN", at(2)),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(limiter.retry_after(ip, "user", at(2)), None);
+        assert_eq!(limiter.retry_after(ip, "admin", at(12)), None);
+
+        for i in 12..15 {
+            limiter.failed(ip, "admin", at(i));
+        }
+        assert_eq!(
+            limiter.retry_after(ip, "admin", at(14)),
+            Some(Duration::from_secs(20))
+        );
+        for i in 34..37 {
+            limiter.failed(ip, "admin", at(i));
+        }
+        // capped at max_lockout
+        assert_eq!(
+            limiter.retry_after(ip, "admin", at(36)),
+            Some(Duration::from_secs(25))
+        );
+
+        // long enough without a lockout starts over
+        for i in 200..203 {
+            limiter.failed(ip, "admin", at(i));
+        }
+        assert_eq!(
+            limiter.retry_after(ip, "admin", at(202)),
+            Some(Duration::from_secs(10))
+        );
+    }
+
+    #[test]
+    fn sliding_window_and_ip() {
+        let mut limiter = RateLimiter::new(policy(3), policy(100));
+        let ip: IpAddr = [10, 0, 0, 1].into();
+        let other: IpAddr = [10, 0, 0, 2].into();
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        limiter.failed(ip, "a", at(0));
+        limiter.failed(ip, "b", at(30));
+        // the first failure left the window
+        limiter.failed(ip, "c", at(61));
+        assert_eq!(limiter.retry_after(ip, "d", at(61)), None);
+        limiter.failed(ip, "d", at(62));
+        assert!(limiter.retry_after(ip, "e", at(62)).is_some());
+        assert_eq!(limiter.retry_after(other, "e", at(62)), None);
+    }
+
+    #[test]
+    fn success_forgives_failures() {
+        let mut limiter = RateLimiter::new(policy(100), policy(2));
+        let ip: IpAddr = [127, 0, 0, 1].into();
+        let now = Instant::now();
+        limiter.failed(ip, "user", now);
+        limiter.succeeded("user");
+        limiter.failed(ip, "user", now);
+        assert_eq!(limiter.retry_after(ip, "user", now), None);
+    }
+}
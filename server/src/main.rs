@@ -1,109 +1,159 @@
-//! rust tcp multi-threaded server
-use std::sync::{Arc, Mutex};
-use tokio::io::AsyncWriteExt;
+//! rust tcp multi-threaded server, see the `server` library for everything but startup
+use clap::Parser;
+use common::connection_protocol::MessageCodec;
+use std::time::Duration;
 use tokio::net::*;
+use tokio::sync::watch;
+use tokio::time::MissedTickBehavior;
+use tokio_util::codec::Framed;
+use tokio_util::task::TaskTracker;
+use tracing_subscriber::EnvFilter;
 
-mod db;
+use server::actor::StateHandle;
+use server::{config, db, migrations, session};
 
 #[tokio::main]
 async fn main() {
-    let mut state = db::ServerState::new();
-    state
-        .users
-        .add_login("admin".to_string(), common::login::hash_password("admin"));
-    state
-        .users
-        .add_login("user".to_string(), common::login::hash_password("user"));
-    state
-        .users
-        .add_login("test".to_string(), common::login::hash_password("test"));
+    let args = config::Args::parse();
+    let config = match config::Config::load(&args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(2);
+        }
+    };
+    let filter = EnvFilter::try_new(&config.log_level).unwrap_or_else(|e| {
+        eprintln!("Invalid log level {:?}: {e}", config.log_level);
+        std::process::exit(2);
+    });
+    tracing_subscriber::fmt().with_env_filter(filter).init();
+
+    if let Some(config::Command::Migrate { dry_run }) = args.command {
+        if let Err(e) = migrations::run(&config, dry_run) {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+        return;
+    }
 
-    println!("users: {:?}", state.users.logins.iter().map(|u|u.username.clone()).collect::<Vec<String>>());
-    let state = Arc::new(Mutex::new(state));
+    let mut state = db::ServerState::new(&config);
+    state.seed_accounts(&config.accounts);
+    tracing::info!("{} users in {}", state.users.count(), config.db.display());
+    let (state, _owner) = StateHandle::spawn(state);
 
-    let listener = TcpListener::bind(common::DEFAULT_SERVER_IP).await.unwrap();
+    let listener = TcpListener::bind(&config.bind).await.unwrap_or_else(|e| {
+        tracing::error!("Failed to listen on {}: {e}", config.bind);
+        std::process::exit(1);
+    });
+    tracing::info!("Listening on {}", config.bind);
 
+    let (stop_sessions, stopping) = watch::channel(false);
+    let sessions = TaskTracker::new();
+    tokio::spawn(flush_periodically(
+        state.clone(),
+        config.save_interval,
+        stopping.clone(),
+    ));
+    let signal = shutdown_signal();
+    tokio::pin!(signal);
     loop {
-        let (socket, addr) = listener.accept().await.unwrap();
-        let state = Arc::clone(&state);
-        tokio::spawn(async move {
-            handle_client(socket, state).await.unwrap();
+        let (socket, addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    tracing::warn!("Failed to accept a connection: {e}");
+                    continue;
+                }
+            },
+            _ = &mut signal => break,
+            // the state may be half changed, a restart loads what was saved before the panic
+            _ = state.stopped() => {
+                tracing::error!("The server state stopped after a panic, exiting without saving");
+                std::process::exit(1);
+            }
+        };
+        let state = state.clone();
+        let stopping = stopping.clone();
+        sessions.spawn(async move {
+            let connection = Framed::new(socket, MessageCodec::new());
+            let session = session::Session::new(connection, state, addr, stopping);
+            if let Err(e) = session.run().await {
+                tracing::warn!("Connection to {addr} failed: {e}");
+            }
         });
     }
+
+    tracing::info!("Shutting down, {} connections open", sessions.len());
+    drop(listener);
+    let _ = stop_sessions.send(true);
+    sessions.close();
+    if tokio::time::timeout(config.shutdown_timeout, sessions.wait())
+        .await
+        .is_err()
+    {
+        tracing::warn!("{} connections did not close in time", sessions.len());
+    }
+
+    match state.call(|state| state.shutdown()).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => {
+            tracing::error!("Failed to save the database: {e}");
+            std::process::exit(1);
+        }
+        Err(e) => {
+            tracing::error!("Failed to save the database: {e}");
+            std::process::exit(1);
+        }
+    }
+    tracing::info!("Database saved");
 }
 
-async fn handle_client(
-    mut socket: TcpStream,
-    state: Arc<Mutex<db::ServerState>>,
-) -> Result<(), Box<dyn std::error::Error>> {
+/// Writes the changes to disk every `interval` until the server stops
+async fn flush_periodically(
+    state: StateHandle,
+    interval: Duration,
+    mut stopping: watch::Receiver<bool>,
+) {
+    let mut interval = tokio::time::interval(interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
     loop {
-        let msg = common::connection_protocol::Message::read_stream(&mut socket).await;
-        match msg {
-            Ok(common::connection_protocol::Message::Login { username, password }) => {
-                let id = {
-                    let state = state.lock().unwrap();
-                    state.users.validate(&username, &password)
-                };
-                if let Some(id) = id {
-                    println!("User {} logged in", username);
-                    let buffer = common::connection_protocol::Message::Ok(0, None).to_bytes();
-                    socket.write(&buffer).await?;
-                    let player_data = {
-                        let mut state = state.lock().unwrap();
-                        let usr = state.users.get_mut(id).unwrap();
-                        usr.status = common::connection_protocol::PlayerStatus::Online;
-                        let usr = state.users.get(id).unwrap();
-                        let mut friends = Vec::new();
-                        for friend_id in &usr.friends {
-                            let friend = state.users.get(*friend_id).unwrap();
-                            let friend = common::connection_protocol::Friend {
-                                username: friend.username.clone(),
-                                status: friend.status.clone(),
-                                quote: friend.quote.clone(),
-                            };
-                            friends.push(friend);
-                        }
-                        common::connection_protocol::ClientData {
-                            username: usr.username.clone(),
-                            funds: usr.funds,
-                            quote: usr.quote.clone(),
-                            friends,
-                            status: usr.status.clone(),
-                        }
-                    };
-                    let buff = common::connection_protocol::Message::ClientData(player_data).to_bytes();
-                    println!("Sending: {:?}", buff.len());
-                    socket.write(&buff).await?;
-                    return Ok(());
-                }
-                let buffer = common::connection_protocol::Message::Error(
-                    0,
-                    Some("Invalid login".as_bytes().to_vec()),
-                )
-                .to_bytes();
-                socket.write(&buffer).await?;
-                return Ok(());
+        tokio::select! {
+            _ = interval.tick() => {},
+            _ = stopping.wait_for(|stopping| *stopping) => return,
+        }
+        match state.call(|state| state.flush()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => tracing::error!("Failed to save the database: {e}"),
+            Err(e) => tracing::error!("Failed to save the database: {e}"),
+        }
+    }
+}
+
+/// Resolves on Ctrl+C, or SIGTERM on unix
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!("Failed to listen for Ctrl+C: {e}");
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
             }
-            Ok(common::connection_protocol::Message::Register { username, password }) => {
-                if state
-                    .lock()
-                    .unwrap()
-                    .users
-                    .add_login(username, password)
-                {
-                    let buffer = common::connection_protocol::Message::Ok(0, None).to_bytes();
-                    socket.write(&buffer).await?;
-                    return Ok(());
-                }
-                let buffer = common::connection_protocol::Message::Error(
-                    0,
-                    Some("User already exists".as_bytes().to_vec()),
-                )
-                .to_bytes();
-                socket.write(&buffer).await?;
-                return Ok(());
+            Err(e) => {
+                tracing::error!("Failed to listen for SIGTERM: {e}");
+                std::future::pending::<()>().await;
             }
-            _ => (),
         }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
     }
 }
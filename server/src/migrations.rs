@@ -0,0 +1,374 @@
+//! Layout versions of the user database
+//!
+//! `users.txt` and `users.journal` start with `MAGIC`, the layout version and, since version 3,
+//! the generation of the snapshot, see `Header`. Files from before the header have no magic and
+//! are version 0. An older layout is upgraded one migration at a time when it is loaded, or
+//! offline with `server migrate`.
+//!
+//! When `DB_USER` changes: bump `USERS_VERSION` and add a migration from the previous version that
+//! rewrites a list of records in the old layout to the new one.
+use common::connection_protocol::{Chunks, ConnectionReader, ConnectionWriter, MessageError};
+
+use crate::config::{Config, Storage};
+use crate::db::Users;
+use crate::storage::DbFile;
+use crate::store::UserStore;
+
+/// Layout written by this build
+pub const USERS_VERSION: u32 = 3;
+
+const MAGIC: &[u8; 4] = b"VNDB";
+
+const HEADER_SIZE: usize = MAGIC.len() + 4;
+
+/// The first version with a generation in the header
+const GENERATION_SINCE: u32 = 3;
+
+/// Size of the header of `GENERATION_SINCE` and later
+const GENERATION_HEADER_SIZE: usize = HEADER_SIZE + 8;
+
+/// Upgrades the records of one layout version to the next
+pub struct Migration {
+    /// The version that is upgraded, the result is `from + 1`
+    pub from: u32,
+    pub description: &'static str,
+    /// Rewrites concatenated records
+    pub migrate: fn(Vec<u8>) -> Result<Vec<u8>, MessageError>,
+}
+
+/// Every migration, ordered by `from`
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        from: 0,
+        description: "add the file header",
+        migrate: unchanged,
+    },
+    Migration {
+        from: 1,
+        description: "add friend requests",
+        migrate: add_friend_requests,
+    },
+    Migration {
+        from: 2,
+        description: "add the snapshot generation to the header",
+        migrate: unchanged,
+    },
+];
+
+/// The records stay the same, only the header is new
+fn unchanged(records: Vec<u8>) -> Result<Vec<u8>, MessageError> {
+    Ok(records)
+}
+
+/// `DB_USER` of version 1, kept as it was since `DB_USER` moves on
+const DB_USER_V1: &[Chunks] = &[
+    // username
+    Chunks::String,
+    // password hash
+    Chunks::Binary,
+    // player id
+    Chunks::Uint { size: 8 },
+    // friends
+    Chunks::List(&[Chunks::Uint { size: 8 }]),
+    // quote
+    Chunks::String,
+    // funds
+    Chunks::Uint { size: 8 },
+    // card collection
+    Chunks::List(&[Chunks::Struct(common::connection_protocol::DB_OWNED_CARD)]),
+];
+
+/// What version 2 appends to every user
+const FRIEND_REQUESTS: &[Chunks] = &[
+    // received
+    Chunks::List(&[Chunks::Uint { size: 8 }]),
+    // sent
+    Chunks::List(&[Chunks::Uint { size: 8 }]),
+];
+
+/// Every user gets empty lists of friend requests
+fn add_friend_requests(records: Vec<u8>) -> Result<Vec<u8>, MessageError> {
+    let none: &[u64] = &[];
+    let requests = ConnectionWriter::new(FRIEND_REQUESTS)
+        .write_list(none, |_, _| {})
+        .write_list(none, |_, _| {})
+        .finalize();
+    let mut migrated = Vec::with_capacity(records.len());
+    let mut reader = ConnectionReader::new(DB_USER_V1, &records);
+    while reader.current_byte < records.len() {
+        let start = reader.current_byte;
+        reader.current_chunk = 0;
+        while reader.current_chunk < DB_USER_V1.len() {
+            reader.try_skip()?;
+        }
+        migrated.extend(&records[start..reader.current_byte]);
+        migrated.extend(&requests);
+    }
+    Ok(migrated)
+}
+
+/// Why a database file can't be read
+#[derive(Debug)]
+pub enum DbError {
+    /// Written by a newer build, or a migration is missing
+    UnknownVersion(u32),
+    Read(MessageError),
+}
+
+impl From<MessageError> for DbError {
+    fn from(e: MessageError) -> Self {
+        DbError::Read(e)
+    }
+}
+
+impl From<common::connection_protocol::ReadError> for DbError {
+    fn from(e: common::connection_protocol::ReadError) -> Self {
+        DbError::Read(e.into())
+    }
+}
+
+impl std::fmt::Display for DbError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DbError::UnknownVersion(version) => write!(
+                f,
+                "unknown database version {version}, this build reads up to {USERS_VERSION}"
+            ),
+            DbError::Read(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+/// The start of a database file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Header {
+    /// Layout of the records
+    pub version: u32,
+    /// Goes up with every snapshot, a journal only belongs to the snapshot of its generation
+    ///
+    /// 0 in files from before `GENERATION_SINCE`
+    pub generation: u64,
+}
+
+/// The header of a file in the current layout
+pub fn header(generation: u64) -> Vec<u8> {
+    let mut header = MAGIC.to_vec();
+    header.extend(USERS_VERSION.to_be_bytes());
+    header.extend(generation.to_be_bytes());
+    header
+}
+
+/// True if `bytes` start like a header but end before it does, a crash while writing it
+pub fn is_torn_header(bytes: &[u8]) -> bool {
+    let magic = bytes.len().min(MAGIC.len());
+    !bytes.is_empty() && bytes[..magic] == MAGIC[..magic] && split_header(bytes).0.version == 0
+}
+
+/// The header of a file and the records after it
+///
+/// A header that is cut off is no header, the records after it won't parse, see
+/// `is_torn_header`
+pub fn split_header(bytes: &[u8]) -> (Header, &[u8]) {
+    let headerless = Header {
+        version: 0,
+        generation: 0,
+    };
+    let Some(rest) = bytes
+        .strip_prefix(MAGIC)
+        .filter(|_| bytes.len() >= HEADER_SIZE)
+    else {
+        return (headerless, bytes);
+    };
+    let version = u32::from_be_bytes(rest[..4].try_into().unwrap());
+    if version < GENERATION_SINCE {
+        let header = Header {
+            version,
+            generation: 0,
+        };
+        return (header, &bytes[HEADER_SIZE..]);
+    }
+    if bytes.len() < GENERATION_HEADER_SIZE {
+        return (headerless, bytes);
+    }
+    let generation = u64::from_be_bytes(rest[4..12].try_into().unwrap());
+    let header = Header {
+        version,
+        generation,
+    };
+    (header, &bytes[GENERATION_HEADER_SIZE..])
+}
+
+/// The migrations that bring `version` up to date
+pub fn pending(version: u32) -> impl Iterator<Item = &'static Migration> {
+    MIGRATIONS.iter().filter(move |migration| migration.from >= version)
+}
+
+/// Rewrite records of layout `version` in the current layout
+pub fn upgrade(version: u32, mut records: Vec<u8>) -> Result<Vec<u8>, DbError> {
+    if version > USERS_VERSION {
+        return Err(DbError::UnknownVersion(version));
+    }
+    for from in version..USERS_VERSION {
+        let Some(migration) = MIGRATIONS.iter().find(|migration| migration.from == from) else {
+            return Err(DbError::UnknownVersion(from));
+        };
+        records = (migration.migrate)(records)?;
+    }
+    Ok(records)
+}
+
+/// `server migrate`, upgrades the database of `config` and folds the journal into it
+///
+/// The server must not run at the same time
+pub fn run(config: &Config, dry_run: bool) -> Result<(), String> {
+    if config.storage != Storage::File {
+        println!("Only the file storage has migrations, {:?} updates itself", config.storage);
+        return Ok(());
+    }
+    let path = config.users_path();
+    let bytes = match std::fs::read(&path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            println!("No database at {}", path.display());
+            return Ok(());
+        }
+        Err(e) => return Err(format!("Failed to read {}: {e}", path.display())),
+    };
+    let version = split_header(&bytes).0.version;
+    if version > USERS_VERSION {
+        return Err(format!("{}: {}", path.display(), DbError::UnknownVersion(version)));
+    }
+    if version == USERS_VERSION {
+        println!("{} is up to date, version {version}", path.display());
+    } else {
+        println!("{}: version {version} to {USERS_VERSION}", path.display());
+        for migration in pending(version) {
+            println!("  {} -> {}: {}", migration.from, migration.from + 1, migration.description);
+        }
+    }
+    if dry_run {
+        return Ok(());
+    }
+    let mut users = Users::load_db(
+        DbFile::new(path.clone(), config.backups),
+        &config.journal_path(),
+    );
+    // loading already wrote an old layout back, a second snapshot would push the old file out
+    // of the backups
+    if users.has_unsaved_changes() {
+        users
+            .compact()
+            .map_err(|e| format!("Failed to save {}: {e}", path.display()))?;
+    }
+    println!("Saved {} users", users.count());
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::db::UsersInfo;
+    use common::connection_protocol::Chunked;
+
+    /// A user the way versions 0 and 1 wrote it
+    pub fn v1_user(username: &str, player_id: u64) -> Vec<u8> {
+        let no_cards: &[(u64, u8)] = &[];
+        ConnectionWriter::new(DB_USER_V1)
+            .write_string(username)
+            .write_binary(&[1; 32])
+            .write_uint(player_id)
+            .write_list(&[7], |writer, friend| {
+                writer.write_uint(*friend);
+            })
+            .write_string("hi")
+            .write_uint(100)
+            .write_list(no_cards, |_, _| {})
+            .finalize()
+    }
+
+    #[test]
+    fn headers_and_versions() {
+        let records = [v1_user("user", 1), v1_user("other", 2)].concat();
+        let headerless = Header {
+            version: 0,
+            generation: 0,
+        };
+        assert_eq!(split_header(&records), (headerless, &records[..]));
+        let mut file = header(5);
+        file.extend(&records);
+        let current = Header {
+            version: USERS_VERSION,
+            generation: 5,
+        };
+        assert_eq!(split_header(&file), (current, &records[..]));
+        // version 2 had no generation
+        let mut old = file[..HEADER_SIZE].to_vec();
+        old[HEADER_SIZE - 1] = 2;
+        old.extend(&records);
+        let v2 = Header {
+            version: 2,
+            generation: 0,
+        };
+        assert_eq!(split_header(&old), (v2, &records[..]));
+        assert_eq!(split_header(&file[..10]), (headerless, &file[..10]));
+
+        let upgraded = upgrade(0, records.clone()).unwrap();
+        assert_eq!(upgrade(1, records.clone()).unwrap(), upgraded);
+        assert_eq!(upgrade(USERS_VERSION, upgraded.clone()).unwrap(), upgraded);
+        assert!(matches!(
+            upgrade(USERS_VERSION + 1, records.clone()),
+            Err(DbError::UnknownVersion(_))
+        ));
+        // a record cut off in the middle
+        assert!(upgrade(1, records[..records.len() - 1].to_vec()).is_err());
+        // every old version has a way up
+        for version in 0..USERS_VERSION {
+            assert_eq!(pending(version).count(), (USERS_VERSION - version) as usize);
+        }
+
+        let mut reader = ConnectionReader::new(UsersInfo::SCHEMA, &upgraded);
+        let user = UsersInfo::read_chunks(&mut reader).unwrap();
+        reader.current_chunk = 0;
+        let other = UsersInfo::read_chunks(&mut reader).unwrap();
+        assert_eq!(reader.current_byte, upgraded.len());
+        assert_eq!((user.username.as_str(), user.player_id), ("user", 1));
+        assert_eq!((user.friends, user.quote, user.funds), (vec![7], "hi".to_string(), 100));
+        assert!(user.friend_requests.is_empty() && user.sent_requests.is_empty());
+        assert_eq!(other.username, "other");
+    }
+
+    #[test]
+    fn migrate_command_folds_an_old_journal_in() {
+        let dir = std::env::temp_dir().join(format!("verynoha-migrate-run-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = Config {
+            db: dir.clone(),
+            ..Default::default()
+        };
+        let legacy = v1_user("user", 1);
+        std::fs::write(config.users_path(), &legacy).unwrap();
+        // a journal from before the header, adding a user in the layout of version 1
+        let entry = ConnectionWriter::new(&[Chunks::Uint { size: 1 }, Chunks::Binary])
+            .write_uint(0)
+            .write_binary(&v1_user("other", 2))
+            .finalize();
+        std::fs::write(config.journal_path(), entry).unwrap();
+
+        let dry_run = run(&config, true);
+        let untouched = std::fs::read(config.users_path()).unwrap();
+        let migrated = run(&config, false);
+        let saved = std::fs::read(config.users_path()).unwrap();
+        let journal = std::fs::read(config.journal_path()).unwrap();
+        let backup = std::fs::read(DbFile::new(config.users_path(), 1).backup_path(1)).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!((dry_run, migrated), (Ok(()), Ok(())));
+        assert_eq!((untouched, backup), (legacy.clone(), legacy));
+        assert_eq!(journal, header(1));
+        let users = Users::from_bytes(&saved).unwrap();
+        assert_eq!(split_header(&saved).0.version, USERS_VERSION);
+        assert_eq!(users.count(), 2);
+        let other = users.get(2).unwrap();
+        assert_eq!((other.username.as_str(), &other.friends), ("other", &vec![7]));
+    }
+}
@@ -0,0 +1,130 @@
+//! Storage of password hashes
+//!
+//! Clients send `common::login::hash_password` of the password, the server only stores a salted
+//! PBKDF2-HMAC-SHA256 of that. Records from before are the bare client hash (32 bytes) and get
+//! replaced on the next successful login.
+use pbkdf2::pbkdf2_hmac;
+use rand::RngCore;
+use sha2::Sha256;
+
+/// First byte of a PBKDF2-HMAC-SHA256 record
+const PBKDF2_SHA256: u8 = 1;
+
+/// Work factor of new hashes
+pub const ITERATIONS: u32 = 100_000;
+
+const SALT_SIZE: usize = 16;
+const HASH_SIZE: usize = 32;
+
+/// tag + iterations + salt + hash
+const RECORD_SIZE: usize = 1 + 4 + SALT_SIZE + HASH_SIZE;
+
+/// Size of the unsalted records written by older servers
+const LEGACY_SIZE: usize = 32;
+
+/// A stored password, see the module docs
+#[derive(Debug, PartialEq)]
+enum Record<'a> {
+    /// The client hash as it was sent
+    Legacy(&'a [u8]),
+    Pbkdf2 {
+        iterations: u32,
+        salt: &'a [u8],
+        hash: &'a [u8],
+    },
+}
+
+impl<'a> Record<'a> {
+    fn parse(stored: &'a [u8]) -> Option<Self> {
+        match stored.len() {
+            LEGACY_SIZE => Some(Record::Legacy(stored)),
+            RECORD_SIZE if stored[0] == PBKDF2_SHA256 => {
+                let iterations = u32::from_be_bytes(stored[1..5].try_into().unwrap());
+                Some(Record::Pbkdf2 {
+                    iterations,
+                    salt: &stored[5..5 + SALT_SIZE],
+                    hash: &stored[5 + SALT_SIZE..],
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Hash the password sent by the client with a new random salt
+pub fn hash(password: &[u8]) -> Vec<u8> {
+    hash_with_iterations(password, ITERATIONS)
+}
+
+fn hash_with_iterations(password: &[u8], iterations: u32) -> Vec<u8> {
+    let mut salt = [0; SALT_SIZE];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let mut record = Vec::with_capacity(RECORD_SIZE);
+    record.push(PBKDF2_SHA256);
+    record.extend_from_slice(&iterations.to_be_bytes());
+    record.extend_from_slice(&salt);
+    record.extend_from_slice(&derive(password, &salt, iterations));
+    record
+}
+
+fn derive(password: &[u8], salt: &[u8], iterations: u32) -> [u8; HASH_SIZE] {
+    let mut hash = [0; HASH_SIZE];
+    pbkdf2_hmac::<Sha256>(password, salt, iterations, &mut hash);
+    hash
+}
+
+/// Checks the password sent by the client against the stored record
+pub fn verify(stored: &[u8], password: &[u8]) -> bool {
+    match Record::parse(stored) {
+        Some(Record::Legacy(hash)) => constant_time_eq(hash, password),
+        Some(Record::Pbkdf2 {
+            iterations,
+            salt,
+            hash,
+        }) => constant_time_eq(hash, &derive(password, salt, iterations)),
+        None => false,
+    }
+}
+
+/// Checks if the record should be replaced by `hash` after a successful login
+pub fn needs_upgrade(stored: &[u8]) -> bool {
+    match Record::parse(stored) {
+        Some(Record::Pbkdf2 { iterations, .. }) => iterations < ITERATIONS,
+        _ => true,
+    }
+}
+
+/// Compares without returning early, so the time taken doesn't leak the matching prefix
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn salted_records() {
+        let password = common::login::hash_password("password");
+        let first = hash_with_iterations(&password, 10);
+        let second = hash_with_iterations(&password, 10);
+        assert_eq!(first.len(), RECORD_SIZE);
+        assert_ne!(first, second);
+        assert!(verify(&first, &password));
+        assert!(verify(&second, &password));
+        assert!(!verify(&first, &common::login::hash_password("Password")));
+        // the stored record doesn't work as the password
+        assert!(!verify(&first, &first));
+        assert!(needs_upgrade(&first));
+    }
+
+    #[test]
+    fn legacy_records() {
+        let password = common::login::hash_password("password");
+        assert!(verify(&password, &password));
+        assert!(!verify(&password, &common::login::hash_password("other")));
+        assert!(needs_upgrade(&password));
+        assert!(!verify(&[], &[]));
+        assert!(!needs_upgrade(&hash(&password)));
+    }
+}
@@ -0,0 +1,209 @@
+//! Rules for new usernames and for quotes
+use common::login::{self, LoginValidation};
+use serde::Deserialize;
+
+/// What a username has to look like to be registered
+///
+/// The length and whitespace rules of `common::login::validate_username` always apply
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UsernamePolicy {
+    /// Names nobody can register, compared case insensitively
+    pub reserved: Vec<String>,
+    /// Allow letters and digits outside of ASCII
+    pub allow_unicode: bool,
+    /// Characters allowed besides letters and digits
+    pub allowed_symbols: String,
+}
+
+impl Default for UsernamePolicy {
+    fn default() -> Self {
+        Self {
+            reserved: ["admin", "administrator", "moderator", "server", "system"]
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+            allow_unicode: true,
+            allowed_symbols: "_-.".to_string(),
+        }
+    }
+}
+
+/// Why a username was rejected
+#[derive(Debug, PartialEq)]
+pub enum UsernameViolation {
+    Invalid(LoginValidation),
+    Reserved,
+    InvalidCharacter(char),
+}
+
+impl std::fmt::Display for UsernameViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UsernameViolation::Invalid(validation) => write!(f, "{validation}"),
+            UsernameViolation::Reserved => write!(f, "Username is reserved"),
+            UsernameViolation::InvalidCharacter(c) => {
+                write!(f, "Username can not contain {c:?}")
+            }
+        }
+    }
+}
+
+impl UsernamePolicy {
+    pub fn check(&self, username: &str) -> Result<(), UsernameViolation> {
+        let validation = login::validate_username(username);
+        if !validation.is_valid() {
+            return Err(UsernameViolation::Invalid(validation));
+        }
+        if let Some(c) = username.chars().find(|c| !self.allows(*c)) {
+            return Err(UsernameViolation::InvalidCharacter(c));
+        }
+        let lowercase = username.to_lowercase();
+        if self.reserved.iter().any(|name| name.to_lowercase() == lowercase) {
+            return Err(UsernameViolation::Reserved);
+        }
+        Ok(())
+    }
+
+    fn allows(&self, c: char) -> bool {
+        if c.is_ascii_alphanumeric() {
+            return true;
+        }
+        if c.is_alphanumeric() {
+            return self.allow_unicode;
+        }
+        self.allowed_symbols.contains(c)
+    }
+}
+
+/// What a quote has to look like to be set
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuotePolicy {
+    /// Counted in characters, not bytes
+    pub max_length: usize,
+    /// Words no quote can contain, compared case insensitively
+    pub blocked_words: Vec<String>,
+}
+
+impl Default for QuotePolicy {
+    fn default() -> Self {
+        Self {
+            max_length: 100,
+            blocked_words: Vec::new(),
+        }
+    }
+}
+
+/// Why a quote was rejected
+#[derive(Debug, PartialEq)]
+pub enum QuoteViolation {
+    TooLong { max: usize },
+    /// Line breaks, tabs and other characters that would break the friend list
+    ControlCharacter,
+    BlockedWord,
+}
+
+impl std::fmt::Display for QuoteViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QuoteViolation::TooLong { max } => {
+                write!(f, "Quote can not be longer than {max} characters")
+            }
+            QuoteViolation::ControlCharacter => {
+                write!(f, "Quote can not contain control characters")
+            }
+            QuoteViolation::BlockedWord => write!(f, "Quote contains a blocked word"),
+        }
+    }
+}
+
+impl QuotePolicy {
+    /// An empty quote is fine, it clears the old one
+    pub fn check(&self, quote: &str) -> Result<(), QuoteViolation> {
+        if quote.chars().count() > self.max_length {
+            return Err(QuoteViolation::TooLong {
+                max: self.max_length,
+            });
+        }
+        if quote.chars().any(char::is_control) {
+            return Err(QuoteViolation::ControlCharacter);
+        }
+        let lowercase = quote.to_lowercase();
+        if self
+            .blocked_words
+            .iter()
+            .any(|word| !word.is_empty() && lowercase.contains(&word.to_lowercase()))
+        {
+            return Err(QuoteViolation::BlockedWord);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_policy() {
+        let policy = UsernamePolicy::default();
+        assert_eq!(policy.check("player_1"), Ok(()));
+        assert_eq!(policy.check("jürgen"), Ok(()));
+        assert_eq!(
+            policy.check(""),
+            Err(UsernameViolation::Invalid(LoginValidation::UsernameTooShort))
+        );
+        assert_eq!(
+            policy.check(&"a".repeat(10 * 1024)),
+            Err(UsernameViolation::Invalid(LoginValidation::UsernameTooLong))
+        );
+        assert_eq!(
+            policy.check("two words"),
+            Err(UsernameViolation::Invalid(
+                LoginValidation::UsernameContainsWhitespace
+            ))
+        );
+        assert_eq!(policy.check("AdMiN"), Err(UsernameViolation::Reserved));
+        assert_eq!(
+            policy.check("semi;colon"),
+            Err(UsernameViolation::InvalidCharacter(';'))
+        );
+    }
+
+    #[test]
+    fn ascii_only() {
+        let policy = UsernamePolicy {
+            allow_unicode: false,
+            allowed_symbols: String::new(),
+            ..Default::default()
+        };
+        assert_eq!(policy.check("player1"), Ok(()));
+        assert_eq!(
+            policy.check("jürgen"),
+            Err(UsernameViolation::InvalidCharacter('ü'))
+        );
+        assert_eq!(
+            policy.check("player_1"),
+            Err(UsernameViolation::InvalidCharacter('_'))
+        );
+    }
+
+    #[test]
+    fn quotes() {
+        let policy = QuotePolicy {
+            max_length: 5,
+            blocked_words: vec!["Bad".to_string()],
+        };
+        assert_eq!(policy.check(""), Ok(()));
+        assert_eq!(policy.check("ünï"), Ok(()));
+        assert_eq!(policy.check("äöüäö"), Ok(()));
+        assert_eq!(
+            policy.check("äöüäöü"),
+            Err(QuoteViolation::TooLong { max: 5 })
+        );
+        assert_eq!(policy.check("a\nb"), Err(QuoteViolation::ControlCharacter));
+        assert_eq!(policy.check("BADly"), Err(QuoteViolation::BlockedWord));
+        assert_eq!(QuotePolicy::default().check(&"a".repeat(100)), Ok(()));
+    }
+}
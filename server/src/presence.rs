@@ -0,0 +1,62 @@
+//! The sessions of logged in players, used to push messages to them
+//!
+//! A player can be logged in on several connections at once, every one of them gets the pushes.
+//! The registry is part of the `ServerState`, so registering a session and changing the status
+//! of its player happen together.
+use std::collections::HashMap;
+
+use tokio::sync::mpsc;
+
+use common::connection_protocol::Message;
+
+/// Identifies one connection of a player
+pub type SessionId = u64;
+
+/// Where a session receives its pushes
+///
+/// Unbounded since the state thread must never wait for a slow client
+pub type Pushes = mpsc::UnboundedSender<Message>;
+
+#[derive(Default)]
+pub struct Sessions {
+    next_id: SessionId,
+    by_player: HashMap<u64, Vec<(SessionId, Pushes)>>,
+}
+
+impl Sessions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a new session of `player_id`
+    pub fn add(&mut self, player_id: u64, pushes: Pushes) -> SessionId {
+        self.next_id += 1;
+        self.by_player
+            .entry(player_id)
+            .or_default()
+            .push((self.next_id, pushes));
+        self.next_id
+    }
+
+    /// Forgets the session, returns true if it was the last one of `player_id`
+    pub fn remove(&mut self, player_id: u64, session: SessionId) -> bool {
+        let Some(sessions) = self.by_player.get_mut(&player_id) else {
+            return false;
+        };
+        let before = sessions.len();
+        sessions.retain(|(id, _)| *id != session);
+        let removed = sessions.len() < before;
+        if sessions.is_empty() {
+            self.by_player.remove(&player_id);
+        }
+        removed && !self.by_player.contains_key(&player_id)
+    }
+
+    /// Sends `message` to every session of `player_id`, nothing if they are not connected
+    pub fn push(&self, player_id: u64, message: &Message) {
+        for (_, pushes) in self.by_player.get(&player_id).into_iter().flatten() {
+            // a closed session is removed as soon as its task ends
+            let _ = pushes.send(message.clone());
+        }
+    }
+}
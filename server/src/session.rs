@@ -0,0 +1,918 @@
+//! The conversation with a single client
+use crate::actor::{StateError, StateHandle};
+use crate::db::ServerState;
+use crate::friends::FriendError;
+use crate::password;
+use crate::presence::{Pushes, SessionId};
+use common::connection_protocol::{
+    self, Connection, Envelope, ErrorCode, Message, MessageError, PlayerStatus,
+};
+use common::login;
+use futures::{SinkExt, StreamExt};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, watch};
+
+/// Name the server announces in its `Hello`
+const SERVER_NAME: &str = concat!("verynoha-server/", env!("CARGO_PKG_VERSION"));
+
+/// What the client is allowed to do
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    /// Only login and registration
+    Unauthenticated,
+    /// Logged in, requests act on behalf of `player_id`
+    Authenticated { player_id: u64, session: SessionId },
+}
+
+pub struct Session {
+    connection: Connection,
+    state: StateHandle,
+    phase: Phase,
+    /// Address of the client, failed logins are limited per address
+    ip: IpAddr,
+    /// Becomes true when the server is stopping
+    shutdown: watch::Receiver<bool>,
+    /// Agreed on in the handshake
+    capabilities: Vec<String>,
+    /// Handed to the state when the user logs in, see `presence`
+    pushes: Pushes,
+    queued_pushes: mpsc::UnboundedReceiver<Message>,
+}
+
+/// Error telling the client when it can try again
+fn rate_limited(retry_after: Duration) -> Message {
+    let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    let retry_after = seconds.try_into().unwrap_or(u32::MAX);
+    Message::Error(ErrorCode::RateLimited { retry_after }, None)
+}
+
+impl Session {
+    pub fn new(
+        connection: Connection,
+        state: StateHandle,
+        addr: SocketAddr,
+        shutdown: watch::Receiver<bool>,
+    ) -> Self {
+        let (pushes, queued_pushes) = mpsc::unbounded_channel();
+        Self {
+            connection,
+            state,
+            phase: Phase::Unauthenticated,
+            ip: addr.ip(),
+            shutdown,
+            capabilities: Vec::new(),
+            pushes,
+            queued_pushes,
+        }
+    }
+
+    /// Serve the client until it disconnects or the server shuts down
+    ///
+    /// The user is set offline however the connection ends
+    pub async fn run(mut self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let res = self.serve().await;
+        self.logout().await;
+        res
+    }
+
+    /// A request that was read when the server started stopping is answered before the client
+    /// is told about the shutdown
+    async fn serve(&mut self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut shutdown = self.shutdown.clone();
+        let greeted = tokio::select! {
+            biased;
+            _ = stopping(&mut shutdown) => return self.shutting_down().await,
+            greeted = self.handshake() => greeted?,
+        };
+        if !greeted {
+            return Ok(());
+        }
+        loop {
+            let frame = tokio::select! {
+                biased;
+                _ = stopping(&mut shutdown) => return self.shutting_down().await,
+                frame = self.connection.next() => frame,
+                Some(push) = self.queued_pushes.recv() => {
+                    if self.wants(&push) {
+                        self.connection.send(push).await?;
+                    }
+                    continue;
+                }
+            };
+            let envelope = match frame {
+                Some(Ok(Ok(envelope))) => envelope,
+                Some(Ok(Err(invalid))) => {
+                    tracing::debug!("Invalid message: {}", invalid.error);
+                    let detail = Some(invalid.error.to_string());
+                    let error = Message::Error(ErrorCode::InvalidMessage, detail);
+                    self.connection
+                        .send(Envelope::new(invalid.id, error))
+                        .await?;
+                    continue;
+                }
+                None | Some(Err(MessageError::ConnectionClosed | MessageError::Io(_))) => {
+                    return Ok(())
+                }
+                Some(Err(e)) => {
+                    // the codec can't find the next frame after a broken length
+                    tracing::warn!("Closing connection after invalid frame: {e}");
+                    let error = Message::Error(ErrorCode::InvalidMessage, Some(e.to_string()));
+                    let _ = self.connection.send(error).await;
+                    return Ok(());
+                }
+            };
+            if envelope.is_push() {
+                tracing::debug!("Ignoring message without request id");
+                continue;
+            }
+            let was_authenticated = self.phase != Phase::Unauthenticated;
+            let response = match self.phase {
+                Phase::Unauthenticated => self.unauthenticated(envelope.message).await,
+                Phase::Authenticated { player_id, .. } => {
+                    self.authenticated(player_id, envelope.message).await
+                }
+            };
+            let response = response.unwrap_or_else(|e| {
+                tracing::error!("Request failed: {e}");
+                Message::Error(ErrorCode::Internal, None)
+            });
+            self.connection
+                .send(Envelope::new(envelope.id, response))
+                .await?;
+            if let (false, Phase::Authenticated { player_id, .. }) = (was_authenticated, self.phase)
+            {
+                let data = self
+                    .state
+                    .call(move |state| state.client_data(player_id))
+                    .await;
+                if let Ok(Some(data)) = data {
+                    self.connection.send(Message::ClientData(data)).await?;
+                }
+            }
+        }
+    }
+
+    async fn shutting_down(&mut self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let message = Message::Error(ErrorCode::ShuttingDown, None);
+        self.connection.send(message).await.map_err(Into::into)
+    }
+
+    /// Answers the `Hello` of the client, in the layout every version of the client can read
+    ///
+    /// Returns false if the connection should be closed
+    async fn handshake(&mut self) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        self.connection.codec_mut().handshake = true;
+        let (request, message) = match self.connection.next().await {
+            Some(Ok(Ok(envelope))) => (envelope.id, Some(envelope.message)),
+            // not a `Hello` of any version
+            Some(Ok(Err(invalid))) => (invalid.id, None),
+            _ => return Ok(false),
+        };
+        match message {
+            Some(Message::Hello {
+                protocol_version,
+                client_name,
+                capabilities,
+            }) => {
+                if !connection_protocol::is_compatible(protocol_version) {
+                    tracing::info!("Refusing {client_name}, protocol version {protocol_version}");
+                    let detail = format!(
+                        "Unsupported protocol version {protocol_version}, server supports {} to {}",
+                        connection_protocol::MIN_PROTOCOL_VERSION,
+                        connection_protocol::PROTOCOL_VERSION
+                    );
+                    self.connection
+                        .send(Envelope::new(
+                            request,
+                            Message::Error(ErrorCode::ProtocolMismatch, Some(detail)),
+                        ))
+                        .await?;
+                    return Ok(false);
+                }
+                tracing::info!(
+                    "Client {client_name} connected, protocol version {protocol_version}"
+                );
+                self.capabilities = connection_protocol::common_capabilities(&capabilities);
+                let hello = Message::Hello {
+                    protocol_version: connection_protocol::PROTOCOL_VERSION,
+                    client_name: SERVER_NAME.to_string(),
+                    capabilities: self.capabilities.clone(),
+                };
+                self.connection.send(Envelope::new(request, hello)).await?;
+                self.connection.codec_mut().handshake = false;
+                Ok(true)
+            }
+            _ => {
+                self.connection
+                    .send(Envelope::new(
+                        request,
+                        Message::Error(
+                            ErrorCode::ProtocolMismatch,
+                            Some("Expected Hello".to_string()),
+                        ),
+                    ))
+                    .await?;
+                Ok(false)
+            }
+        }
+    }
+
+    async fn unauthenticated(&mut self, message: Message) -> Result<Message, StateError> {
+        match message {
+            Message::Login { username, password } => self.login(username, password).await,
+            Message::Register { username, password } => self.register(username, password).await,
+            Message::Resume { token } => {
+                let pushes = self.pushes.clone();
+                let resumed = self
+                    .state
+                    .call(move |state| {
+                        let player_id = state.resume(&token)?;
+                        let session = state.connect(player_id, pushes);
+                        state.push_friend_requests(player_id);
+                        Some((player_id, session))
+                    })
+                    .await?;
+                match resumed {
+                    Some((player_id, session)) => {
+                        tracing::info!("Player {player_id} resumed a session");
+                        self.phase = Phase::Authenticated { player_id, session };
+                        Ok(Message::Ok(player_id, None))
+                    }
+                    None => Ok(Message::Error(ErrorCode::InvalidToken, None)),
+                }
+            }
+            Message::GetClientData
+            | Message::Logout
+            | Message::ChangePassword { .. }
+            | Message::FriendRequest { .. }
+            | Message::FriendAccept { .. }
+            | Message::FriendDecline { .. }
+            | Message::FriendRemove { .. }
+            | Message::SetQuote { .. }
+            | Message::SetStatus { .. } => Ok(Message::Error(ErrorCode::NotLoggedIn, None)),
+            _ => Ok(Message::Error(ErrorCode::InvalidMessage, None)),
+        }
+    }
+
+    /// Checks the password outside of the state, the login only counts if the stored record
+    /// did not change in the meantime
+    async fn login(&mut self, username: String, password: Vec<u8>) -> Result<Message, StateError> {
+        let ip = self.ip;
+        let name = username.clone();
+        let stored = self
+            .state
+            .call(move |state| {
+                let now = Instant::now();
+                if let Some(retry_after) = state.limiter.retry_after(ip, &name, now) {
+                    return Err(rate_limited(retry_after));
+                }
+                match state.users.get_id(&name).and_then(|id| state.users.get(id)) {
+                    Some(user) => Ok((user.player_id, user.password.clone())),
+                    None => {
+                        state.limiter.failed(ip, &name, now);
+                        Err(Message::Error(ErrorCode::UnknownUser, None))
+                    }
+                }
+            })
+            .await?;
+        let (player_id, record) = match stored {
+            Ok(stored) => stored,
+            Err(response) => return Ok(response),
+        };
+
+        let (valid, upgrade) = check_password(record.clone(), password).await;
+        let name = username.clone();
+        let pushes = self.pushes.clone();
+        let token = self
+            .state
+            .call(move |state| {
+                let now = Instant::now();
+                // other connections may have failed while this one was hashing
+                if let Some(retry_after) = state.limiter.retry_after(ip, &name, now) {
+                    return Err(rate_limited(retry_after));
+                }
+                let current = state.users.get(player_id).map(|user| user.password.clone());
+                if !valid || current.as_ref() != Some(&record) {
+                    state.limiter.failed(ip, &name, now);
+                    return Err(Message::Error(ErrorCode::InvalidCredentials, None));
+                }
+                state.limiter.succeeded(&name);
+                if let Some(upgrade) = upgrade {
+                    tracing::info!("Upgrading the password hash of {name}");
+                    state.users.set_password_record(player_id, upgrade);
+                }
+                let session = state.connect(player_id, pushes);
+                state.push_friend_requests(player_id);
+                Ok((session, state.issue_token(player_id)))
+            })
+            .await?;
+        match token {
+            Ok((session, token)) => {
+                tracing::info!("User {} logged in", username);
+                self.phase = Phase::Authenticated { player_id, session };
+                Ok(Message::Ok(player_id, Some(token)))
+            }
+            Err(response) => Ok(response),
+        }
+    }
+
+    async fn register(
+        &mut self,
+        username: String,
+        password: Vec<u8>,
+    ) -> Result<Message, StateError> {
+        let name = username.clone();
+        let allowed = self
+            .state
+            .call(move |state| state.username_policy.check(&name))
+            .await?;
+        if let Err(violation) = allowed {
+            return Ok(Message::Error(
+                ErrorCode::InvalidUsername,
+                Some(violation.to_string()),
+            ));
+        }
+        if password.len() != login::PASSWORD_HASH_SIZE {
+            return Ok(Message::Error(
+                ErrorCode::InvalidPassword,
+                Some("Expected a hashed password".to_string()),
+            ));
+        }
+        let record = hash_password(password).await;
+        let created = self
+            .state
+            .call(move |state| state.users.create(username, record))
+            .await?;
+        match created {
+            Some(_) => Ok(Message::Ok(0, None)),
+            None => Ok(Message::Error(ErrorCode::UserExists, None)),
+        }
+    }
+
+    async fn authenticated(
+        &mut self,
+        player_id: u64,
+        message: Message,
+    ) -> Result<Message, StateError> {
+        match message {
+            Message::GetClientData => {
+                let data = self
+                    .state
+                    .call(move |state| state.client_data(player_id))
+                    .await?;
+                match data {
+                    Some(data) => Ok(Message::ClientData(data)),
+                    None => Ok(Message::Error(ErrorCode::Internal, None)),
+                }
+            }
+            Message::Logout => {
+                self.state
+                    .call(move |state| state.revoke_tokens(player_id))
+                    .await?;
+                self.logout().await;
+                Ok(Message::Ok(0, None))
+            }
+            Message::ChangePassword {
+                password,
+                new_password,
+            } => {
+                self.change_password(player_id, password, new_password)
+                    .await
+            }
+            Message::FriendRequest { username } => {
+                self.friends(player_id, username, ServerState::request_friend)
+                    .await
+            }
+            Message::FriendAccept { username } => {
+                self.friends(player_id, username, ServerState::accept_friend)
+                    .await
+            }
+            Message::FriendDecline { username } => {
+                self.friends(player_id, username, ServerState::decline_friend)
+                    .await
+            }
+            Message::FriendRemove { username } => {
+                self.friends(player_id, username, ServerState::remove_friend)
+                    .await
+            }
+            Message::SetQuote { quote } => {
+                self.state
+                    .call(move |state| {
+                        if let Err(e) = state.quote_policy.check(&quote) {
+                            return Message::Error(ErrorCode::InvalidQuote, Some(e.to_string()));
+                        }
+                        match state.set_quote(player_id, quote) {
+                            true => Message::Ok(0, None),
+                            false => Message::Error(ErrorCode::Internal, None),
+                        }
+                    })
+                    .await
+            }
+            Message::SetStatus { status } => match status {
+                // offline and in game follow from the connection and the games
+                PlayerStatus::Online | PlayerStatus::Away | PlayerStatus::Busy => {
+                    self.state
+                        .call(move |state| state.set_status(player_id, status))
+                        .await?;
+                    Ok(Message::Ok(0, None))
+                }
+                _ => Ok(Message::Error(
+                    ErrorCode::InvalidMessage,
+                    Some("Only online, away and busy can be chosen".to_string()),
+                )),
+            },
+            Message::Login { .. } | Message::Register { .. } | Message::Resume { .. } => {
+                Ok(Message::Error(
+                    ErrorCode::InvalidMessage,
+                    Some("Already logged in".to_string()),
+                ))
+            }
+            _ => Ok(Message::Error(ErrorCode::InvalidMessage, None)),
+        }
+    }
+
+    /// Like `login`, the new password is only set if the old record is still the one checked
+    async fn change_password(
+        &mut self,
+        player_id: u64,
+        password: Vec<u8>,
+        new_password: Vec<u8>,
+    ) -> Result<Message, StateError> {
+        let ip = self.ip;
+        let stored = self
+            .state
+            .call(move |state| {
+                let Some(user) = state.users.get(player_id) else {
+                    return Err(Message::Error(ErrorCode::Internal, None));
+                };
+                if let Some(retry_after) =
+                    state
+                        .limiter
+                        .retry_after(ip, &user.username, Instant::now())
+                {
+                    return Err(rate_limited(retry_after));
+                }
+                Ok((user.username.clone(), user.password.clone()))
+            })
+            .await?;
+        let (username, record) = match stored {
+            Ok(stored) => stored,
+            Err(response) => return Ok(response),
+        };
+
+        let (valid, _) = check_password(record.clone(), password).await;
+        if valid && new_password.len() != login::PASSWORD_HASH_SIZE {
+            return Ok(Message::Error(
+                ErrorCode::InvalidPassword,
+                Some("Expected a hashed password".to_string()),
+            ));
+        }
+        let new_record = match valid {
+            true => Some(hash_password(new_password).await),
+            false => None,
+        };
+        self.state
+            .call(move |state| {
+                let now = Instant::now();
+                if let Some(retry_after) = state.limiter.retry_after(ip, &username, now) {
+                    return rate_limited(retry_after);
+                }
+                let current = state.users.get(player_id).map(|user| user.password.clone());
+                let new_record = new_record.filter(|_| current.as_ref() == Some(&record));
+                let Some(new_record) = new_record else {
+                    state.limiter.failed(ip, &username, now);
+                    return Message::Error(ErrorCode::InvalidCredentials, None);
+                };
+                state.users.set_password_record(player_id, new_record);
+                state.revoke_tokens(player_id);
+                Message::Ok(0, None)
+            })
+            .await
+    }
+
+    /// Runs one of the friend methods of `ServerState` for the logged in player
+    async fn friends(
+        &self,
+        player_id: u64,
+        username: String,
+        change: fn(&mut ServerState, u64, &str) -> Result<(), FriendError>,
+    ) -> Result<Message, StateError> {
+        let changed = self
+            .state
+            .call(move |state| change(state, player_id, &username))
+            .await?;
+        match changed {
+            Ok(()) => Ok(Message::Ok(0, None)),
+            Err(e) => Ok(e.to_message()),
+        }
+    }
+
+    /// Pushes only go to clients that agreed on the capability they belong to
+    fn wants(&self, push: &Message) -> bool {
+        let capability = match push {
+            Message::FriendStatusChanged { .. } => "presence",
+            Message::FriendRequest { .. }
+            | Message::FriendAccept { .. }
+            | Message::FriendDecline { .. }
+            | Message::FriendRemove { .. } => "friends",
+            Message::FriendQuoteChanged { .. } => "profile",
+            _ => return true,
+        };
+        self.capabilities.iter().any(|agreed| agreed == capability)
+    }
+
+    /// Back to `Phase::Unauthenticated`, the user is shown as offline if this was their last
+    /// connection
+    async fn logout(&mut self) {
+        if let Phase::Authenticated { player_id, session } = self.phase {
+            let offline = self
+                .state
+                .call(move |state| state.disconnect(player_id, session))
+                .await;
+            if let Err(e) = offline {
+                tracing::error!("Failed to set player {player_id} offline: {e}");
+            }
+            tracing::info!("Player {player_id} logged out");
+        }
+        self.phase = Phase::Unauthenticated;
+        // pushes for the old user can still be queued, the next user must not get them
+        (self.pushes, self.queued_pushes) = mpsc::unbounded_channel();
+    }
+}
+
+/// Resolves once the server is stopping
+async fn stopping(shutdown: &mut watch::Receiver<bool>) {
+    let _ = shutdown.wait_for(|stopping| *stopping).await;
+}
+
+/// Checks `password` against the stored `record` on the blocking pool, hashing is slow
+///
+/// Also returns the record that replaces `record` if it is in an old format
+async fn check_password(record: Vec<u8>, password: Vec<u8>) -> (bool, Option<Vec<u8>>) {
+    let check = move || {
+        if !password::verify(&record, &password) {
+            return (false, None);
+        }
+        let upgrade = password::needs_upgrade(&record).then(|| password::hash(&password));
+        (true, upgrade)
+    };
+    tokio::task::spawn_blocking(check)
+        .await
+        .unwrap_or((false, None))
+}
+
+/// The record of a new password, hashed on the blocking pool
+async fn hash_password(password: Vec<u8>) -> Vec<u8> {
+    tokio::task::spawn_blocking(move || password::hash(&password))
+        .await
+        .expect("Hashing a password panicked")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, Storage};
+    use common::connection_protocol::MessageCodec;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_util::codec::Framed;
+
+    type Client = Framed<TcpStream, MessageCodec>;
+
+    /// An in-memory server with the accounts `alice` and `bob`, their password hash is `[1; 32]`
+    fn server() -> (StateHandle, watch::Sender<bool>) {
+        let mut state = ServerState::new(&Config {
+            storage: Storage::Memory,
+            ..Default::default()
+        });
+        for name in ["alice", "bob"] {
+            assert!(state.users.add_login(name.to_string(), vec![1; 32]));
+        }
+        let (state, _) = StateHandle::spawn(state);
+        (state, watch::channel(false).0)
+    }
+
+    /// A client that agreed on every capability with a new session
+    async fn connect(state: &StateHandle, shutdown: &watch::Sender<bool>) -> Client {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (socket, addr) = listener.accept().await.unwrap();
+        let session = Session::new(
+            Framed::new(socket, MessageCodec::new()),
+            state.clone(),
+            addr,
+            shutdown.subscribe(),
+        );
+        tokio::spawn(session.run());
+
+        let mut client = Framed::new(client, MessageCodec::new());
+        client.codec_mut().handshake = true;
+        let hello = Message::Hello {
+            protocol_version: connection_protocol::PROTOCOL_VERSION,
+            client_name: "test".to_string(),
+            capabilities: connection_protocol::CAPABILITIES
+                .iter()
+                .map(|capability| capability.to_string())
+                .collect(),
+        };
+        client.send(Envelope::new(1, hello)).await.unwrap();
+        assert!(matches!(
+            next(&mut client).await.message,
+            Message::Hello { .. }
+        ));
+        client.codec_mut().handshake = false;
+        client
+    }
+
+    async fn next(client: &mut Client) -> Envelope {
+        tokio::time::timeout(Duration::from_secs(10), client.next())
+            .await
+            .expect("No answer from the session")
+            .unwrap()
+            .unwrap()
+            .unwrap()
+    }
+
+    /// Logs in and reads the `Ok` and the `ClientData` that follows it
+    async fn login(client: &mut Client, username: &str) {
+        let login = Message::Login {
+            username: username.to_string(),
+            password: vec![1; 32],
+        };
+        client.send(Envelope::new(2, login)).await.unwrap();
+        assert!(matches!(next(client).await.message, Message::Ok(..)));
+        assert!(matches!(next(client).await.message, Message::ClientData(_)));
+    }
+
+    /// Blocks the state until the returned sender is used or dropped
+    fn block(state: &StateHandle) -> std::sync::mpsc::Sender<()> {
+        let (release, blocked) = std::sync::mpsc::channel::<()>();
+        let state = state.clone();
+        tokio::spawn(async move { state.call(move |_| blocked.recv()).await });
+        release
+    }
+
+    #[tokio::test]
+    async fn old_clients_are_refused_in_their_layout() {
+        use common::connection_protocol::{ConnectionWriter, HANDSHAKE_CONTAINER, HELLO, LOGIN};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (state, shutdown) = server();
+        // a version 1 client and one from before the handshake, neither knows request ids
+        let hello = ConnectionWriter::new(HELLO)
+            .write_uint(1)
+            .write_string("old")
+            .write_list(&["login"], |writer, cap| {
+                writer.write_string(cap);
+            })
+            .finalize();
+        let login = ConnectionWriter::new(LOGIN)
+            .write_string("alice")
+            .write_binary(&[1; 32])
+            .finalize();
+        for (head, body) in [(5, hello), (0, login)] {
+            let frame = ConnectionWriter::new(HANDSHAKE_CONTAINER)
+                .write_uint(head)
+                .write_binary(&body)
+                .finalize();
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let mut client = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (socket, addr) = listener.accept().await.unwrap();
+            let session = Session::new(
+                Framed::new(socket, MessageCodec::new()),
+                state.clone(),
+                addr,
+                shutdown.subscribe(),
+            );
+            tokio::spawn(session.run());
+
+            client.write_all(&frame).await.unwrap();
+            let mut answer = Vec::new();
+            tokio::time::timeout(Duration::from_secs(10), client.read_to_end(&mut answer))
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(matches!(
+                Message::from_handshake_bytes(&answer),
+                Ok(Message::Error(ErrorCode::ProtocolMismatch, _))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_messages_are_answered_and_the_connection_stays() {
+        use common::connection_protocol::{ConnectionWriter, CONTAINER};
+        use tokio::io::AsyncWriteExt;
+
+        let (state, shutdown) = server();
+        let mut client = connect(&state, &shutdown).await;
+        login(&mut client, "alice").await;
+
+        let unknown = ConnectionWriter::new(CONTAINER)
+            .write_uint(999)
+            .write_uint(3)
+            .write_binary(&[1, 2, 3])
+            .finalize();
+        client.get_mut().write_all(&unknown).await.unwrap();
+        client
+            .send(Envelope::new(4, Message::GetClientData))
+            .await
+            .unwrap();
+
+        let answer = next(&mut client).await;
+        assert_eq!(answer.id, 3);
+        assert!(matches!(
+            answer.message,
+            Message::Error(ErrorCode::InvalidMessage, Some(_))
+        ));
+        let answer = next(&mut client).await;
+        assert_eq!(answer.id, 4);
+        assert!(matches!(answer.message, Message::ClientData(_)));
+    }
+
+    #[tokio::test]
+    async fn requests_on_one_connection_are_answered_in_order() {
+        let (state, shutdown) = server();
+        let mut client = connect(&state, &shutdown).await;
+        login(&mut client, "alice").await;
+
+        for (id, message) in [
+            (3, Message::GetClientData),
+            (4, Message::GetClientData),
+            (5, Message::Logout),
+            (6, Message::GetClientData),
+        ] {
+            client.send(Envelope::new(id, message)).await.unwrap();
+        }
+        for id in [3, 4] {
+            let answer = next(&mut client).await;
+            assert_eq!(answer.id, id);
+            assert!(matches!(answer.message, Message::ClientData(_)));
+        }
+        let answer = next(&mut client).await;
+        assert_eq!(answer.id, 5);
+        assert!(matches!(answer.message, Message::Ok(..)));
+        let answer = next(&mut client).await;
+        assert_eq!(answer.id, 6);
+        assert!(matches!(
+            answer.message,
+            Message::Error(ErrorCode::NotLoggedIn, _)
+        ));
+    }
+
+    #[tokio::test]
+    async fn unexpected_messages_are_refused_and_the_connection_stays() {
+        let (state, shutdown) = server();
+        let mut client = connect(&state, &shutdown).await;
+
+        client
+            .send(Envelope::new(3, Message::GetClientData))
+            .await
+            .unwrap();
+        let answer = next(&mut client).await;
+        assert_eq!(answer.id, 3);
+        assert!(matches!(
+            answer.message,
+            Message::Error(ErrorCode::NotLoggedIn, _)
+        ));
+
+        // a second `Hello` after the handshake
+        let hello = Message::Hello {
+            protocol_version: connection_protocol::PROTOCOL_VERSION,
+            client_name: "test".to_string(),
+            capabilities: Vec::new(),
+        };
+        client.send(Envelope::new(4, hello)).await.unwrap();
+        let answer = next(&mut client).await;
+        assert_eq!(answer.id, 4);
+        assert!(matches!(
+            answer.message,
+            Message::Error(ErrorCode::InvalidMessage, _)
+        ));
+
+        login(&mut client, "alice").await;
+        let login = Message::Login {
+            username: "bob".to_string(),
+            password: vec![1; 32],
+        };
+        client.send(Envelope::new(5, login)).await.unwrap();
+        let answer = next(&mut client).await;
+        assert_eq!(answer.id, 5);
+        assert!(matches!(
+            answer.message,
+            Message::Error(ErrorCode::InvalidMessage, _)
+        ));
+        client
+            .send(Envelope::new(6, Message::GetClientData))
+            .await
+            .unwrap();
+        let answer = next(&mut client).await;
+        assert_eq!(answer.id, 6);
+        assert!(matches!(answer.message, Message::ClientData(_)));
+    }
+
+    #[tokio::test]
+    async fn clients_leaving_mid_request_are_set_offline() {
+        let (state, shutdown) = server();
+        let mut client = connect(&state, &shutdown).await;
+        login(&mut client, "alice").await;
+        let alice = state
+            .call(|state| state.users.get_id("alice"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            state.call(move |state| state.status(alice)).await.unwrap(),
+            PlayerStatus::Online
+        );
+
+        let release = block(&state);
+        client
+            .send(Envelope::new(3, Message::GetClientData))
+            .await
+            .unwrap();
+        drop(client);
+        release.send(()).unwrap();
+
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while state.call(move |state| state.status(alice)).await.unwrap()
+                != PlayerStatus::Offline
+            {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("The session did not end");
+    }
+
+    #[tokio::test]
+    async fn requests_in_flight_are_answered_on_shutdown() {
+        let (state, shutdown) = server();
+        let mut client = connect(&state, &shutdown).await;
+        login(&mut client, "alice").await;
+
+        let release = block(&state);
+        client
+            .send(Envelope::new(3, Message::GetClientData))
+            .await
+            .unwrap();
+        // the session waits for the state with the request read
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        shutdown.send(true).unwrap();
+        drop(release);
+
+        let answer = next(&mut client).await;
+        assert_eq!(answer.id, 3);
+        assert!(matches!(answer.message, Message::ClientData(_)));
+        assert_eq!(
+            next(&mut client).await.message,
+            Message::Error(ErrorCode::ShuttingDown, None)
+        );
+    }
+
+    #[tokio::test]
+    async fn pushes_are_not_kept_across_logout() {
+        let (state, shutdown) = server();
+        let mut client = connect(&state, &shutdown).await;
+        login(&mut client, "alice").await;
+        let alice = state
+            .call(|state| state.users.get_id("alice"))
+            .await
+            .unwrap()
+            .unwrap();
+
+        // a push for alice that reaches the session while it is logging out
+        let release = block(&state);
+        client
+            .send(Envelope::new(3, Message::Logout))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let pusher = state.clone();
+        let pushed = tokio::spawn(async move {
+            let request = Message::FriendRequest {
+                username: "carol".to_string(),
+            };
+            pusher.call(move |state| state.push(alice, &request)).await
+        });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        drop(release);
+        pushed.await.unwrap().unwrap();
+        let answer = next(&mut client).await;
+        assert_eq!(answer.id, 3);
+        assert!(matches!(answer.message, Message::Ok(..)));
+
+        login(&mut client, "bob").await;
+        client
+            .send(Envelope::new(4, Message::GetClientData))
+            .await
+            .unwrap();
+        let answer = next(&mut client).await;
+        assert_eq!(answer.id, 4);
+        assert!(matches!(answer.message, Message::ClientData(_)));
+    }
+}
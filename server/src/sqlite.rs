@@ -0,0 +1,334 @@
+//! The `Storage::Sqlite` backend, for databases too big to keep in memory
+//!
+//! Every change is its own transaction, so `flush` has nothing left to do.
+use std::borrow::Cow;
+use std::path::Path;
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::db::UsersInfo;
+use crate::store::UserStore;
+
+/// Layout of the tables, kept in `PRAGMA user_version`
+const SCHEMA_VERSION: u32 = 2;
+
+/// The tables of version 1, `UPGRADES` brings them up to date
+const SCHEMA: &str = "
+    CREATE TABLE users (
+        player_id INTEGER PRIMARY KEY,
+        username TEXT NOT NULL UNIQUE,
+        -- lowercase username, names have to be unique in any letter case
+        username_key TEXT NOT NULL UNIQUE,
+        password BLOB NOT NULL,
+        quote TEXT NOT NULL DEFAULT '',
+        funds INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE TABLE friends (
+        player_id INTEGER NOT NULL REFERENCES users,
+        friend_id INTEGER NOT NULL REFERENCES users,
+        PRIMARY KEY (player_id, friend_id)
+    );
+    CREATE TABLE cards (
+        player_id INTEGER NOT NULL REFERENCES users,
+        card_id INTEGER NOT NULL,
+        count INTEGER NOT NULL,
+        PRIMARY KEY (player_id, card_id)
+    );
+";
+
+/// `UPGRADES[n]` turns version `n + 1` into `n + 2`
+const UPGRADES: &[&str] = &["
+    CREATE TABLE friend_requests (
+        from_id INTEGER NOT NULL REFERENCES users,
+        to_id INTEGER NOT NULL REFERENCES users,
+        PRIMARY KEY (from_id, to_id)
+    );
+"];
+
+pub struct SqliteStore {
+    connection: Connection,
+}
+
+/// Errors are logged where they happen, callers only see that the change did not happen
+fn logged<T>(result: rusqlite::Result<T>) -> Option<T> {
+    result
+        .map_err(|e| tracing::error!("SQLite error: {e}"))
+        .ok()
+}
+
+impl SqliteStore {
+    pub fn open(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let connection = Connection::open(path)?;
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        // NORMAL would not sync the WAL on commit, a power loss could undo the last changes
+        connection.pragma_update(None, "synchronous", "FULL")?;
+        Self::init(connection)
+    }
+
+    #[cfg(test)]
+    pub fn open_in_memory() -> Result<Self, Box<dyn std::error::Error>> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(connection: Connection) -> Result<Self, Box<dyn std::error::Error>> {
+        connection.pragma_update(None, "foreign_keys", true)?;
+        let version: u32 = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        if version > SCHEMA_VERSION {
+            return Err(format!(
+                "unknown schema version {version}, this build reads up to {SCHEMA_VERSION}"
+            )
+            .into());
+        }
+        if version < SCHEMA_VERSION {
+            if version > 0 {
+                tracing::info!("Upgrading the SQLite schema from version {version} to {SCHEMA_VERSION}");
+            }
+            let mut batch = String::from("BEGIN;");
+            if version == 0 {
+                batch.push_str(SCHEMA);
+            }
+            for upgrade in &UPGRADES[version.max(1) as usize - 1..] {
+                batch.push_str(upgrade);
+            }
+            batch.push_str(&format!("PRAGMA user_version = {SCHEMA_VERSION}; COMMIT;"));
+            connection.execute_batch(&batch)?;
+        }
+        Ok(Self { connection })
+    }
+
+    fn exists(&self, id: u64) -> bool {
+        let found = self
+            .connection
+            .query_row(
+                "SELECT 1 FROM users WHERE player_id = ?1",
+                [id as i64],
+                |_| Ok(()),
+            )
+            .optional();
+        matches!(logged(found), Some(Some(())))
+    }
+
+    /// Run a statement that changes the user `id`, false if there is no such user
+    fn update(&self, id: u64, sql: &str, params: impl rusqlite::Params) -> bool {
+        self.exists(id) && logged(self.connection.execute(sql, params)).is_some()
+    }
+
+    /// Run statements about two users in one transaction, `?1` is `a` and `?2` is `b`
+    ///
+    /// False if one of them is not a user
+    fn update_pair(&mut self, a: u64, b: u64, statements: &[&str]) -> bool {
+        if !self.exists(a) || !self.exists(b) {
+            return false;
+        }
+        let run = |connection: &mut Connection| {
+            let transaction = connection.transaction()?;
+            for sql in statements {
+                transaction.execute(sql, params![a as i64, b as i64])?;
+            }
+            transaction.commit()
+        };
+        logged(run(&mut self.connection)).is_some()
+    }
+
+    /// The player ids `sql` selects for the user `id`, in the order they were added
+    fn ids(&self, sql: &str, id: u64) -> Option<Vec<u64>> {
+        let ids = self.connection.prepare_cached(sql).and_then(|mut statement| {
+            statement
+                .query_map([id as i64], |row| row.get::<_, i64>(0))?
+                .map(|id| id.map(|id| id as u64))
+                .collect()
+        });
+        logged(ids)
+    }
+}
+
+impl UserStore for SqliteStore {
+    fn create(&mut self, username: String, password: Vec<u8>) -> Option<u64> {
+        let inserted = self.connection.execute(
+            "INSERT INTO users (username, username_key, password) VALUES (?1, ?2, ?3)
+             ON CONFLICT DO NOTHING",
+            params![username, username.to_lowercase(), password],
+        );
+        match logged(inserted)? {
+            0 => None,
+            _ => Some(self.connection.last_insert_rowid() as u64),
+        }
+    }
+
+    fn get(&self, id: u64) -> Option<Cow<'_, UsersInfo>> {
+        let user = self
+            .connection
+            .query_row(
+                "SELECT username, password, quote, funds FROM users WHERE player_id = ?1",
+                [id as i64],
+                |row| {
+                    let mut user = UsersInfo::new(row.get(0)?, row.get(1)?, id);
+                    user.quote = row.get(2)?;
+                    user.funds = row.get::<_, i64>(3)? as u64;
+                    Ok(user)
+                },
+            )
+            .optional();
+        let mut user = logged(user)??;
+
+        user.friends = self.ids(
+            "SELECT friend_id FROM friends WHERE player_id = ?1 ORDER BY rowid",
+            id,
+        )?;
+        user.friend_requests = self.ids(
+            "SELECT from_id FROM friend_requests WHERE to_id = ?1 ORDER BY rowid",
+            id,
+        )?;
+        user.sent_requests = self.ids(
+            "SELECT to_id FROM friend_requests WHERE from_id = ?1 ORDER BY rowid",
+            id,
+        )?;
+
+        let cards = self
+            .connection
+            .prepare_cached("SELECT card_id, count FROM cards WHERE player_id = ?1 ORDER BY rowid")
+            .and_then(|mut statement| {
+                statement
+                    .query_map([id as i64], |row| {
+                        Ok((row.get::<_, i64>(0)? as u64, row.get(1)?))
+                    })?
+                    .collect()
+            });
+        user.card_collection = logged(cards)?;
+        Some(Cow::Owned(user))
+    }
+
+    fn get_id(&self, username: &str) -> Option<u64> {
+        let id = self
+            .connection
+            .query_row(
+                "SELECT player_id FROM users WHERE username = ?1",
+                [username],
+                |row| row.get::<_, i64>(0),
+            )
+            .optional();
+        logged(id)?.map(|id| id as u64)
+    }
+
+    fn count(&self) -> usize {
+        let count = self
+            .connection
+            .query_row("SELECT COUNT(*) FROM users", [], |row| row.get::<_, i64>(0));
+        logged(count).unwrap_or(0) as usize
+    }
+
+    fn set_password_record(&mut self, id: u64, password: Vec<u8>) -> bool {
+        self.update(
+            id,
+            "UPDATE users SET password = ?2 WHERE player_id = ?1",
+            params![id as i64, password],
+        )
+    }
+
+    fn set_funds(&mut self, id: u64, funds: u64) -> bool {
+        self.update(
+            id,
+            "UPDATE users SET funds = ?2 WHERE player_id = ?1",
+            params![id as i64, funds as i64],
+        )
+    }
+
+    fn set_quote(&mut self, id: u64, quote: String) -> bool {
+        self.update(
+            id,
+            "UPDATE users SET quote = ?2 WHERE player_id = ?1",
+            params![id as i64, quote],
+        )
+    }
+
+    fn add_friend(&mut self, id: u64, friend_id: u64) -> bool {
+        id != friend_id
+            && self.exists(friend_id)
+            && self.update(
+                id,
+                "INSERT INTO friends (player_id, friend_id) VALUES (?1, ?2) ON CONFLICT DO NOTHING",
+                params![id as i64, friend_id as i64],
+            )
+    }
+
+    fn befriend(&mut self, id: u64, friend_id: u64) -> bool {
+        id != friend_id
+            && self.update_pair(
+                id,
+                friend_id,
+                &[
+                    "INSERT INTO friends (player_id, friend_id) VALUES (?1, ?2) ON CONFLICT DO NOTHING",
+                    "INSERT INTO friends (player_id, friend_id) VALUES (?2, ?1) ON CONFLICT DO NOTHING",
+                    "DELETE FROM friend_requests WHERE from_id IN (?1, ?2) AND to_id IN (?1, ?2)",
+                ],
+            )
+    }
+
+    fn unfriend(&mut self, id: u64, friend_id: u64) -> bool {
+        self.update_pair(
+            id,
+            friend_id,
+            &["DELETE FROM friends WHERE player_id IN (?1, ?2) AND friend_id IN (?1, ?2)"],
+        )
+    }
+
+    fn add_friend_request(&mut self, from: u64, to: u64) -> bool {
+        from != to
+            && self.update_pair(
+                from,
+                to,
+                &["INSERT INTO friend_requests (from_id, to_id) VALUES (?1, ?2) ON CONFLICT DO NOTHING"],
+            )
+    }
+
+    fn remove_friend_request(&mut self, from: u64, to: u64) -> bool {
+        self.update_pair(
+            from,
+            to,
+            &["DELETE FROM friend_requests WHERE from_id = ?1 AND to_id = ?2"],
+        )
+    }
+
+    fn set_card_count(&mut self, id: u64, card_id: u64, count: u8) -> bool {
+        if count == 0 {
+            return self.update(
+                id,
+                "DELETE FROM cards WHERE player_id = ?1 AND card_id = ?2",
+                params![id as i64, card_id as i64],
+            );
+        }
+        self.update(
+            id,
+            "INSERT INTO cards (player_id, card_id, count) VALUES (?1, ?2, ?3)
+             ON CONFLICT DO UPDATE SET count = excluded.count",
+            params![id as i64, card_id as i64, count],
+        )
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn version_1_is_upgraded() {
+        let connection = Connection::open_in_memory().unwrap();
+        connection.execute_batch(SCHEMA).unwrap();
+        connection.pragma_update(None, "user_version", 1).unwrap();
+        let mut store = SqliteStore::init(connection).unwrap();
+        let version: u32 = store
+            .connection
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap();
+        let from = store.create("from".to_string(), vec![1; 32]).unwrap();
+        let to = store.create("to".to_string(), vec![1; 32]).unwrap();
+
+        assert_eq!(version, SCHEMA_VERSION);
+        assert!(store.add_friend_request(from, to));
+        assert_eq!(store.get(to).unwrap().friend_requests, vec![from]);
+    }
+}
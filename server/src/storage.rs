@@ -0,0 +1,228 @@
+//! Database files on disk
+//!
+//! A save never touches the file in place: the bytes are written and fsynced to `<file>.tmp`,
+//! the current file is kept as `<file>.1` (older copies move up to `<file>.N`) and the temporary
+//! file is renamed over it. Loading falls back to the newest backup that can be parsed, the
+//! copies that could not be parsed are moved aside to `<copy>.corrupt` so later saves don't
+//! rotate them into the backups in place of good ones.
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// A database file and its backups
+#[derive(Debug, Clone)]
+pub struct DbFile {
+    path: PathBuf,
+    /// Older versions kept next to the file
+    backups: usize,
+}
+
+impl DbFile {
+    pub fn new(path: impl Into<PathBuf>, backups: usize) -> Self {
+        Self {
+            path: path.into(),
+            backups,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// `<file>.n`, the higher `n` the older
+    pub fn backup_path(&self, n: usize) -> PathBuf {
+        with_suffix(&self.path, &n.to_string())
+    }
+
+    /// Replace the file with `bytes`, a crash at any point leaves a complete file behind
+    pub fn write(&self, bytes: &[u8]) -> io::Result<()> {
+        let tmp = with_suffix(&self.path, "tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(bytes)?;
+        file.sync_all()?;
+        drop(file);
+
+        if self.backups > 0 && self.path.exists() {
+            for n in (1..self.backups).rev() {
+                let from = self.backup_path(n);
+                if from.exists() {
+                    std::fs::rename(&from, self.backup_path(n + 1))?;
+                }
+            }
+            let backup = self.backup_path(1);
+            std::fs::copy(&self.path, &backup)?;
+            // the backup has to be on disk before the file it copies is replaced
+            File::options().write(true).open(&backup)?.sync_all()?;
+            sync_dir(&backup);
+        }
+        std::fs::rename(&tmp, &self.path)?;
+        sync_dir(&self.path);
+        Ok(())
+    }
+
+    /// Read and parse the file, or the newest backup that parses if it is missing or corrupt
+    ///
+    /// `Ok(None)` if nothing was saved yet. Nothing is moved aside if no copy parses.
+    pub fn read<T, E: std::fmt::Debug>(
+        &self,
+        parse: impl Fn(&[u8]) -> Result<T, E>,
+    ) -> Result<Option<T>, String> {
+        let candidates = std::iter::once(self.path.clone())
+            .chain((1..=self.backups).map(|n| self.backup_path(n)));
+        let mut found = false;
+        let mut corrupt: Vec<PathBuf> = Vec::new();
+        for path in candidates {
+            let contents = match std::fs::read(&path) {
+                Ok(contents) => contents,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    tracing::error!("Failed to read {}: {e}", path.display());
+                    found = true;
+                    continue;
+                }
+            };
+            found = true;
+            match parse(&contents) {
+                Ok(value) => {
+                    if path != self.path {
+                        tracing::warn!("Loaded the backup {}", path.display());
+                    }
+                    for path in corrupt {
+                        move_aside(&path);
+                    }
+                    return Ok(Some(value));
+                }
+                Err(e) => {
+                    tracing::error!("Failed to parse {}: {e:?}", path.display());
+                    corrupt.push(path);
+                }
+            }
+        }
+        if found {
+            Err(format!("No readable copy of {}", self.path.display()))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// Renames a copy that does not parse to `<copy>.corrupt`, replacing an older one
+fn move_aside(path: &Path) {
+    let aside = with_suffix(path, "corrupt");
+    match std::fs::rename(path, &aside) {
+        Ok(()) => tracing::warn!("Moved {} to {}", path.display(), aside.display()),
+        Err(e) => tracing::error!("Failed to move {} aside: {e}", path.display()),
+    }
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".");
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// Makes renames and new files in the directory of `path` survive a power loss
+#[cfg(unix)]
+fn sync_dir(path: &Path) {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    if let Err(e) = File::open(dir).and_then(|dir| dir.sync_all()) {
+        tracing::warn!("Failed to sync {}: {e}", dir.display());
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(bytes: &[u8]) -> Result<String, ()> {
+        match bytes {
+            [b'!', ..] => Err(()),
+            _ => Ok(String::from_utf8_lossy(bytes).into_owned()),
+        }
+    }
+
+    #[test]
+    fn backups_rotate_and_load() {
+        let dir = std::env::temp_dir().join(format!("verynoha-storage-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = DbFile::new(dir.join("users.txt"), 2);
+
+        let empty = file.read(parse);
+        for version in ["one", "two", "three", "four"] {
+            file.write(version.as_bytes()).unwrap();
+        }
+        let newest = file.read(parse);
+        let backups = [
+            file.backup_path(1),
+            file.backup_path(2),
+            file.backup_path(3),
+        ]
+        .map(|path| std::fs::read_to_string(path).ok());
+        let files = std::fs::read_dir(&dir).unwrap().count();
+
+        // a corrupt file falls back to the newest good backup
+        std::fs::write(file.path(), "!broken").unwrap();
+        let fallback = file.read(parse);
+        std::fs::write(file.backup_path(1), "!broken").unwrap();
+        std::fs::write(file.backup_path(2), "!broken").unwrap();
+        let nothing = file.read(parse);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(empty, Ok(None));
+        assert_eq!(newest, Ok(Some("four".to_string())));
+        assert_eq!(
+            backups,
+            [Some("three".to_string()), Some("two".to_string()), None]
+        );
+        // the file and two backups, no temporary file left behind
+        assert_eq!(files, 3);
+        assert_eq!(fallback, Ok(Some("three".to_string())));
+        assert!(nothing.is_err());
+    }
+
+    #[test]
+    fn corrupt_file_is_not_rotated_into_the_backups() {
+        let dir = std::env::temp_dir().join(format!("verynoha-corrupt-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = DbFile::new(dir.join("users.txt"), 2);
+        for version in ["one", "two", "three"] {
+            file.write(version.as_bytes()).unwrap();
+        }
+        std::fs::write(file.path(), "!broken").unwrap();
+        let loaded = file.read(parse);
+
+        // every save after loading the backup leaves only copies that parse
+        let mut copies = Vec::new();
+        for version in ["four", "five", "six"] {
+            file.write(version.as_bytes()).unwrap();
+            let chain = [
+                file.path().to_path_buf(),
+                file.backup_path(1),
+                file.backup_path(2),
+            ]
+            .map(|path| std::fs::read(path).ok().map(|bytes| parse(&bytes)));
+            copies.push(chain);
+        }
+        let aside = std::fs::read_to_string(dir.join("users.txt.corrupt"));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(loaded, Ok(Some("two".to_string())));
+        let ok = |version: &str| Some(Ok(version.to_string()));
+        assert_eq!(
+            copies,
+            [
+                [ok("four"), ok("two"), ok("one")],
+                [ok("five"), ok("four"), ok("two")],
+                [ok("six"), ok("five"), ok("four")],
+            ]
+        );
+        assert_eq!(aside.unwrap(), "!broken");
+    }
+}
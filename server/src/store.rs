@@ -0,0 +1,165 @@
+//! Where the accounts live, see `config::Storage` for the available backends
+use std::borrow::Cow;
+use std::io;
+
+use crate::db::UsersInfo;
+use crate::password;
+
+/// Persistent data of the accounts
+///
+/// Passwords are stored as records of `password`, the provided methods take the hash sent by the
+/// client. Methods that change a user return false if the user doesn't exist.
+pub trait UserStore: Send {
+    /// Adds a user and returns the new id, `None` if the name is taken in any letter case
+    fn create(&mut self, username: String, password: Vec<u8>) -> Option<u64>;
+
+    /// Borrowed from backends that keep the users in memory
+    fn get(&self, id: u64) -> Option<Cow<'_, UsersInfo>>;
+
+    /// Id of the user with exactly this name
+    fn get_id(&self, username: &str) -> Option<u64>;
+
+    /// Number of users
+    fn count(&self) -> usize;
+
+    fn set_password_record(&mut self, id: u64, password: Vec<u8>) -> bool;
+
+    fn set_funds(&mut self, id: u64, funds: u64) -> bool;
+
+    /// The quote is not checked, see `policy::QuotePolicy`
+    fn set_quote(&mut self, id: u64, quote: String) -> bool;
+
+    /// Adds `friend_id` to the friends of `id`, only in this direction
+    fn add_friend(&mut self, id: u64, friend_id: u64) -> bool;
+
+    /// Makes the two users friends in both directions and drops the requests between them
+    fn befriend(&mut self, id: u64, friend_id: u64) -> bool;
+
+    /// Ends the friendship in both directions
+    fn unfriend(&mut self, id: u64, friend_id: u64) -> bool;
+
+    /// Stores a friend request with the sender and the receiver
+    fn add_friend_request(&mut self, from: u64, to: u64) -> bool;
+
+    fn remove_friend_request(&mut self, from: u64, to: u64) -> bool;
+
+    /// Sets how often `id` owns `card_id`, 0 removes the card
+    fn set_card_count(&mut self, id: u64, card_id: u64, count: u8) -> bool;
+
+    /// Make the changes survive a crash
+    fn flush(&mut self) -> io::Result<()>;
+
+    /// The last save before the server exits
+    fn close(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+
+    /// Adds a new user, `password` is the hash sent by the client
+    fn add_login(&mut self, name: String, password: Vec<u8>) -> bool {
+        self.create(name, password::hash(&password)).is_some()
+    }
+
+    /// Checks the login, records still in an old format are rehashed on success
+    fn validate(&mut self, username: &str, password: &[u8]) -> Option<u64> {
+        let user = self.get(self.get_id(username)?)?;
+        if !password::verify(&user.password, password) {
+            return None;
+        }
+        let (id, upgrade) = (user.player_id, password::needs_upgrade(&user.password));
+        if upgrade {
+            tracing::info!("Upgrading the password hash of {username}");
+            self.set_password(id, password);
+        }
+        Some(id)
+    }
+
+    fn get_username(&self, id: u64) -> Option<String> {
+        self.get(id).map(|user| user.username.clone())
+    }
+
+    /// Replaces the password, `password` is the hash sent by the client
+    fn set_password(&mut self, id: u64, password: &[u8]) -> bool {
+        self.set_password_record(id, password::hash(password))
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::db::Users;
+    use crate::sqlite::SqliteStore;
+
+    /// What every backend has to do the same way
+    pub fn check_store(store: &mut dyn UserStore) {
+        assert_eq!(store.count(), 0);
+        assert!(store.add_login("user".to_string(), vec![1; 32]));
+        assert!(store.add_login("friend".to_string(), vec![2; 32]));
+        assert!(!store.add_login("USER".to_string(), vec![3; 32]));
+        assert_eq!(store.count(), 2);
+
+        let id = store.get_id("user").unwrap();
+        let friend = store.get_id("friend").unwrap();
+        assert_ne!(id, friend);
+        assert_eq!(store.get_id("User"), None);
+        assert_eq!(store.get_username(friend).as_deref(), Some("friend"));
+        assert_eq!(store.validate("user", &[1; 32]), Some(id));
+        assert_eq!(store.validate("user", &[2; 32]), None);
+        assert_eq!(store.validate("nobody", &[1; 32]), None);
+
+        assert!(store.set_password(id, &[4; 32]));
+        assert_eq!(store.validate("user", &[4; 32]), Some(id));
+        assert!(store.set_funds(id, 300));
+        assert!(store.set_quote(id, "first".to_string()));
+        assert!(store.set_quote(id, "hello there".to_string()));
+        assert!(!store.set_quote(1000, "nobody".to_string()));
+        assert!(store.add_friend(id, friend));
+        assert!(store.add_friend(id, friend));
+        assert!(!store.add_friend(id, id));
+        assert!(!store.add_friend(id, 1000));
+        let other = store.create("other".to_string(), vec![5; 32]).unwrap();
+        assert!(store.add_friend_request(id, other));
+        assert!(store.add_friend_request(id, other));
+        assert!(store.add_friend_request(friend, other));
+        assert!(!store.add_friend_request(id, id));
+        assert!(!store.add_friend_request(id, 1000));
+        let requested = store.get(other).unwrap();
+        assert_eq!(requested.friend_requests, vec![id, friend]);
+        assert_eq!(store.get(id).unwrap().sent_requests, vec![other]);
+        assert!(store.remove_friend_request(friend, other));
+        assert!(store.get(friend).unwrap().sent_requests.is_empty());
+        assert!(store.befriend(other, id));
+        assert!(!store.befriend(other, other));
+        let befriended = store.get(other).unwrap();
+        assert_eq!(befriended.friends, vec![id]);
+        assert!(befriended.friend_requests.is_empty());
+        assert!(store.get(id).unwrap().sent_requests.is_empty());
+        assert!(store.unfriend(id, other));
+        assert!(store.get(other).unwrap().friends.is_empty());
+
+        assert!(store.set_card_count(id, 9, 2));
+        assert!(store.set_card_count(id, 4, 1));
+        assert!(store.set_card_count(id, 9, 5));
+        assert!(store.set_card_count(id, 4, 0));
+        assert!(!store.set_funds(1000, 1));
+
+        let user = store.get(id).unwrap();
+        assert_eq!(user.username, "user");
+        assert_eq!(user.funds, 300);
+        assert_eq!(user.quote, "hello there");
+        assert_eq!(user.friends, vec![friend]);
+        assert_eq!(user.card_collection, vec![(9, 5)]);
+        assert!(store.get(friend).unwrap().friends.is_empty());
+        assert!(store.get(1000).is_none());
+        store.flush().unwrap();
+    }
+
+    #[test]
+    fn memory_store() {
+        check_store(&mut Users::new());
+    }
+
+    #[test]
+    fn sqlite_store() {
+        check_store(&mut SqliteStore::open_in_memory().unwrap());
+    }
+}
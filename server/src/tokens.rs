@@ -0,0 +1,175 @@
+//! Session tokens handed out on login
+//!
+//! Only a SHA-256 of every token is stored, so a leaked `tokens.txt` can't be used to log in.
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::storage::DbFile;
+use common::connection_protocol::{Chunked, ConnectionReader, MessageError};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+pub const TOKEN_SIZE: usize = 32;
+
+/// How long a token can be used to resume a session
+pub const TOKEN_LIFETIME: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// A token as stored in the database
+#[derive(Clone, Debug, Chunked)]
+pub struct StoredToken {
+    #[chunk(binary)]
+    pub hash: Vec<u8>,
+    pub player_id: u64,
+    /// Unix time in seconds
+    pub expires: u64,
+}
+
+pub struct Tokens {
+    pub tokens: Vec<StoredToken>,
+    /// Where changes are saved, `None` keeps them in memory
+    file: Option<DbFile>,
+    /// Changed since the last save
+    dirty: bool,
+}
+
+/// Current unix time in seconds
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0)
+}
+
+fn hash(token: &[u8]) -> Vec<u8> {
+    Sha256::digest(token).to_vec()
+}
+
+impl Default for Tokens {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Tokens {
+    pub fn new() -> Self {
+        Self {
+            tokens: Vec::new(),
+            file: None,
+            dirty: false,
+        }
+    }
+
+    /// Create a new token for `player_id`, the token itself is only returned here
+    pub fn issue(&mut self, player_id: u64, now: u64) -> Vec<u8> {
+        let mut token = vec![0; TOKEN_SIZE];
+        rand::thread_rng().fill_bytes(&mut token);
+        self.tokens.retain(|stored| stored.expires > now);
+        self.tokens.push(StoredToken {
+            hash: hash(&token),
+            player_id,
+            expires: now + TOKEN_LIFETIME.as_secs(),
+        });
+        token
+    }
+
+    /// The player the token belongs to, if it is still valid
+    pub fn resume(&self, token: &[u8], now: u64) -> Option<u64> {
+        let hash = hash(token);
+        self.tokens
+            .iter()
+            .find(|stored| stored.hash == hash && stored.expires > now)
+            .map(|stored| stored.player_id)
+    }
+
+    /// Invalidate every token of `player_id`
+    pub fn revoke_all(&mut self, player_id: u64) {
+        self.tokens.retain(|stored| stored.player_id != player_id);
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, MessageError> {
+        let mut tokens = Vec::new();
+        let mut reader = ConnectionReader::new(StoredToken::SCHEMA, bytes);
+        while reader.current_byte < bytes.len() {
+            reader.current_chunk = 0;
+            tokens.push(StoredToken::read_chunks(&mut reader)?);
+            reader.try_finalize()?;
+        }
+        Ok(Self {
+            tokens,
+            file: None,
+            dirty: false,
+        })
+    }
+
+    /// Loads the tokens, a missing file means nobody is logged in
+    pub fn load_db(file: DbFile) -> Self {
+        let mut tokens = match file.read(Self::from_bytes) {
+            Ok(Some(tokens)) => tokens,
+            Ok(None) => Self::new(),
+            Err(e) => {
+                tracing::warn!("{e}, all sessions are lost");
+                Self::new()
+            }
+        };
+        tokens.file = Some(file);
+        tokens
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        for token in &self.tokens {
+            buffer.extend(token.to_chunks());
+        }
+        buffer
+    }
+
+    pub fn save_db(&self) -> std::io::Result<()> {
+        match &self.file {
+            Some(file) => file.write(&self.to_bytes()),
+            None => Ok(()),
+        }
+    }
+
+    /// Changes are saved by the next `flush` instead of right away
+    pub fn mark_dirty(&mut self) {
+        self.dirty = true;
+    }
+
+    /// Save if anything changed since the last save
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        if self.dirty {
+            self.save_db()?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn issue_resume_revoke() {
+        let mut tokens = Tokens::new();
+        let first = tokens.issue(1, 100);
+        let second = tokens.issue(1, 100);
+        let other = tokens.issue(2, 100);
+        assert_ne!(first, second);
+        assert_eq!(tokens.resume(&first, 101), Some(1));
+        assert_eq!(tokens.resume(&other, 101), Some(2));
+        assert_eq!(tokens.resume(&[0; TOKEN_SIZE], 101), None);
+        // the stored hash is not a token
+        assert_eq!(tokens.resume(&tokens.tokens[0].hash.clone(), 101), None);
+
+        let expiry = 100 + TOKEN_LIFETIME.as_secs();
+        assert_eq!(tokens.resume(&first, expiry), None);
+
+        tokens.revoke_all(1);
+        assert_eq!(tokens.resume(&first, 101), None);
+        assert_eq!(tokens.resume(&second, 101), None);
+        assert_eq!(tokens.resume(&other, 101), Some(2));
+
+        let read = Tokens::from_bytes(&tokens.to_bytes()).unwrap();
+        assert_eq!(read.resume(&other, 101), Some(2));
+    }
+}
@@ -1,18 +1,15 @@
-use tokio::net::TcpStream;
+use crate::requests::Requests;
 
 use termui::*;
 
-pub async fn start_client(stream: &mut TcpStream) -> Result<(), Box<dyn std::error::Error>> {
+pub async fn start_client(requests: &mut Requests) -> Result<(), Box<dyn std::error::Error>> {
     println!("Connected to server");
-    let data = match common::connection_protocol::Message::read_stream(stream).await {
-        Ok(data) => data,
-        Err(e) => {
-            println!("Failed to read response: {e:?}");
-            return Err("Failed to read response".into());
-        }
+    let data = match requests.next_push().await {
+        Some(data) => data,
+        None => return Err("Connection closed by server".into()),
     };
     println!("Received: {data:?}");
 
     wait();
     Ok(())
-}
\ No newline at end of file
+}
@@ -1,7 +1,11 @@
+use crate::options::Options;
+use crate::requests::Requests;
+use common::connection_protocol::{self, Connection, ErrorCode, Message, MessageCodec};
+use futures::{SinkExt, StreamExt};
 use std::net::SocketAddr;
 use termui::wait_clear;
-use tokio::io::AsyncWriteExt;
 use tokio::net::{TcpSocket, TcpStream};
+use tokio_util::codec::Framed;
 
 fn ask_for_login() -> Option<(String, String)> {
     loop {
@@ -20,7 +24,7 @@ fn ask_for_login() -> Option<(String, String)> {
     }
 }
 
-async fn make_conncetion() -> Result<TcpStream, Box<dyn std::error::Error>> {
+async fn make_conncetion() -> Result<Requests, Box<dyn std::error::Error>> {
     let addr = common::DEFAULT_SERVER_IP;
     let stream = match connect(Some(addr)).await {
         Ok(stream) => stream,
@@ -30,12 +34,9 @@ async fn make_conncetion() -> Result<TcpStream, Box<dyn std::error::Error>> {
             for _ in 0..3 {
                 println!("Retrying in 3 seconds...");
                 tokio::time::sleep(std::time::Duration::from_secs(3)).await;
-                match connect(Some(addr)).await {
-                    Ok(stream) => {
-                        tcp_stream = Some(stream);
-                        break;
-                    }
-                    Err(_) => (),
+                if let Ok(stream) = connect(Some(addr)).await {
+                    tcp_stream = Some(stream);
+                    break;
                 }
             }
             match tcp_stream {
@@ -44,10 +45,106 @@ async fn make_conncetion() -> Result<TcpStream, Box<dyn std::error::Error>> {
             }
         }
     };
-    Ok(stream)
+    let mut connection = Framed::new(stream, MessageCodec::new());
+    handshake(&mut connection).await?;
+    Ok(Requests::new(connection))
+}
+
+/// Name the client announces in its `Hello`
+const CLIENT_NAME: &str = concat!("verynoha/", env!("CARGO_PKG_VERSION"));
+
+/// Exchange `Hello` messages with the server, in the layout of `HANDSHAKE_CONTAINER`
+async fn handshake(connection: &mut Connection) -> Result<(), Box<dyn std::error::Error>> {
+    connection.codec_mut().handshake = true;
+    connection.send(connection_protocol::hello(CLIENT_NAME)).await?;
+    let response = match connection.next().await {
+        Some(Ok(Ok(envelope))) => envelope.message,
+        Some(Ok(Err(invalid))) => {
+            println!("Failed to read response: {:?}", invalid.error);
+            return Err("Failed to read response".into());
+        }
+        Some(Err(e)) => {
+            println!("Failed to read response: {e:?}");
+            return Err("Failed to read response".into());
+        }
+        None => return Err("Server closed the connection".into()),
+    };
+    connection.codec_mut().handshake = false;
+    match response {
+        Message::Hello { protocol_version, .. } if connection_protocol::is_compatible(protocol_version) => Ok(()),
+        Message::Hello { protocol_version, .. } => {
+            Err(format!("Server uses unsupported protocol version {protocol_version}").into())
+        }
+        Message::Error(code, detail) => {
+            Err(format!("Server refused the connection: {}", describe_error(&code, &detail)).into())
+        }
+        _ => Err("Unexpected handshake response".into()),
+    }
+}
+
+/// Send a request and wait for the response of the server
+async fn request(requests: &mut Requests, message: Message) -> Result<Message, Box<dyn std::error::Error>> {
+    match requests.request(message).await {
+        Ok(response) => Ok(response),
+        Err(e) => {
+            println!("Failed to read response: {e:?}");
+            Err("Failed to read response".into())
+        }
+    }
 }
 
-pub async fn login_to_server() -> Result<TcpStream, Box<dyn std::error::Error>> {
+/// Text shown to the user for an error from the server, the detail if there is one
+fn describe_error(code: &ErrorCode, detail: &Option<String>) -> String {
+    match detail {
+        Some(detail) => detail.clone(),
+        None => code.to_string(),
+    }
+}
+
+/// Log in with the token saved by an earlier login
+async fn resume(token: &[u8]) -> Result<Requests, Box<dyn std::error::Error>> {
+    let mut requests = make_conncetion().await?;
+
+    match request(&mut requests, Message::Resume { token: token.to_vec() }).await? {
+        Message::Ok(_, _) => Ok(requests),
+        Message::Error(code, detail) => Err(describe_error(&code, &detail).into()),
+        _ => Err("Unexpected response".into()),
+    }
+}
+
+/// Save the session token if the user wants to stay logged in
+fn remember_session(options: &mut Options, username: String, token: Option<Vec<u8>>) {
+    let Some(token) = token else {
+        return;
+    };
+    if !options.auto_login {
+        println!("Stay logged in on this computer?");
+        options.auto_login = termui::Confirm::yesno(Some(false));
+    }
+    if options.auto_login {
+        options.username = username;
+        options.token = token;
+        options.save();
+    }
+}
+
+pub async fn login_to_server() -> Result<Requests, Box<dyn std::error::Error>> {
+    let mut options = Options::load().unwrap_or_else(Options::default);
+    if options.auto_login && !options.token.is_empty() {
+        match resume(&options.token).await {
+            Ok(requests) => {
+                println!("Logged in as {}", options.username);
+                wait_clear();
+                return Ok(requests);
+            }
+            Err(e) => {
+                println!("Failed to resume the session: {e}");
+                options.token.clear();
+                options.save();
+            }
+        }
+    }
+
     let (username, password) = match ask_for_login() {
         Some((username, password)) => (username, password),
         None => return Err("Login cancelled".into()),
@@ -55,31 +152,32 @@ pub async fn login_to_server() -> Result<TcpStream, Box<dyn std::error::Error>>
     
     let hash = common::login::hash_password(&password);
     
-    let mut stream = make_conncetion().await?;
+    let mut requests = make_conncetion().await?;
 
-    let buffer = common::connection_protocol::Message::Login { username: username.clone(), password: hash }.to_bytes();
-    println!("sending buffer: {:?}", buffer.len());
-    stream.write(&buffer).await?;
-
-    let response = match common::connection_protocol::Message::read_stream(&mut stream).await {
-        Ok(response) => response,
-        Err(e) => {
-            println!("Failed to read response: {e:?}");
-            return Err("Failed to read response".into());
-        }
-    };
+    let response = request(&mut requests, Message::Login { username: username.clone(), password: hash.clone() }).await?;
 
     let res = match response {
-        common::connection_protocol::Message::Ok(_, _) => {
+        Message::Ok(_, token) => {
             println!("Login successful");
-            Ok(stream)
+            remember_session(&mut options, username, token);
+            Ok(requests)
         }
-        common::connection_protocol::Message::Error(_, Some(message)) => {
-            println!("Login failed: {}", String::from_utf8_lossy(&message));
-            Err("Login failed".into())
+        Message::Error(ErrorCode::UnknownUser, _) => {
+            println!("User {username} does not exist, do you want to register it?");
+            if termui::Confirm::yesno(Some(false)) {
+                match send_register(&mut requests, username.clone(), hash).await {
+                    Ok(token) => {
+                        remember_session(&mut options, username, token);
+                        Ok(requests)
+                    }
+                    Err(e) => Err(e),
+                }
+            } else {
+                Err("Login failed".into())
+            }
         }
-        common::connection_protocol::Message::Error(_, None) => {
-            println!("Login failed");
+        Message::Error(code, detail) => {
+            println!("Login failed: {}", describe_error(&code, &detail));
             Err("Login failed".into())
         }
         _ => {
@@ -90,47 +188,51 @@ pub async fn login_to_server() -> Result<TcpStream, Box<dyn std::error::Error>>
     res
 }
 
-pub async fn register() -> Result<TcpStream, Box<dyn std::error::Error>> {
+pub async fn register() -> Result<Requests, Box<dyn std::error::Error>> {
     let (username, password) = match ask_for_login() {
         Some((username, password)) => (username, password),
         None => return Err("Registration cancelled".into()),
     };
     
     let hash = common::login::hash_password(&password);
-    
-    let mut stream = make_conncetion().await?;
 
-    let buffer = common::connection_protocol::Message::Register { username: username.clone(), password: hash }.to_bytes();
-    
-    stream.write(&buffer).await?;
+    let mut requests = make_conncetion().await?;
 
-    let response = match common::connection_protocol::Message::read_stream(&mut stream).await {
-        Ok(response) => response,
-        Err(e) => {
-            println!("Failed to read response: {e:?}");
-            return Err("Failed to read response".into());
+    let res = match send_register(&mut requests, username.clone(), hash).await {
+        Ok(token) => {
+            let mut options = Options::load().unwrap_or_else(Options::default);
+            remember_session(&mut options, username, token);
+            Ok(requests)
         }
+        Err(e) => Err(e),
     };
+    wait_clear();
+    res
+}
 
-    let res = match response {
-        common::connection_protocol::Message::Ok(_, _) => {
-            println!("Registration successful");
-            Ok(stream)
-        }
-        common::connection_protocol::Message::Error(_, Some(message)) => {
-            println!("Registration failed: {}", String::from_utf8_lossy(&message));
-            Err("Registration failed".into())
-        }
-        common::connection_protocol::Message::Error(_, None) => {
-            println!("Registration failed");
-            Err("Registration failed".into())
+/// Register a new account and log into it on the same connection
+///
+/// Returns the session token of the login
+async fn send_register(requests: &mut Requests, username: String, hash: Vec<u8>) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+    let response = request(requests, Message::Register { username: username.clone(), password: hash.clone() }).await?;
+
+    match response {
+        Message::Ok(_, _) => println!("Registration successful"),
+        Message::Error(code, detail) => {
+            println!("Registration failed: {}", describe_error(&code, &detail));
+            return Err("Registration failed".into());
         }
-        _ => {
-            Err("Unexpected response".into())
+        _ => return Err("Unexpected response".into()),
+    }
+
+    match request(requests, Message::Login { username, password: hash }).await? {
+        Message::Ok(_, token) => Ok(token),
+        Message::Error(code, detail) => {
+            println!("Login failed: {}", describe_error(&code, &detail));
+            Err("Login failed".into())
         }
-    };
-    wait_clear();
-    res
+        _ => Err("Unexpected response".into()),
+    }
 }
 
 async fn connect(addr: Option<&str>) -> Result<TcpStream, Box<dyn std::error::Error>> {
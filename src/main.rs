@@ -1,14 +1,9 @@
-use std::io::{self, Write};
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::sync::{Arc, Mutex};
 use termui::*;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpSocket, TcpStream};
-use tokio::task;
 
 mod login;
 mod options;
 mod client;
+mod requests;
 
 #[tokio::main]
 async fn main() {
@@ -18,27 +13,27 @@ async fn main() {
         termui::clear_screen();
         match op {
             0 => {
-                let mut connection = match login::login_to_server().await {
-                    Ok(connection) => connection,
+                let mut requests = match login::login_to_server().await {
+                    Ok(requests) => requests,
                     Err(e) =>  {
                         println!("Failed to connect to server: {e}");
                         continue;
                     },
                 };
-                match client::start_client(&mut connection).await {
+                match client::start_client(&mut requests).await {
                     Ok(_) => (),
                     Err(e) => println!("Failed to start client: {e}"),
                 }
             },
             1 => {
-                let mut connection = match login::register().await {
-                    Ok(connection) => connection,
+                let mut requests = match login::register().await {
+                    Ok(requests) => requests,
                     Err(e) => {
                         println!("Failed to connect to server: {e}");
                         continue;
                     },
                 };
-                match client::start_client(&mut connection).await {
+                match client::start_client(&mut requests).await {
                     Ok(_) => (),
                     Err(e) => println!("Failed to start client: {e}"),
                 }
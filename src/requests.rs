@@ -0,0 +1,176 @@
+//! Multiplexes requests over one connection
+//!
+//! Every request gets its own id, the server echoes it in the response so several requests can
+//! be in flight at once. Messages without a request id (server pushes) are queued separately.
+use common::connection_protocol::{Connection, Envelope, ErrorCode, Message, MessageError, RequestId};
+use futures::stream::{SplitSink, SplitStream};
+use futures::{SinkExt, StreamExt};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+/// Requests waiting for their response, `None` once the connection is closed
+type Pending = Arc<Mutex<Option<HashMap<RequestId, oneshot::Sender<Message>>>>>;
+
+pub struct Requests {
+    sink: SplitSink<Connection, Envelope>,
+    pending: Pending,
+    next_id: RequestId,
+    pushes: mpsc::UnboundedReceiver<Message>,
+    reader: JoinHandle<()>,
+}
+
+/// Response of a request that was already sent
+pub struct PendingResponse {
+    response: oneshot::Receiver<Message>,
+}
+
+impl PendingResponse {
+    /// Wait for the response, fails if the connection closes first
+    pub async fn wait(self) -> Result<Message, MessageError> {
+        self.response
+            .await
+            .map_err(|_| MessageError::ConnectionClosed)
+    }
+}
+
+impl Requests {
+    /// Take over the connection, responses are read in a background task
+    pub fn new(connection: Connection) -> Requests {
+        let (sink, stream) = connection.split();
+        let pending = Arc::new(Mutex::new(Some(HashMap::new())));
+        let (push_sender, pushes) = mpsc::unbounded_channel();
+        let reader = tokio::spawn(read_responses(stream, Arc::clone(&pending), push_sender));
+        Requests {
+            sink,
+            pending,
+            // 0 is the id of pushes
+            next_id: 1,
+            pushes,
+            reader,
+        }
+    }
+
+    /// Send a request without waiting for the response
+    pub async fn send(&mut self, message: Message) -> Result<PendingResponse, MessageError> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let (sender, response) = oneshot::channel();
+        match self.pending.lock().unwrap().as_mut() {
+            Some(pending) => pending.insert(id, sender),
+            None => return Err(MessageError::ConnectionClosed),
+        };
+        if let Err(e) = self.sink.send(Envelope::new(id, message)).await {
+            if let Some(pending) = self.pending.lock().unwrap().as_mut() {
+                pending.remove(&id);
+            }
+            return Err(e);
+        }
+        Ok(PendingResponse { response })
+    }
+
+    /// Send a request and wait for its response
+    pub async fn request(&mut self, message: Message) -> Result<Message, MessageError> {
+        self.send(message).await?.wait().await
+    }
+
+    /// Wait for the next message the server sent on its own
+    ///
+    /// Returns `None` once the connection is closed
+    pub async fn next_push(&mut self) -> Option<Message> {
+        self.pushes.recv().await
+    }
+}
+
+impl Drop for Requests {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+/// Hands every response to the request waiting for it
+///
+/// When the connection closes the pending requests are dropped, which fails their `wait`
+async fn read_responses(
+    mut stream: SplitStream<Connection>,
+    pending: Pending,
+    pushes: mpsc::UnboundedSender<Message>,
+) {
+    while let Some(envelope) = stream.next().await {
+        let envelope = match envelope {
+            Ok(Ok(envelope)) => envelope,
+            // the request still gets an answer, the connection goes on
+            Ok(Err(invalid)) => {
+                println!("Failed to read response: {:?}", invalid.error);
+                let detail = Some(invalid.error.to_string());
+                Envelope::new(invalid.id, Message::Error(ErrorCode::InvalidMessage, detail))
+            }
+            Err(e) => {
+                println!("Failed to read response: {e:?}");
+                break;
+            }
+        };
+        if envelope.is_push() {
+            let _ = pushes.send(envelope.message);
+            continue;
+        }
+        let sender = pending
+            .lock()
+            .unwrap()
+            .as_mut()
+            .and_then(|pending| pending.remove(&envelope.id));
+        match sender {
+            Some(sender) => {
+                let _ = sender.send(envelope.message);
+            }
+            None => println!("Got a response to unknown request {}", envelope.id),
+        }
+    }
+    pending.lock().unwrap().take();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::connection_protocol::MessageCodec;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_util::codec::Framed;
+
+    #[tokio::test]
+    async fn responses_out_of_order() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut connection = Framed::new(socket, MessageCodec::new());
+            let first = connection.next().await.unwrap().unwrap().unwrap();
+            let second = connection.next().await.unwrap().unwrap().unwrap();
+            // answer the second request first with a push in between
+            connection
+                .send(Envelope::new(second.id, Message::Ok(2, None)))
+                .await
+                .unwrap();
+            connection.send(Message::Ok(0, None)).await.unwrap();
+            connection
+                .send(Envelope::new(first.id, Message::Error(ErrorCode::Internal, None)))
+                .await
+                .unwrap();
+        });
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut requests = Requests::new(Framed::new(stream, MessageCodec::new()));
+        let first = requests.send(Message::Ok(1, None)).await.unwrap();
+        let second = requests.send(Message::Ok(2, None)).await.unwrap();
+        assert_eq!(second.wait().await, Ok(Message::Ok(2, None)));
+        assert_eq!(first.wait().await, Ok(Message::Error(ErrorCode::Internal, None)));
+        assert_eq!(requests.next_push().await, Some(Message::Ok(0, None)));
+
+        server.await.unwrap();
+        assert_eq!(requests.next_push().await, None);
+        assert_eq!(
+            requests.request(Message::Ok(3, None)).await,
+            Err(MessageError::ConnectionClosed)
+        );
+    }
+}